@@ -1,15 +1,483 @@
 extern crate liblitereader;
 
 use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
 
-use liblitereader::{Parser, SqliteFile};
+use liblitereader::{Parser, SqliteFile, TextEncoding, read_file, write_file_atomic};
+use liblitereader::carving;
+use liblitereader::checksum;
+use liblitereader::diff;
+use liblitereader::history;
+use liblitereader::recovery;
+use liblitereader::salvage;
+use liblitereader::writer;
+#[cfg(feature = "sqlcipher")]
+use liblitereader::sqlcipher;
+use liblitereader::parser::{parse_changeset, parse_journal, parse_wal};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(|a| a.as_ref()) {
+        None => Err("No file specified".to_string()),
+        Some("set-header") => set_header(&args[1..]),
+        Some("verify-checksums") => verify_checksums(&args[1..]),
+        Some("vacuum") => vacuum(args[1..].to_vec()),
+        Some("recover") => recover(args[1..].to_vec()),
+        Some("carve") => carve(args[1..].to_vec()),
+        Some("salvage") => salvage(&args[1..]),
+        Some("attribute") => attribute(&args[1..]),
+        Some("history") => wal_history(args[1..].to_vec()),
+        Some("diff") => diff(args[1..].to_vec()),
+        Some("changeset") => changeset(args[1..].to_vec()),
+        Some(_) => show(args),
+    };
+    if let Err(e) = result {
+        println!("{}", e);
+        process::exit(1);
+    }
+}
+
+/*
+ * Remove an option and its value from the arguments
+ */
+fn take_option(args: &mut Vec<String>, name: &str, value: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|a| a == name) {
+        Some(index) if index + 1 < args.len() => {
+            args.remove(index);
+            Ok(Some(args.remove(index)))
+        },
+        Some(_) => Err(format!("{} needs {}", name, value)),
+        None => Ok(None),
+    }
+}
+
+fn show(mut args: Vec<String>) -> Result<(), String> {
+    let rollback = match args.iter().position(|a| a == "--rollback") {
+        Some(index) => { args.remove(index); true },
+        None => false,
+    };
+    let key = take_option(&mut args, "--key", "a passphrase or x'<hex>' key")?;
+    if args.is_empty() {
+        return Err("No file specified".to_string())
+    }
+    let mut file = match key {
+        Some(key) => open_encrypted(&args[0], &key)?,
+        None => SqliteFile::from_file(&args[0])?,
+    };
+
+    let journal_path = format!("{}-journal", args[0]);
+    if Path::new(&journal_path).exists() {
+        let journal = read_file(&journal_path).and_then(|b| parse_journal(&b));
+        match journal {
+            Ok(ref journal) if journal.is_hot(&file) => {
+                println!("Hot journal: {} ({} pages)", journal_path, journal.valid_records().len());
+                if rollback {
+                    file = journal.rollback(&file)
+                        .map_err(|e| format!("Rollback failed: {}", e))?;
+                }
+            },
+            Ok(_) => (),
+            Err(e) => println!("Ignoring journal {}: {}", journal_path, e),
+        }
+    }
+    println!("{}", file);
+    if file.header.sqlite_version != 0 {
+        println!("SQLite version: {}", file.header.writer_version());
+    }
+    if file.header.application_id != 0 {
+        println!("Application: {}", file.header.application());
+    }
+    for warning in file.validate() {
+        println!("Warning: {}", warning);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlcipher")]
+fn open_encrypted(path: &str, key: &str) -> Result<SqliteFile, String> {
+    let key = sqlcipher::CipherKey::parse(key)?;
+    sqlcipher::open(&read_file(path)?, &key)
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+fn open_encrypted(_path: &str, _key: &str) -> Result<SqliteFile, String> {
+    Err("litereader was built without the sqlcipher feature".to_string())
+}
+
+/*
+ * litereader set-header <file> <field>=<value>...
+ *
+ * The file is replaced without taking SQLite's locks, so it must not be open
+ * in any other process
+ */
+fn set_header(args: &[String]) -> Result<(), String> {
+    if args.len() < 2 {
+        return Err("Usage: litereader set-header <file> <field>=<value>...\n\
+                    The database must not be open in any other process.".to_string())
+    }
+    let path = &args[0];
+    let mut file = SqliteFile::from_file(path)?;
+
+    // Pages in a hot journal or the WAL would override the edited header
+    let journal_path = format!("{}-journal", path);
+    if let Ok(journal) = read_file(&journal_path).and_then(|b| parse_journal(&b)) {
+        if journal.is_hot(&file) {
+            return Err(format!("Refusing to edit, {} is hot", journal_path))
+        }
+    }
+    let wal_path = format!("{}-wal", path);
+    if fs::metadata(&wal_path).map(|m| m.len() > 0).unwrap_or(false) {
+        return Err(format!("Refusing to edit, {} is not empty", wal_path))
+    }
+
+    file.update_header(|header| {
+        for assignment in &args[1..] {
+            let mut parts = assignment.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(field), Some(value)) => header.set_field(field, value)?,
+                _ => return Err(format!("Expected <field>=<value>, got {}", assignment)),
+            }
+        }
+        Ok(())
+    })?;
+    write_file_atomic(path, file.as_bytes())?;
+    println!("{}", file);
+    Ok(())
+}
+
+/*
+ * litereader verify-checksums <file>
+ *
+ * Check the cksumvfs checksums of every page and of every frame in the WAL
+ */
+fn verify_checksums(args: &[String]) -> Result<(), String> {
+    if args.is_empty() {
+        return Err("Usage: litereader verify-checksums <file>".to_string())
+    }
+    let file = SqliteFile::from_file(&args[0])?;
+    let mut mismatches = checksum::verify_file(&file)?;
+    let wal_path = format!("{}-wal", args[0]);
+    if Path::new(&wal_path).exists() {
+        let wal = parse_wal(&read_file(&wal_path)?)?;
+        mismatches.extend(checksum::verify_wal(&wal));
+    }
+    for mismatch in &mismatches {
+        println!("Checksum mismatch: {}", mismatch);
+    }
+    match mismatches.len() {
+        0 => Ok(()),
+        n => Err(format!("{} checksum mismatches", n)),
+    }
+}
+
+/*
+ * The database as SQLite sees it, with a hot journal rolled back and
+ * committed WAL frames applied
+ */
+fn open_as_seen(path: &str) -> Result<SqliteFile, String> {
+    let mut file = SqliteFile::from_file(path)?;
+    let journal_path = format!("{}-journal", path);
+    if let Ok(journal) = read_file(&journal_path).and_then(|b| parse_journal(&b)) {
+        if journal.is_hot(&file) {
+            eprintln!("Rolling back hot journal {}", journal_path);
+            file = journal.rollback(&file)?;
+        }
+    }
+    let wal_path = format!("{}-wal", path);
+    if fs::metadata(&wal_path).map(|m| m.len() > 0).unwrap_or(false) {
+        let wal = parse_wal(&read_file(&wal_path)?)?;
+        eprintln!("Applying {} committed WAL frames", wal.committed_frames().len());
+        file = wal.checkpoint(&file)?;
+    }
+    Ok(file)
+}
+
+/*
+ * litereader vacuum <in> <out> [--page-size <n>] [--reserved-space <n>]
+ *                   [--text-encoding <utf-8|utf-16le|utf-16be>]
+ *
+ * Write a compacted copy of the database as SQLite sees it
+ */
+fn vacuum(mut args: Vec<String>) -> Result<(), String> {
+    let page_size = match take_option(&mut args, "--page-size", "a size")? {
+        Some(x) => Some(x.parse::<u32>().map_err(|e| format!("Invalid page size {}: {}", x, e))?),
+        None => None,
+    };
+    let reserved_space = match take_option(&mut args, "--reserved-space", "a number of bytes")? {
+        Some(x) => Some(x.parse::<u8>().map_err(|e| format!("Invalid reserved space {}: {}", x, e))?),
+        None => None,
+    };
+    let text_encoding = match take_option(&mut args, "--text-encoding", "an encoding")? {
+        Some(x) => Some(x.parse::<TextEncoding>()?),
+        None => None,
+    };
+    if args.len() != 2 {
+        return Err("Usage: litereader vacuum <in> <out> [--page-size <n>] [--reserved-space <n>] \
+                    [--text-encoding <utf-8|utf-16le|utf-16be>]".to_string())
+    }
+    let (input, output) = (&args[0], &args[1]);
+    if fs::canonicalize(output).ok().is_some_and(|o| fs::canonicalize(input).ok() == Some(o)) {
+        return Err("The output has to be a different file".to_string())
+    }
+    let file = open_as_seen(input)?;
+    if file.header.largest_root_page != 0 {
+        println!("Note: {} is written without auto_vacuum", output);
+    }
+
+    if let Some(encoding) = text_encoding.filter(|&x| x != file.header.text_encoding.effective()) {
+        println!("Converting text from {} to {}", file.header.text_encoding, encoding);
+    }
+
+    let buffer = writer::vacuum(&file, page_size, reserved_space, text_encoding)?;
+    write_file_atomic(output, &buffer)?;
+    let page_size = page_size.unwrap_or(file.header.page_size) as usize;
+    println!("{} pages, {} bytes -> {} pages, {} bytes", file.page_count(), file.as_bytes().len(),
+             buffer.len() / page_size, buffer.len());
+    Ok(())
+}
+
+/*
+ * litereader recover <file> [--freelist] [--min-confidence <0..1>]
+ *
+ * List deleted rows that are still in the free space of table pages, or
+ * with --freelist on the pages of the freelist
+ */
+fn recover(mut args: Vec<String>) -> Result<(), String> {
+    let freelist = match args.iter().position(|a| a == "--freelist") {
+        Some(index) => { args.remove(index); true },
+        None => false,
+    };
+    let min_confidence = match take_option(&mut args, "--min-confidence", "a number between 0 and 1")? {
+        Some(x) => x.parse::<f64>().map_err(|e| format!("Invalid confidence {}: {}", x, e))?,
+        None => recovery::MIN_CONFIDENCE,
+    };
+    if args.len() != 1 {
+        return Err("Usage: litereader recover <file> [--freelist] [--min-confidence <0..1>]".to_string())
+    }
+    let file = SqliteFile::from_file(&args[0])?;
+    let rows = match freelist {
+        true => recovery::recover_freelist(&file, min_confidence)?,
+        false => recovery::recover_deleted(&file, min_confidence)?,
+    };
+    for row in &rows {
+        println!("{}", row);
+    }
+    println!("{} rows recovered", rows.len());
+    Ok(())
+}
+
+/*
+ * litereader carve <image> <output directory> [--page-size <bytes>] [--alignment <bytes>]
+ *
+ * Cut databases and stray B-tree pages out of a disk image or memory dump
+ */
+fn carve(mut args: Vec<String>) -> Result<(), String> {
+    let page_size = match take_option(&mut args, "--page-size", "a size")? {
+        Some(x) => Some(x.parse::<u32>().map_err(|e| format!("Invalid page size {}: {}", x, e))?),
+        None => None,
+    };
+    let alignment = match take_option(&mut args, "--alignment", "a number of bytes")? {
+        Some(x) => x.parse::<usize>().ok().filter(|&x| x > 0).ok_or(format!("Invalid alignment {}", x))?,
+        None => carving::SECTOR_SIZE,
+    };
+    if args.len() != 2 {
+        return Err("Usage: litereader carve <image> <output directory> [--page-size <bytes>] \
+                    [--alignment <bytes>]".to_string())
+    }
+    let image = read_file(&args[0])?;
+    fs::create_dir_all(&args[1]).map_err(|e| format!("couldn't create {}: {}", args[1], e))?;
+    let carving = carving::carve(&image, page_size, alignment);
+    for database in &carving.databases {
+        let path = Path::new(&args[1]).join(format!("database-{}.db", database.offset));
+        write_file_atomic(&path.to_string_lossy(), database.file.as_bytes())?;
+        println!("{} -> {}", database, path.display());
+    }
+    for page in &carving.pages {
+        let path = Path::new(&args[1]).join(format!("page-{}.bin", page.offset));
+        write_file_atomic(&path.to_string_lossy(), &page.bytes)?;
+        println!("{} -> {}", page, path.display());
+    }
+    println!("{} databases, {} pages carved", carving.databases.len(), carving.pages.len());
+    Ok(())
+}
+
+/*
+ * litereader salvage <file>
+ *
+ * Print SQL that recreates whatever can still be read of a damaged database,
+ * like .recover in the sqlite3 shell
+ */
+fn salvage(args: &[String]) -> Result<(), String> {
+    if args.len() != 1 {
+        return Err("Usage: litereader salvage <file>".to_string())
+    }
+    println!("{}", salvage::salvage(&read_file(&args[0])?)?.to_sql());
+    Ok(())
+}
+
+/*
+ * litereader attribute <database> <page file>...
+ *
+ * Rank the tables of a database by how likely the rows of each page file,
+ * as written by carve, belong to them
+ */
+fn attribute(args: &[String]) -> Result<(), String> {
     if args.len() < 2 {
-        println!("No file specified");
-        return
+        return Err("Usage: litereader attribute <database> <page file>...".to_string())
+    }
+    let file = SqliteFile::from_file(&args[0])?;
+    let shapes = recovery::TableShape::from_schema(&file)?;
+    let known_rows: Vec<_> = shapes.iter().map(|x| file.table_rows(x.root).unwrap_or_default()).collect();
+    let reserved_space = file.header.reserved_space as usize;
+    for path in &args[1..] {
+        let page = read_file(path)?;
+        let usable_size = page.len().saturating_sub(reserved_space) as u32;
+        let rows = match recovery::page_rows(&page, usable_size, file.header.text_encoding) {
+            Ok(rows) if !rows.is_empty() => rows,
+            Ok(_) => { println!("{}: no readable rows", path); continue },
+            Err(e) => { println!("{}: {}", path, e); continue },
+        };
+        let (first, last) = (rows.iter().map(|x| x.0).min(), rows.iter().map(|x| x.0).max());
+        println!("{}: {} rows, rowids {} to {}", path, rows.len(), first.unwrap_or(0), last.unwrap_or(0));
+        for attribution in recovery::attribute_rows(&rows, &shapes, &known_rows).iter().take(3) {
+            println!("  {}", attribution);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * litereader history <file> [--versions]
+ *
+ * List the rows each transaction in the WAL changed, including those of
+ * earlier generations still in the frames behind the current ones, or with
+ * --versions every version of every page
+ */
+fn wal_history(mut args: Vec<String>) -> Result<(), String> {
+    let versions = match args.iter().position(|a| a == "--versions") {
+        Some(index) => { args.remove(index); true },
+        None => false,
+    };
+    if args.len() != 1 {
+        return Err("Usage: litereader history <file> [--versions]".to_string())
+    }
+    let file = SqliteFile::from_file(&args[0])?;
+    let wal_path = format!("{}-wal", args[0]);
+    if !Path::new(&wal_path).exists() {
+        return Err(format!("No WAL at {}", wal_path))
+    }
+    let wal = parse_wal(&read_file(&wal_path)?)?;
+    if versions {
+        let mut versions = history::page_versions(&file, &wal);
+        versions.sort_by_key(|x| x.page_number);
+        for version in &versions {
+            println!("{}", version);
+        }
+        let mut pages: Vec<u32> = versions.iter().map(|x| x.page_number).collect();
+        pages.dedup();
+        println!("{} versions of {} pages", versions.len(), pages.len());
+        return Ok(())
+    }
+    let changes = history::history(&file, &wal)?;
+    for change in &changes {
+        println!("{}", change);
+    }
+    println!("{} row changes", changes.len());
+    Ok(())
+}
+
+/*
+ * litereader diff <a> <b> [--sql] [--transaction] [--pages]
+ *
+ * List the schema changes and the rows inserted, deleted and updated from
+ * the first database to the second, or with --sql print the statements
+ * that turn the first into the second like sqldiff. With --pages compare
+ * two versions of the same database page by page and count the changed
+ * pages of each table and index.
+ */
+fn diff(mut args: Vec<String>) -> Result<(), String> {
+    let sql = match args.iter().position(|a| a == "--sql") {
+        Some(index) => { args.remove(index); true },
+        None => false,
+    };
+    let transaction = match args.iter().position(|a| a == "--transaction") {
+        Some(index) => { args.remove(index); true },
+        None => false,
+    };
+    let pages = match args.iter().position(|a| a == "--pages") {
+        Some(index) => { args.remove(index); true },
+        None => false,
+    };
+    if args.len() != 2 || (transaction && !sql) || (pages && sql) {
+        return Err("Usage: litereader diff <a> <b> [--sql] [--transaction] [--pages]".to_string())
+    }
+    let (old, new) = (open_as_seen(&args[0])?, open_as_seen(&args[1])?);
+    if pages {
+        let diff = diff::diff_pages(&old, &new)?;
+        println!("{}", diff);
+        let mut owners: Vec<(String, usize)> = vec![];
+        for page in &diff.pages {
+            let owner = page.new_owner.as_ref().or(page.old_owner.as_ref())
+                .map_or("unknown".to_string(), |x| x.to_string());
+            match owners.iter().position(|x| x.0 == owner) {
+                Some(index) => owners[index].1 += 1,
+                None => owners.push((owner, 1)),
+            }
+        }
+        owners.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        for (owner, count) in owners {
+            println!("{}: {} pages", owner, count);
+        }
+        println!("{} of {} pages changed", diff.pages.len(), new.page_count());
+        return Ok(())
+    }
+    if sql {
+        let script = diff::diff_sql(&old, &new)?;
+        match transaction {
+            true => print!("BEGIN TRANSACTION;\n{}COMMIT;\n", script),
+            false => print!("{}", script),
+        }
+        return Ok(())
+    }
+    let diff = diff::diff(&old, &new)?;
+    for change in &diff.schema {
+        println!("{}", change);
+    }
+    let (mut inserted, mut deleted, mut updated) = (0, 0, 0);
+    for table in diff.tables.iter().filter(|x| !x.rows.is_empty()) {
+        println!("{}", table);
+        for row in &table.rows {
+            match row.change {
+                diff::RowChange::Inserted(_) => inserted += 1,
+                diff::RowChange::Deleted(_) => deleted += 1,
+                diff::RowChange::Updated(_) => updated += 1,
+            }
+        }
+    }
+    println!("{} schema changes, {} rows inserted, {} deleted, {} updated",
+             diff.schema.len(), inserted, deleted, updated);
+    Ok(())
+}
+
+/*
+ * litereader changeset <file> [--json]
+ *
+ * Show a changeset or patchset written by the session extension
+ */
+fn changeset(mut args: Vec<String>) -> Result<(), String> {
+    let json = match args.iter().position(|a| a == "--json") {
+        Some(index) => { args.remove(index); true },
+        None => false,
+    };
+    if args.len() != 1 {
+        return Err("Usage: litereader changeset <file> [--json]".to_string())
+    }
+    let changeset = parse_changeset(&read_file(&args[0])?)?;
+    match json {
+        true => println!("{}", changeset.to_json()),
+        false => println!("{}", changeset),
     }
-    let file = SqliteFile::from_file(&args[1]);
-    println!("{}", file.unwrap());
+    Ok(())
 }
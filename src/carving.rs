@@ -0,0 +1,221 @@
+/*
+ * Carving databases out of raw byte streams like disk images and memory
+ * dumps. A database file starts with the header string and its size is in
+ * the header, so it can be cut out whole if every page is still there.
+ * Everything else is searched for pages that look like B-tree pages, which
+ * are kept as standalone fragments.
+ */
+extern crate std;
+
+use std::collections::HashSet;
+
+use data_structures::{BTreePage, SqliteFile};
+use parser::{cell_size, parse_btree_page, parse_cells, HEADER_STRING, PAGE_SIZE_MAX};
+use Parser;
+
+// Files start at sector boundaries, and so do their pages
+pub const SECTOR_SIZE: usize = 512;
+// The largest fragmented byte count SQLite ever leaves on a page
+const FRAGMENTED_BYTES_MAX: u8 = 60;
+
+/*
+ * A database found whole, with every B-tree readable
+ */
+#[derive(Debug)]
+pub struct CarvedDatabase {
+    pub offset: usize,
+    pub file: SqliteFile,
+}
+
+/*
+ * A B-tree page found on its own. Page 1 keeps the database header in front
+ * of its B-tree header.
+ */
+#[derive(Debug,Clone)]
+pub struct CarvedPage {
+    pub offset: usize,
+    pub page: BTreePage,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Carving {
+    pub databases: Vec<CarvedDatabase>,
+    pub pages: Vec<CarvedPage>,
+}
+
+/*
+ * Offsets of every database header in the stream
+ */
+pub fn find_headers(image: &[u8]) -> Vec<usize> {
+    let magic = HEADER_STRING.as_bytes();
+    (0..image.len().saturating_sub(99))
+        .filter(|&x| image[x] == magic[0] && image[x..].starts_with(magic))
+        .collect()
+}
+
+/*
+ * The database starting at offset, if all of its pages are in the stream.
+ * Whatever follows a truncated database fills the missing pages, so every
+ * B-tree, overflow chain and the freelist have to be readable.
+ */
+pub fn carve_database(image: &[u8], offset: usize) -> Option<SqliteFile> {
+    let header = SqliteFile::from_vec(&image[offset..(offset + 100).min(image.len())]).ok()?.header;
+    if !header.database_size_is_valid() {
+        return None
+    }
+    let end = offset.checked_add(header.database_size as usize * header.page_size as usize)?;
+    if end > image.len() {
+        return None
+    }
+    let file = SqliteFile::from_vec(&image[offset..end]).ok()?;
+    let readable = file.schema().ok()?.iter()
+        .filter(|x| x.rootpage > 0)
+        .all(|x| match file.btree_cells(x.rootpage) {
+            Ok(cells) => cells.iter().all(|x| file.read_payload(x).is_ok()),
+            Err(_) => false,
+        });
+    let freelist = file.freelist_pages().map(|x| x.len() as u32);
+    if readable && freelist == Ok(file.header.freelist_count) { Some(file) } else { None }
+}
+
+/*
+ * The B-tree page at the start of bytes if it has the given size. The header
+ * has to be consistent and the cells have to lie side by side in the cell
+ * content area.
+ */
+pub fn plausible_page(bytes: &[u8], page_size: usize) -> Option<BTreePage> {
+    if bytes.len() < page_size {
+        return None
+    }
+    let page = &bytes[..page_size];
+    let btree_page = parse_btree_page(page).ok()?;
+    let header = &btree_page.header;
+    let header_offset = if page.starts_with(HEADER_STRING.as_bytes()) { 100 } else { 0 };
+    let pointer_end = header_offset + header.page_type.header_size() + 2 * header.cell_count as usize;
+    let content_offset = header.cell_content_offset as usize;
+    if header.cell_count == 0
+        || content_offset < pointer_end
+        || content_offset > page_size
+        || header.fragmented_free_byte_count > FRAGMENTED_BYTES_MAX
+        || header.freeblock_offset.is_some_and(|x| (x as usize) < content_offset
+                                                   || x as usize + 4 > page_size)
+        || header.right_most_pointer == Some(0) {
+        return None
+    }
+    let cells = parse_cells(page, &btree_page, page_size as u32).ok()?;
+    if cells.iter().any(|x| x.left_child == Some(0)) {
+        return None
+    }
+    let mut extents = vec![];
+    for &pointer in &btree_page.cell_pointer {
+        let pointer = pointer as usize;
+        let size = cell_size(&page[pointer..], page_size as u32, header.page_type).ok()?;
+        extents.push((pointer, pointer + size));
+    }
+    extents.sort();
+    let overlapping = extents.windows(2).any(|x| x[0].1 > x[1].0);
+    if overlapping || extents.last().is_some_and(|x| x.1 > page_size) {
+        return None
+    }
+    Some(btree_page)
+}
+
+/*
+ * Databases and stray pages in the stream. Pages are looked for at every
+ * multiple of alignment, with the page size given or those of the database
+ * headers found first, and otherwise the smallest plausible size.
+ */
+pub fn carve(image: &[u8], page_size: Option<u32>, alignment: usize) -> Carving {
+    let mut databases = vec![];
+    let mut page_sizes = vec![];
+    page_sizes.extend(page_size.map(|x| x as usize));
+    for offset in find_headers(image) {
+        match carve_database(image, offset) {
+            Some(file) => databases.push(CarvedDatabase { offset, file }),
+            None => {
+                if let Ok(file) = SqliteFile::from_vec(&image[offset..offset + 100]) {
+                    page_sizes.push(file.header.page_size as usize);
+                }
+            },
+        }
+    }
+    let mut seen = HashSet::new();
+    page_sizes.retain(|x| seen.insert(*x));
+    let guesses: Vec<usize> = (9..17).map(|x| 1 << x).filter(|&x| x <= PAGE_SIZE_MAX as usize).collect();
+
+    let mut pages = vec![];
+    let mut offset = 0;
+    while offset < image.len() {
+        let end = |x: &CarvedDatabase| x.offset + x.file.as_bytes().len();
+        if let Some(database) = databases.iter().find(|x| x.offset <= offset && offset < end(x)) {
+            offset = end(database);
+            offset += (alignment - offset % alignment) % alignment;
+            continue
+        }
+        let found = page_sizes.iter().chain(&guesses)
+            .find_map(|&x| plausible_page(&image[offset..], x).map(|y| (x, y)));
+        match found {
+            Some((size, page)) => {
+                pages.push(CarvedPage { offset, page, bytes: image[offset..offset + size].to_vec() });
+                offset += size;
+                offset += (alignment - offset % alignment) % alignment;
+            },
+            None => offset += alignment,
+        }
+    }
+    Carving { databases, pages }
+}
+
+impl std::fmt::Display for CarvedDatabase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Database at offset {}: {} pages of {} bytes",
+               self.offset, self.file.page_count(), self.file.header.page_size)
+    }
+}
+
+impl std::fmt::Display for CarvedPage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Page at offset {}: {:?}, {} cells, {} bytes",
+               self.offset, self.page.header.page_type, self.page.header.cell_count, self.bytes.len())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_structures::{SqliteFile, Value};
+    use writer::DatabaseBuilder;
+    use Parser;
+
+    fn database(rows: i64) -> Vec<u8> {
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        let values = (1..rows).map(|i| (i, vec![Value::Text(format!("row {}", i)), Value::Integer(i)]));
+        builder.add_table("t", "CREATE TABLE t(a TEXT, b INTEGER)", values).unwrap();
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn databases_and_pages_are_carved() {
+        let whole = database(200);
+        let truncated = database(300);
+        let mut image = vec![0xa5; 3 * super::SECTOR_SIZE];
+        image.extend(&whole);
+        image.extend(vec![0; super::SECTOR_SIZE]);
+        image.extend(&truncated[..truncated.len() - 1024]);
+
+        let carving = super::carve(&image, None, super::SECTOR_SIZE);
+        assert_eq!(1, carving.databases.len());
+        assert_eq!(3 * super::SECTOR_SIZE, carving.databases[0].offset);
+        assert_eq!(whole, carving.databases[0].file.as_bytes());
+
+        // Every page of the truncated database but the missing one, page 1 included
+        let start = image.len() - (truncated.len() - 1024);
+        let pages = SqliteFile::from_vec(&truncated).unwrap().page_count() as usize;
+        assert_eq!(pages - 1, carving.pages.len());
+        for (i, page) in carving.pages.iter().enumerate() {
+            assert_eq!(start + i * 1024, page.offset);
+            assert_eq!(&truncated[i * 1024..(i + 1) * 1024], page.bytes.as_slice());
+        }
+    }
+}
@@ -0,0 +1,173 @@
+extern crate std;
+
+use data_structures::{SqliteFile, Wal};
+
+pub const CKSUMVFS_RESERVED_SPACE: u8 = 8;
+const LOCK_BYTE_OFFSET: usize = 0x4000_0000;
+
+/*
+ * The checksum used for WAL headers and frames. Words are read in the byte
+ * order given by the WAL magic, and the result is chained from frame to frame.
+ */
+pub fn wal_checksum(big_endian: bool, data: &[u8], seed: (u32, u32)) -> (u32, u32) {
+    let (mut s0, mut s1) = seed;
+    let read = |chunk: &[u8]| -> u32 {
+        let word = [chunk[0], chunk[1], chunk[2], chunk[3]];
+        if big_endian { u32::from_be_bytes(word) } else { u32::from_le_bytes(word) }
+    };
+    for chunk in data.chunks(8) {
+        s0 = s0.wrapping_add(read(&chunk[..4])).wrapping_add(s1);
+        s1 = s1.wrapping_add(read(&chunk[4..])).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+/*
+ * cksumvfs stores this checksum of the rest of the page in the last 8 bytes
+ * of every page. It is the WAL checksum over little-endian words, written
+ * out in little-endian byte order.
+ */
+pub fn cksumvfs_checksum(data: &[u8]) -> [u8; 8] {
+    let (s1, s2) = wal_checksum(false, data, (0, 0));
+    let mut checksum = [0; 8];
+    checksum[..4].copy_from_slice(&s1.to_le_bytes());
+    checksum[4..].copy_from_slice(&s2.to_le_bytes());
+    checksum
+}
+
+pub fn cksumvfs_page_is_valid(page: &[u8]) -> bool {
+    if page.len() < CKSUMVFS_RESERVED_SPACE as usize {
+        return false
+    }
+    let (data, checksum) = page.split_at(page.len() - CKSUMVFS_RESERVED_SPACE as usize);
+    cksumvfs_checksum(data) == checksum
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum ChecksumMismatch {
+    Page(u32),
+    WalFrame(usize, u32),
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ChecksumMismatch::Page(page) => write!(f, "page {}", page),
+            ChecksumMismatch::WalFrame(frame, page) => write!(f, "WAL frame {} (page {})", frame, page),
+        }
+    }
+}
+
+pub fn verify_file(file: &SqliteFile) -> Result<Vec<ChecksumMismatch>, String> {
+    if file.header.reserved_space != CKSUMVFS_RESERVED_SPACE {
+        return Err(format!("Reserved space is {}, cksumvfs needs {}",
+                           file.header.reserved_space, CKSUMVFS_RESERVED_SPACE))
+    }
+    if !file.header.page_size_is_valid() {
+        return Err(format!("Invalid page size {}", file.header.page_size))
+    }
+    let page_size = file.header.page_size as usize;
+    // The page holding the lock byte is never written
+    let lock_byte_page = (LOCK_BYTE_OFFSET / page_size + 1) as u32;
+    let mut mismatches = vec![];
+    for page_number in 1..file.page_count() + 1 {
+        let start = (page_number as usize - 1) * page_size;
+        let page = match file.as_bytes().get(start..start + page_size) {
+            Some(page) => page,
+            None => break,
+        };
+        if page_number != lock_byte_page && !cksumvfs_page_is_valid(page) {
+            mismatches.push(ChecksumMismatch::Page(page_number));
+        }
+    }
+    Ok(mismatches)
+}
+
+pub fn verify_wal(wal: &Wal) -> Vec<ChecksumMismatch> {
+    wal.frames.iter().enumerate()
+        .filter(|&(_, frame)| !cksumvfs_page_is_valid(&frame.data))
+        .map(|(i, frame)| ChecksumMismatch::WalFrame(i, frame.page_number))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_structures::{SqliteFile, Value, Wal, WalFrame, WalHeader};
+    use writer::DatabaseBuilder;
+    use Parser;
+
+    #[test]
+    fn cksumvfs_page() {
+        let mut page: Vec<u8> = (0..1024).map(|x| (x * 7 % 251) as u8).collect();
+        let checksum = super::cksumvfs_checksum(&page[..1016]);
+        page[1016..].copy_from_slice(&checksum);
+        assert!(super::cksumvfs_page_is_valid(&page));
+        page[100] ^= 1;
+        assert!(!super::cksumvfs_page_is_valid(&page));
+        assert!(!super::cksumvfs_page_is_valid(&page[..4]));
+    }
+
+    // Computed by cksmCompute() from cksumvfs.c on a little-endian machine
+    #[test]
+    fn cksumvfs_known_answer() {
+        let page: Vec<u8> = (0..1024).map(|x| (x * 7 % 251) as u8).collect();
+        assert_eq!([0x5d, 0xd7, 0xb8, 0x69, 0x12, 0x90, 0x72, 0x75], super::cksumvfs_checksum(&page[..1016]));
+    }
+
+    fn checksummed_database() -> Vec<u8> {
+        let mut builder = DatabaseBuilder::new().page_size(1024).reserved_space(8);
+        let rows = (1..100).map(|i| (i, vec![Value::Text(format!("row {:0100}", i))]));
+        builder.add_table("t", "CREATE TABLE t(name TEXT)", rows).unwrap();
+        let mut bytes = builder.build().unwrap();
+        for page in bytes.chunks_mut(1024) {
+            let (data, checksum) = page.split_at_mut(1016);
+            checksum.copy_from_slice(&super::cksumvfs_checksum(data));
+        }
+        bytes
+    }
+
+    #[test]
+    fn corrupt_page() {
+        let mut bytes = checksummed_database();
+        assert!(bytes.len() / 1024 > 3);
+        assert_eq!(Ok(vec![]), super::verify_file(&SqliteFile::from_vec(&bytes).unwrap()));
+        bytes[2 * 1024 + 500] ^= 1;
+        assert_eq!(Ok(vec![super::ChecksumMismatch::Page(3)]),
+                   super::verify_file(&SqliteFile::from_vec(&bytes).unwrap()));
+    }
+
+    #[test]
+    fn corrupt_wal_frame() {
+        let bytes = checksummed_database();
+        let header = WalHeader { magic: 0x377f_0682, format_version: 3_007_000, page_size: 1024,
+                                 checkpoint_sequence: 0, salt: (1, 2), checksum: (0, 0) };
+        let frames = (1..4).map(|page_number| {
+            let start = (page_number as usize - 1) * 1024;
+            WalFrame { page_number, commit_size: 0, salt: header.salt, checksum: (0, 0),
+                       data: bytes[start..start + 1024].to_vec() }
+        }).collect();
+        let mut wal = Wal { header, frames };
+        assert!(super::verify_wal(&wal).is_empty());
+        wal.frames[1].data[10] ^= 1;
+        assert_eq!(vec![super::ChecksumMismatch::WalFrame(1, 2)], super::verify_wal(&wal));
+    }
+
+    #[test]
+    fn invalid_page_size() {
+        let mut bytes = DatabaseBuilder::new().page_size(1024).reserved_space(8).build().unwrap();
+        for &page_size in &[0u16, 4] {
+            bytes[16..18].copy_from_slice(&page_size.to_be_bytes());
+            let file = SqliteFile::from_vec(&bytes).unwrap();
+            assert_eq!(Err(format!("Invalid page size {}", page_size)), super::verify_file(&file));
+        }
+    }
+
+    #[test]
+    fn wal_checksum_byte_order() {
+        let data = [1, 0, 0, 0, 2, 0, 0, 0];
+        assert_eq!((1, 3), super::wal_checksum(false, &data, (0, 0)));
+        assert_eq!((1 << 24, 3 << 24), super::wal_checksum(true, &data, (0, 0)));
+        assert_eq!((6, 11), super::wal_checksum(false, &data, (2, 3)));
+    }
+}
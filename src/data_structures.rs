@@ -1,8 +1,16 @@
 extern crate std;
 
+use std::cmp::Ordering;
+
 use enums;
+use enums::{ApplicationId, Feature, HeaderWarning};
+use checksum::wal_checksum;
+use parser::{parse_header, parse_btree_page, parse_cells, parse_record, parse_create_index,
+             parse_create_table, encode_text, encode_varint, serial_type_size, HEADER_STRING,
+             JOURNAL_MAGIC, PAGE_SIZE_MAX};
+use Parser;
 
-#[derive(Debug,Copy,Clone)]
+#[derive(Debug,Copy,Clone,PartialEq)]
 pub struct Header {
     pub page_size: u32,
     pub read_version: enums::ReadVersion,
@@ -23,12 +31,269 @@ pub struct Header {
     pub user_version: u32,
     pub incremental_vacuum_mode: bool,
     pub application_id: u32,
+    pub reserved: [u8; 20],
     pub version_valid_for: u32,
     pub sqlite_version: u32,
 }
 
 
-#[derive(Debug,Copy,Clone)]
+impl Header {
+    /*
+     * The exact 100 byte header as SQLite writes it to disk
+     */
+    pub fn to_bytes(&self) -> [u8; 100] {
+        let mut bytes = [0; 100];
+        bytes[0..16].copy_from_slice(HEADER_STRING.as_bytes());
+        let page_size = match self.page_size {
+            PAGE_SIZE_MAX => 1,
+            x => x as u16,
+        };
+        bytes[16..18].copy_from_slice(&page_size.to_be_bytes());
+        bytes[18] = self.write_version.to_u8();
+        bytes[19] = self.read_version.to_u8();
+        bytes[20] = self.reserved_space;
+        bytes[21] = self.max_embedded_payload_fraction;
+        bytes[22] = self.min_embedded_payload_fraction;
+        bytes[23] = self.leaf_payload_fraction;
+        let fields = [
+            (24, self.file_change_counter),
+            (28, self.database_size),
+            (32, self.freelist_trunk_page),
+            (36, self.freelist_count),
+            (40, self.schema_cookie),
+            (44, self.schema_format.to_u32()),
+            (48, self.default_page_cache_size),
+            (52, self.largest_root_page),
+            (56, self.text_encoding.to_u32()),
+            (60, self.user_version),
+            (64, self.incremental_vacuum_mode as u32),
+            (68, self.application_id),
+            (92, self.version_valid_for),
+            (96, self.sqlite_version),
+        ];
+        for &(offset, value) in &fields {
+            bytes[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        }
+        bytes[72..92].copy_from_slice(&self.reserved);
+        bytes
+    }
+
+    /*
+     * Set one of the fields that SQLite lets you change with a PRAGMA on an
+     * existing database
+     */
+    pub fn set_field(&mut self, field: &str, value: &str) -> Result<(), String> {
+        let parse_u32 = |value: &str| -> Result<u32, String> {
+            match value.parse::<i64>() {
+                Ok(x) if x >= i32::MIN as i64 && x <= u32::MAX as i64 => Ok(x as u32),
+                _ => Err(format!("Invalid value for {}: {}", field, value)),
+            }
+        };
+        let parse_wal = |value: &str| -> Result<bool, String> {
+            match value.to_lowercase().as_ref() {
+                "1" | "legacy" => Ok(false),
+                "2" | "wal" => Ok(true),
+                _ => Err(format!("Invalid value for {}: {}", field, value)),
+            }
+        };
+        match field {
+            "user_version" => self.user_version = parse_u32(value)?,
+            "application_id" => self.application_id = parse_u32(value)?,
+            "default_page_cache_size" => self.default_page_cache_size = parse_u32(value)?,
+            "read_version" => {
+                self.read_version = match parse_wal(value)? {
+                    false => enums::ReadVersion::Legacy,
+                    true => enums::ReadVersion::WAL,
+                }
+            },
+            "write_version" => {
+                self.write_version = match parse_wal(value)? {
+                    false => enums::WriteVersion::Legacy,
+                    true => enums::WriteVersion::WAL,
+                }
+            },
+            "incremental_vacuum_mode" => {
+                self.incremental_vacuum_mode = match value.to_lowercase().as_ref() {
+                    "0" | "false" => false,
+                    "1" | "true" => true,
+                    _ => return Err(format!("Invalid value for {}: {}", field, value)),
+                };
+                if self.incremental_vacuum_mode && self.largest_root_page == 0 {
+                    return Err("Incremental vacuum requires an auto-vacuum database".to_string())
+                }
+            },
+            _ => return Err(format!("Unsupported header field: {}", field)),
+        }
+        Ok(())
+    }
+
+    /*
+     * Page size minus the bytes extensions reserve at the end of each page.
+     * All B-tree layout computations are based on this.
+     */
+    pub fn usable_size(&self) -> u32 {
+        self.page_size.saturating_sub(self.reserved_space as u32)
+    }
+
+    pub fn writer_version(&self) -> SqliteVersion {
+        SqliteVersion::from(self.sqlite_version)
+    }
+
+    pub fn valid_for_version(&self) -> SqliteVersion {
+        SqliteVersion::from(self.version_valid_for)
+    }
+
+    /*
+     * Features the header proves are in use. Those that can only be seen in
+     * the schema are in SqliteFile::schema_features.
+     */
+    pub fn features(&self) -> Vec<Feature> {
+        let mut features = vec![];
+        if let enums::SchemaFormat::Format4 = self.schema_format {
+            features.push(Feature::DescendingIndexes);
+        }
+        if let enums::WriteVersion::WAL = self.write_version {
+            features.push(Feature::WriteAheadLog);
+        }
+        features
+    }
+
+    pub fn application(&self) -> ApplicationId {
+        ApplicationId::from(self.application_id)
+    }
+
+    // A power of two from 512 to 65536
+    pub fn page_size_is_valid(&self) -> bool {
+        self.page_size.is_power_of_two() && self.page_size >= 512 && self.page_size <= PAGE_SIZE_MAX
+    }
+
+    // SQLite needs at least 480 usable bytes on a page to lay out B-tree cells
+    pub fn page_layout_is_valid(&self) -> bool {
+        self.page_size_is_valid() && self.usable_size() >= 480
+    }
+
+    pub fn database_size_is_valid(&self) -> bool {
+        self.database_size != 0 && self.version_valid_for == self.file_change_counter
+    }
+
+    /*
+     * Report every value that SQLite itself would consider corrupt or that
+     * no known writer produces. The freelist and root page checks can only
+     * be done if the in-header database size is trustworthy.
+     */
+    pub fn validate(&self) -> Vec<HeaderWarning> {
+        let mut warnings = vec![];
+        if !self.page_size_is_valid() {
+            warnings.push(HeaderWarning::InvalidPageSize(self.page_size));
+        }
+        if self.max_embedded_payload_fraction != 64
+            || self.min_embedded_payload_fraction != 32
+            || self.leaf_payload_fraction != 32 {
+            warnings.push(HeaderWarning::InvalidPayloadFractions(
+                self.max_embedded_payload_fraction,
+                self.min_embedded_payload_fraction,
+                self.leaf_payload_fraction,
+            ));
+        }
+        if self.usable_size() < 480 {
+            warnings.push(HeaderWarning::ReservedSpaceTooLarge(self.reserved_space));
+        }
+        if self.reserved.iter().any(|&x| x != 0) {
+            warnings.push(HeaderWarning::NonZeroReservedBytes);
+        }
+        if self.version_valid_for != self.file_change_counter {
+            warnings.push(HeaderWarning::StaleDatabaseSize(
+                self.version_valid_for,
+                self.file_change_counter,
+            ));
+        }
+        if self.freelist_count > 0 && self.freelist_trunk_page == 0 {
+            warnings.push(HeaderWarning::FreelistTrunkMissing(self.freelist_count));
+        }
+        if self.database_size_is_valid() {
+            warnings.extend(self.validate_page_count(self.database_size));
+        }
+        if self.incremental_vacuum_mode && self.largest_root_page == 0 {
+            warnings.push(HeaderWarning::IncrementalVacuumWithoutAutoVacuum);
+        }
+        match (self.read_version, self.write_version) {
+            (enums::ReadVersion::Legacy, enums::WriteVersion::Legacy) => (),
+            (enums::ReadVersion::WAL, enums::WriteVersion::WAL) => (),
+            _ => warnings.push(HeaderWarning::MixedFileFormatVersions),
+        }
+        warnings.extend(self.feature_warnings(&self.features()));
+        warnings
+    }
+
+    /*
+     * Features the writer version can't explain. Writers before 3.7.0 leave
+     * the version at zero, so a file without one can't use anything newer.
+     */
+    fn feature_warnings(&self, features: &[Feature]) -> Vec<HeaderWarning> {
+        let first_with_version = SqliteVersion { major: 3, minor: 7, patch: 0 };
+        features.iter().filter_map(|&feature| match self.sqlite_version {
+            0 if feature.introduced_in() >= first_with_version => {
+                Some(HeaderWarning::FeatureWithoutWriterVersion(feature))
+            },
+            0 => None,
+            _ if !self.writer_version().could_produce(feature) => {
+                Some(HeaderWarning::FeatureNewerThanWriter(feature, self.writer_version()))
+            },
+            _ => None,
+        }).collect()
+    }
+
+    fn validate_page_count(&self, page_count: u32) -> Vec<HeaderWarning> {
+        let mut warnings = vec![];
+        if self.freelist_count >= page_count {
+            warnings.push(HeaderWarning::FreelistCountExceedsPageCount(
+                self.freelist_count, page_count));
+        }
+        if self.freelist_trunk_page > page_count {
+            warnings.push(HeaderWarning::FreelistTrunkOutOfRange(
+                self.freelist_trunk_page, page_count));
+        }
+        if self.largest_root_page > page_count {
+            warnings.push(HeaderWarning::LargestRootPageOutOfRange(
+                self.largest_root_page, page_count));
+        }
+        warnings
+    }
+}
+
+
+/*
+ * SQLITE_VERSION_NUMBER as stored in the header, e.g. 3008008 for 3.8.8
+ */
+#[derive(Debug,Copy,Clone,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct SqliteVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl From<u32> for SqliteVersion {
+    fn from(x: u32) -> SqliteVersion {
+        SqliteVersion {
+            major: x / 1_000_000,
+            minor: x / 1000 % 1000,
+            patch: x % 1000,
+        }
+    }
+}
+
+impl SqliteVersion {
+    pub fn to_u32(self) -> u32 {
+        self.major * 1_000_000 + self.minor * 1000 + self.patch
+    }
+
+    pub fn could_produce(self, feature: Feature) -> bool {
+        self >= feature.introduced_in()
+    }
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq)]
 pub enum BTreePageType {
     InteriorIndexPage,
     InteriorTablePage,
@@ -53,6 +318,392 @@ pub struct BTreePage {
     pub cell_pointer: Vec<u16>,
 }
 
+/*
+ * A cell of any of the four B-tree page types. Table interior cells have no
+ * payload, index cells have no rowid.
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct BTreeCell {
+    pub left_child: Option<u32>,
+    pub rowid: Option<i64>,
+    pub payload_size: u64,
+    pub payload: Vec<u8>,
+    pub overflow_page: Option<u32>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/*
+ * A decoded record together with the serial types from its header
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct Record {
+    pub serial_types: Vec<u64>,
+    pub values: Vec<Value>,
+}
+
+impl Value {
+    /*
+     * The smallest serial type that holds the value, as SQLite chooses it
+     */
+    pub fn serial_type(&self, encoding: enums::TextEncoding) -> u64 {
+        match *self {
+            Value::Null => 0,
+            Value::Integer(0) => 8,
+            Value::Integer(1) => 9,
+            Value::Integer(x) => {
+                let magnitude = if x < 0 { !x } else { x };
+                match magnitude {
+                    0..=0x7f => 1,
+                    0x80..=0x7fff => 2,
+                    0x8000..=0x7f_ffff => 3,
+                    0x80_0000..=0x7fff_ffff => 4,
+                    0x8000_0000..=0x7fff_ffff_ffff => 5,
+                    _ => 6,
+                }
+            },
+            Value::Real(_) => 7,
+            Value::Text(ref x) => encode_text(x, encoding).len() as u64 * 2 + 13,
+            Value::Blob(ref x) => x.len() as u64 * 2 + 12,
+        }
+    }
+
+    /*
+     * SQLite's order of values in an index: NULL, then numbers, then TEXT in
+     * the collation's order, then BLOBs. None when the collation is unknown.
+     */
+    pub fn compare(&self, other: &Value, collation: &enums::Collation, encoding: enums::TextEncoding)
+                   -> Option<Ordering> {
+        fn class(value: &Value) -> u8 {
+            match *value {
+                Value::Null => 0,
+                Value::Integer(_) | Value::Real(_) => 1,
+                Value::Text(_) => 2,
+                Value::Blob(_) => 3,
+            }
+        }
+        match (self, other) {
+            (&Value::Integer(a), &Value::Integer(b)) => Some(a.cmp(&b)),
+            (&Value::Integer(a), &Value::Real(b)) => (a as f64).partial_cmp(&b),
+            (&Value::Real(a), &Value::Integer(b)) => a.partial_cmp(&(b as f64)),
+            (&Value::Real(a), &Value::Real(b)) => a.partial_cmp(&b),
+            (Value::Text(a), Value::Text(b)) => collation.compare(a, b, encoding),
+            (Value::Blob(a), Value::Blob(b)) => Some(a.cmp(b)),
+            _ => Some(class(self).cmp(&class(other))),
+        }
+    }
+}
+
+impl Record {
+    pub fn encode(values: &[Value]) -> Vec<u8> {
+        Record::encode_with_encoding(values, enums::TextEncoding::UTF8)
+    }
+
+    /*
+     * Serialize a record with the minimal serial type for every value and
+     * TEXT in the given database encoding
+     */
+    pub fn encode_with_encoding(values: &[Value], encoding: enums::TextEncoding) -> Vec<u8> {
+        let mut header = vec![];
+        let mut body = vec![];
+        for value in values {
+            let serial_type = value.serial_type(encoding);
+            header.extend(encode_varint(serial_type));
+            match *value {
+                Value::Null => (),
+                Value::Integer(x) => {
+                    let size = serial_type_size(serial_type);
+                    body.extend_from_slice(&x.to_be_bytes()[8 - size..]);
+                },
+                Value::Real(x) => body.extend_from_slice(&x.to_bits().to_be_bytes()),
+                Value::Text(ref x) => body.extend(encode_text(x, encoding)),
+                Value::Blob(ref x) => body.extend_from_slice(x),
+            }
+        }
+        // The header size counts its own varint
+        let mut header_size = header.len() + 1;
+        while encode_varint(header_size as u64).len() + header.len() != header_size {
+            header_size = encode_varint(header_size as u64).len() + header.len();
+        }
+        let mut record = encode_varint(header_size as u64);
+        record.extend(header);
+        record.extend(body);
+        record
+    }
+}
+
+/*
+ * A row of sqlite_schema
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct SchemaEntry {
+    pub entry_type: String,
+    pub name: String,
+    pub tbl_name: String,
+    pub rootpage: u32,
+    pub sql: Option<String>,
+}
+
+impl SchemaEntry {
+    /*
+     * A row of sqlite_schema: type, name, tbl_name, rootpage and sql
+     */
+    pub fn from_record(record: &Record) -> SchemaEntry {
+        SchemaEntry::from_values(&record.values)
+    }
+
+    /*
+     * The features the SQL of this entry needs
+     */
+    pub fn features(&self) -> Vec<Feature> {
+        let mut features = vec![];
+        let sql = match self.sql {
+            Some(ref x) => x,
+            None => return features,
+        };
+        if self.entry_type == "table" {
+            if let Ok(table) = parse_create_table(sql) {
+                if table.without_rowid {
+                    features.push(Feature::WithoutRowid);
+                }
+                if table.strict {
+                    features.push(Feature::StrictTables);
+                }
+                if table.columns.iter().any(|x| x.generated) {
+                    features.push(Feature::GeneratedColumns);
+                }
+            }
+        } else if self.entry_type == "index" && parse_create_index(sql).is_ok_and(|x| x.partial) {
+            features.push(Feature::PartialIndexes);
+        }
+        features
+    }
+
+    pub fn from_values(values: &[Value]) -> SchemaEntry {
+        let text = |i: usize| match values.get(i) {
+            Some(Value::Text(x)) => x.clone(),
+            _ => String::new(),
+        };
+        SchemaEntry {
+            entry_type: text(0),
+            name: text(1),
+            tbl_name: text(2),
+            rootpage: match values.get(3) {
+                Some(&Value::Integer(x)) => x as u32,
+                _ => 0,
+            },
+            sql: match values.get(4) {
+                Some(Value::Text(x)) => Some(x.clone()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/*
+ * A column of a CREATE INDEX or of a PRIMARY KEY or UNIQUE constraint.
+ * Expressions have no name.
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct IndexedColumn {
+    pub name: Option<String>,
+    pub collation: Option<String>,
+    pub descending: bool,
+}
+
+/*
+ * PRIMARY KEY or UNIQUE, written after a column or at the end of the table
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct KeyConstraint {
+    pub primary_key: bool,
+    pub column_level: bool,
+    pub columns: Vec<IndexedColumn>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct ColumnDefinition {
+    pub name: String,
+    pub declared_type: String,
+    pub collation: Option<String>,
+    // The DEFAULT if it is a constant, records written before an ALTER TABLE ADD COLUMN read it
+    pub default: Option<Value>,
+    pub not_null: bool,
+    // GENERATED ALWAYS AS, stored or virtual
+    pub generated: bool,
+    // The definition as written in the CREATE TABLE statement
+    pub sql: String,
+}
+
+/*
+ * The parts of a CREATE TABLE statement that decide how its indexes sort.
+ * Constraints are kept in the order SQLite creates their indexes.
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct TableDefinition {
+    pub name: String,
+    pub columns: Vec<ColumnDefinition>,
+    pub constraints: Vec<KeyConstraint>,
+    pub without_rowid: bool,
+    pub strict: bool,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct IndexDefinition {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<IndexedColumn>,
+    // Indexes with a WHERE clause only hold some rows
+    pub partial: bool,
+}
+
+/*
+ * How one field of an index record is ordered
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct KeyColumn {
+    pub name: Option<String>,
+    pub collation: enums::Collation,
+    pub descending: bool,
+}
+
+impl ColumnDefinition {
+    pub fn affinity(&self) -> enums::Affinity {
+        enums::Affinity::from_declared_type(&self.declared_type)
+    }
+}
+
+impl TableDefinition {
+    pub fn column(&self, name: &str) -> Option<&ColumnDefinition> {
+        self.columns.iter().find(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    /*
+     * Position of the INTEGER PRIMARY KEY column. Its value is the rowid and
+     * the record stores NULL in its place.
+     */
+    pub fn rowid_alias(&self) -> Option<usize> {
+        if self.without_rowid {
+            return None
+        }
+        let constraint = self.constraints.iter().find(|c| c.primary_key)?;
+        match constraint.columns.as_slice() {
+            // The DESC quirk only applies to PRIMARY KEY written after the column
+            [column] if !(constraint.column_level && column.descending) => {
+                let name = column.name.as_ref()?;
+                let position = self.columns.iter().position(|c| c.name.eq_ignore_ascii_case(name))?;
+                if self.columns[position].declared_type.eq_ignore_ascii_case("INTEGER") {
+                    Some(position)
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /*
+     * Columns without an explicit collation use the one of the table column
+     */
+    pub fn key_columns(&self, columns: &[IndexedColumn]) -> Vec<KeyColumn> {
+        columns.iter().map(|c| {
+            let default = c.name.as_ref().and_then(|x| self.column(x)).and_then(|x| x.collation.clone());
+            KeyColumn {
+                name: c.name.clone(),
+                collation: c.collation.clone().or(default).map_or(enums::Collation::Binary,
+                                                                  |x| enums::Collation::from(x.as_str())),
+                descending: c.descending,
+            }
+        }).collect()
+    }
+
+    /*
+     * Column names in the order of the values of a record. WITHOUT ROWID
+     * tables store their primary key first.
+     */
+    pub fn record_columns(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        if self.without_rowid {
+            names.extend(self.primary_key().into_iter().filter_map(|x| x.name));
+        }
+        for column in &self.columns {
+            if !names.iter().any(|x| x.eq_ignore_ascii_case(&column.name)) {
+                names.push(column.name.clone());
+            }
+        }
+        names
+    }
+
+    pub fn primary_key(&self) -> Vec<KeyColumn> {
+        self.constraints.iter()
+            .find(|c| c.primary_key)
+            .map_or(vec![], |c| self.key_columns(&c.columns))
+    }
+
+    /*
+     * The indexes behind PRIMARY KEY and UNIQUE constraints, in the order of
+     * the n in sqlite_autoindex_<table>_<n>. An INTEGER PRIMARY KEY is the
+     * rowid, and a constraint repeating the columns of an earlier one gets
+     * no index of its own.
+     */
+    pub fn auto_indexes(&self) -> Vec<Vec<KeyColumn>> {
+        let mut indexes: Vec<Vec<KeyColumn>> = vec![];
+        let rowid_alias = self.rowid_alias();
+        for (i, constraint) in self.constraints.iter().enumerate() {
+            let first_primary_key = self.constraints.iter().position(|c| c.primary_key) == Some(i);
+            if first_primary_key && rowid_alias.is_some() {
+                continue
+            }
+            let key = self.key_columns(&constraint.columns);
+            let duplicate = indexes.iter().any(|index| {
+                index.len() == key.len() && index.iter().zip(&key).all(|(a, b)| {
+                    a.name.as_ref().map(|x| x.to_ascii_lowercase())
+                        == b.name.as_ref().map(|x| x.to_ascii_lowercase())
+                        && a.collation == b.collation
+                })
+            });
+            if !duplicate {
+                indexes.push(key);
+            }
+        }
+        indexes
+    }
+}
+
+impl BTreePageType {
+    pub fn is_leaf(&self) -> bool {
+        matches!(*self, BTreePageType::LeafIndexPage | BTreePageType::LeafTablePage)
+    }
+
+    pub fn is_table(&self) -> bool {
+        matches!(*self, BTreePageType::InteriorTablePage | BTreePageType::LeafTablePage)
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            BTreePageType::InteriorIndexPage => 0x02,
+            BTreePageType::InteriorTablePage => 0x05,
+            BTreePageType::LeafIndexPage => 0x0a,
+            BTreePageType::LeafTablePage => 0x0d,
+            BTreePageType::NullPage => 0x00,
+        }
+    }
+
+    pub fn header_size(&self) -> usize {
+        match *self {
+            BTreePageType::InteriorIndexPage | BTreePageType::InteriorTablePage => 12,
+            _ => 8,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SqliteFile {
     pub header: Header,
@@ -62,9 +713,787 @@ pub struct SqliteFile {
 impl SqliteFile {
     pub fn new(header: Header, buffer: Vec<u8>) -> SqliteFile {
         SqliteFile {
-            header: header,
-            buffer: buffer,
+            header,
+            buffer,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /*
+     * Replace the header the way a write transaction would: the change counter
+     * is bumped so other connections drop their caches, and the database size
+     * is made valid for the new counter.
+     */
+    pub fn update_header<F: FnOnce(&mut Header) -> Result<(), String>>(&mut self, f: F)
+                                                                         -> Result<(), String> {
+        let mut header = self.header;
+        f(&mut header)?;
+        header.database_size = self.page_count();
+        header.file_change_counter = header.file_change_counter.wrapping_add(1);
+        header.version_valid_for = header.file_change_counter;
+        self.buffer[..100].copy_from_slice(&header.to_bytes());
+        self.header = header;
+        Ok(())
+    }
+
+    /*
+     * The in-header database size is only trusted if it is non-zero and was
+     * written by the same transaction as the change counter. Older writers
+     * don't maintain it, so otherwise the size is derived from the file.
+     * SQLite treats a size beyond the end of the file as corruption and
+     * never trusts it either.
+     */
+    pub fn page_count(&self) -> u32 {
+        let file_pages = self.file_pages();
+        if self.header.database_size_is_valid() && self.header.database_size <= file_pages {
+            return self.header.database_size
+        }
+        file_pages
+    }
+
+    fn file_pages(&self) -> u32 {
+        match self.header.page_size {
+            0 => 0,
+            page_size => (self.buffer.len() / page_size as usize) as u32,
+        }
+    }
+
+    /*
+     * Offset of the B-tree page header, page 1 starts with the file header
+     */
+    pub fn btree_header_offset(page_id: usize) -> usize {
+        if page_id == 1 { 100 } else { 0 }
+    }
+
+    pub fn page_bytes(&self, page_id: usize) -> Result<&[u8], String> {
+        if page_id == 0 {
+            return Err("Page numbers start at 1".to_string())
+        }
+        if page_id > self.page_count() as usize {
+            return Err(format!("Page {} is beyond the database size", page_id))
+        }
+        if !self.header.page_layout_is_valid() {
+            return Err(format!("Invalid page size {} with {} reserved bytes",
+                               self.header.page_size, self.header.reserved_space))
+        }
+        let page_size = self.header.page_size as usize;
+        let start = (page_id - 1) * page_size;
+        match self.buffer.get(start..start + page_size) {
+            Some(page) => Ok(page),
+            None => Err(format!("Page {} is beyond the end of the file", page_id)),
+        }
+    }
+
+    /*
+     * The bytes at the end of each page that extensions like cksumvfs or
+     * SQLCipher keep for themselves
+     */
+    pub fn reserved_bytes(&self, page_id: usize) -> Result<&[u8], String> {
+        let page = self.page_bytes(page_id)?;
+        Ok(&page[self.header.usable_size() as usize..])
+    }
+
+    pub fn get_cells(&self, page_id: usize) -> Result<Vec<BTreeCell>, String> {
+        let page = self.page_bytes(page_id)?;
+        let btree_page = parse_btree_page(page)?;
+        parse_cells(page, &btree_page, self.header.usable_size())
+    }
+
+    /*
+     * The complete payload of a cell, following its overflow chain
+     */
+    pub fn read_payload(&self, cell: &BTreeCell) -> Result<Vec<u8>, String> {
+        let mut payload = cell.payload.clone();
+        let usable_size = self.header.usable_size() as usize;
+        let mut next_page = cell.overflow_page;
+        let mut visited = 0;
+        while let Some(page_id) = next_page {
+            if payload.len() as u64 >= cell.payload_size {
+                break
+            }
+            visited += 1;
+            if visited > self.page_count() {
+                return Err("Overflow chain contains a loop".to_string())
+            }
+            let page = self.page_bytes(page_id as usize)?;
+            let next = u32::from_be_bytes([page[0], page[1], page[2], page[3]]);
+            let remaining = cell.payload_size as usize - payload.len();
+            let length = remaining.min(usable_size - 4);
+            payload.extend_from_slice(&page[4..4 + length]);
+            next_page = if next == 0 { None } else { Some(next) };
+        }
+        if (payload.len() as u64) < cell.payload_size {
+            return Err("Overflow chain ends before the payload is complete".to_string())
+        }
+        Ok(payload)
+    }
+
+    /*
+     * All cells of a B-tree in key order. For tables these are the leaf
+     * cells, for indexes the interior cells carry keys as well and are
+     * returned between the subtrees they separate.
+     */
+    pub fn btree_cells(&self, root: u32) -> Result<Vec<BTreeCell>, String> {
+        let mut cells = vec![];
+        self.collect_cells(root, 0, &mut cells)?;
+        Ok(cells)
+    }
+
+    fn collect_cells(&self, page_id: u32, depth: usize, cells: &mut Vec<BTreeCell>)
+                     -> Result<(), String> {
+        // SQLite never builds trees deeper than this
+        if depth > 20 {
+            return Err(format!("B-tree at page {} is too deep, possible loop", page_id))
+        }
+        let page = self.get_page(page_id as usize)?;
+        let page_cells = self.get_cells(page_id as usize)?;
+        if page.header.page_type.is_leaf() {
+            cells.extend(page_cells);
+            return Ok(())
+        }
+        let is_table = page.header.page_type.is_table();
+        for cell in page_cells {
+            if let Some(child) = cell.left_child {
+                self.collect_cells(child, depth + 1, cells)?;
+            }
+            if !is_table {
+                cells.push(cell);
+            }
+        }
+        match page.header.right_most_pointer {
+            Some(child) => self.collect_cells(child, depth + 1, cells),
+            None => Ok(()),
+        }
+    }
+
+    /*
+     * Every page of a B-tree, interior pages before their children
+     */
+    pub fn btree_pages(&self, root: u32) -> Result<Vec<u32>, String> {
+        let mut pages = vec![];
+        let mut stack = vec![(root, 0)];
+        while let Some((page_id, depth)) = stack.pop() {
+            if depth > 20 {
+                return Err(format!("B-tree at page {} is too deep, possible loop", root))
+            }
+            let page = self.get_page(page_id as usize)?;
+            pages.push(page_id);
+            if !page.header.page_type.is_leaf() {
+                stack.extend(page.header.right_most_pointer.map(|x| (x, depth + 1)));
+                for cell in self.get_cells(page_id as usize)?.into_iter().rev() {
+                    stack.extend(cell.left_child.map(|x| (x, depth + 1)));
+                }
+            }
+        }
+        Ok(pages)
+    }
+
+    /*
+     * The pages on the freelist in chain order, each with whether it is a
+     * trunk page
+     */
+    pub fn freelist_pages(&self) -> Result<Vec<(u32, bool)>, String> {
+        let page_count = self.page_count();
+        let mut pages = vec![];
+        let mut trunk_id = self.header.freelist_trunk_page;
+        while trunk_id != 0 {
+            if pages.len() > page_count as usize {
+                return Err(format!("Freelist loops back to trunk page {}", trunk_id))
+            }
+            let trunk = self.page_bytes(trunk_id as usize)?;
+            let leaf_count = u32::from_be_bytes([trunk[4], trunk[5], trunk[6], trunk[7]]) as usize;
+            if 8 + 4 * leaf_count > trunk.len() {
+                return Err(format!("Freelist trunk {} has too many leaves", trunk_id))
+            }
+            pages.push((trunk_id, true));
+            for leaf in trunk[8..8 + 4 * leaf_count].chunks(4) {
+                let leaf_id = u32::from_be_bytes([leaf[0], leaf[1], leaf[2], leaf[3]]);
+                if leaf_id == 0 || leaf_id > page_count {
+                    return Err(format!("Freelist trunk {} lists invalid page {}", trunk_id, leaf_id))
+                }
+                pages.push((leaf_id, false));
+            }
+            trunk_id = u32::from_be_bytes([trunk[0], trunk[1], trunk[2], trunk[3]]);
+        }
+        Ok(pages)
+    }
+
+    /*
+     * Rowid and decoded record of every row of a table B-tree
+     */
+    pub fn table_rows(&self, root: u32) -> Result<Vec<(i64, Record)>, String> {
+        let mut rows = vec![];
+        for cell in self.btree_cells(root)? {
+            let payload = self.read_payload(&cell)?;
+            let record = parse_record(&payload, self.header.text_encoding)?;
+            rows.push((cell.rowid.unwrap_or(0), record));
+        }
+        Ok(rows)
+    }
+
+    pub fn schema(&self) -> Result<Vec<SchemaEntry>, String> {
+        let mut entries = vec![];
+        for (_, record) in self.table_rows(1)? {
+            entries.push(SchemaEntry::from_record(&record));
+        }
+        Ok(entries)
+    }
+
+    /*
+     * The order of the fields of an index record, including the rowid or
+     * primary key columns at the end. For a WITHOUT ROWID table, the order
+     * of its primary key.
+     */
+    pub fn index_key(&self, entry: &SchemaEntry) -> Result<Vec<KeyColumn>, String> {
+        let table_sql = self.schema()?.into_iter()
+            .find(|x| x.entry_type == "table" && x.name.eq_ignore_ascii_case(&entry.tbl_name))
+            .and_then(|x| x.sql)
+            .ok_or(format!("No definition of table {}", entry.tbl_name))?;
+        let table = parse_create_table(&table_sql)?;
+        let mut key = if entry.entry_type == "table" {
+            table.primary_key()
+        } else {
+            let mut key = match entry.sql {
+                Some(ref sql) => table.key_columns(&parse_create_index(sql)?.columns),
+                None => entry.name.rsplit('_').next()
+                    .and_then(|x| x.parse::<usize>().ok())
+                    .and_then(|n| table.auto_indexes().get(n.wrapping_sub(1)).cloned())
+                    .ok_or(format!("No constraint matches index {}", entry.name))?,
+            };
+            if table.without_rowid {
+                for column in table.primary_key() {
+                    if !key.iter().any(|x| x.name == column.name && x.collation == column.collation) {
+                        key.push(column);
+                    }
+                }
+            } else {
+                key.push(KeyColumn { name: None, collation: enums::Collation::Binary, descending: false });
+            }
+            key
+        };
+        // DESC indexes are stored ascending before schema format 4
+        if self.header.schema_format != enums::SchemaFormat::Format4 {
+            for column in &mut key {
+                column.descending = false;
+            }
+        }
+        Ok(key)
+    }
+
+    /*
+     * Unallocated space between the cell pointer array and the cell content
+     * area, plus all freeblocks and fragmented bytes
+     */
+    pub fn free_space(&self, page_id: usize) -> Result<u32, String> {
+        SqliteFile::page_free_space(self.page_bytes(page_id)?, page_id, self.header.usable_size())
+    }
+
+    pub fn page_free_space(page: &[u8], page_id: usize, usable_size: u32) -> Result<u32, String> {
+        let btree_page = parse_btree_page(page)?;
+        let header = btree_page.header;
+        let pointer_end = (SqliteFile::btree_header_offset(page_id)
+            + header.page_type.header_size()
+            + 2 * header.cell_count as usize) as u32;
+        let content_offset = header.cell_content_offset.min(usable_size);
+        if content_offset < pointer_end {
+            return Err(format!("Cell content of page {} overlaps the cell pointers", page_id))
+        }
+        let mut free = content_offset - pointer_end + header.fragmented_free_byte_count as u32;
+        let mut next = header.freeblock_offset;
+        while let Some(offset) = next {
+            let offset = offset as usize;
+            if offset + 4 > usable_size as usize || offset < content_offset as usize {
+                return Err(format!("Freeblock at {} on page {} is out of bounds", offset, page_id))
+            }
+            let next_offset = u16::from_be_bytes([page[offset], page[offset + 1]]);
+            free += u16::from_be_bytes([page[offset + 2], page[offset + 3]]) as u32;
+            if next_offset != 0 && next_offset as usize <= offset {
+                return Err(format!("Freeblock chain on page {} is not ascending", page_id))
+            }
+            next = if next_offset == 0 { None } else { Some(next_offset) };
+        }
+        Ok(free)
+    }
+
+    /*
+     * Header checks plus everything that needs the actual file length
+     */
+    pub fn validate(&self) -> Vec<HeaderWarning> {
+        let mut warnings = self.header.validate();
+        let page_size = self.header.page_size as usize;
+        if page_size == 0 {
+            return warnings
+        }
+        if !self.buffer.len().is_multiple_of(page_size) {
+            warnings.push(HeaderWarning::FileSizeNotPageAligned(self.buffer.len()));
+        }
+        let file_pages = self.file_pages();
+        if self.header.database_size_is_valid() && self.header.database_size > file_pages {
+            warnings.push(HeaderWarning::DatabaseSizeExceedsFile(self.header.database_size, file_pages));
+        }
+        if !self.header.database_size_is_valid() {
+            warnings.extend(self.header.validate_page_count(self.page_count()));
+        }
+        warnings.extend(self.header.feature_warnings(&self.schema_features()));
+        warnings
+    }
+
+    /*
+     * Features the schema uses. Entries whose SQL can't be read are skipped.
+     */
+    pub fn schema_features(&self) -> Vec<Feature> {
+        let mut unique = vec![];
+        for feature in self.schema().unwrap_or_default().iter().flat_map(|x| x.features()) {
+            if !unique.contains(&feature) {
+                unique.push(feature);
+            }
+        }
+        unique
+    }
+}
+
+#[derive(Debug,Copy,Clone)]
+pub struct JournalHeader {
+    pub record_count: u32,
+    pub nonce: u32,
+    pub initial_size: u32,
+    pub sector_size: u32,
+    pub page_size: u32,
+}
+
+#[derive(Debug,Clone)]
+pub struct JournalRecord {
+    pub page_number: u32,
+    pub data: Vec<u8>,
+    pub checksum: u32,
+}
+
+#[derive(Debug,Clone)]
+pub struct JournalSegment {
+    pub header: JournalHeader,
+    pub records: Vec<JournalRecord>,
+}
+
+#[derive(Debug,Clone)]
+pub struct Journal {
+    pub segments: Vec<JournalSegment>,
+}
+
+impl JournalHeader {
+    /*
+     * SQLite only samples every 200th byte of the page, starting from the end
+     */
+    pub fn checksum(&self, data: &[u8]) -> u32 {
+        let mut checksum = self.nonce;
+        let mut i = data.len() as isize - 200;
+        while i > 0 {
+            checksum = checksum.wrapping_add(data[i as usize] as u32);
+            i -= 200;
+        }
+        checksum
+    }
+
+    /*
+     * The header is padded to a full sector
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = JOURNAL_MAGIC.to_vec();
+        for x in &[self.record_count, self.nonce, self.initial_size, self.sector_size, self.page_size] {
+            bytes.extend_from_slice(&x.to_be_bytes());
+        }
+        bytes.resize(self.sector_size as usize, 0);
+        bytes
+    }
+}
+
+impl JournalRecord {
+    pub fn is_valid(&self, header: &JournalHeader) -> bool {
+        self.checksum == header.checksum(&self.data)
+    }
+}
+
+impl Journal {
+    /*
+     * Each segment starts on a sector boundary
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        for segment in &self.segments {
+            bytes.extend(segment.header.to_bytes());
+            for record in &segment.records {
+                bytes.extend_from_slice(&record.page_number.to_be_bytes());
+                bytes.extend_from_slice(&record.data);
+                bytes.extend_from_slice(&record.checksum.to_be_bytes());
+            }
+            let sector_size = segment.header.sector_size as usize;
+            bytes.resize(bytes.len().div_ceil(sector_size) * sector_size, 0);
+        }
+        bytes
+    }
+
+    pub fn initial_size(&self) -> u32 {
+        self.segments.first().map_or(0, |s| s.header.initial_size)
+    }
+
+    /*
+     * Records in playback order. Playback stops at the first record with a
+     * bad checksum, since everything after it may be a torn write.
+     */
+    pub fn valid_records(&self) -> Vec<&JournalRecord> {
+        let mut records = vec![];
+        for segment in &self.segments {
+            for record in &segment.records {
+                if !record.is_valid(&segment.header) {
+                    return records
+                }
+                records.push(record);
+            }
+        }
+        records
+    }
+
+    /*
+     * The journaled copy of page 1 carries the file change counter from
+     * before the interrupted transaction.
+     */
+    pub fn original_change_counter(&self) -> Option<u32> {
+        self.valid_records().iter()
+            .find(|r| r.page_number == 1)
+            .and_then(|r| parse_header(&r.data).ok())
+            .map(|h| h.file_change_counter)
+    }
+
+    /*
+     * A journal is hot if rolling it back would change the database. A
+     * differing change counter on page 1 settles it quickly, otherwise each
+     * journaled page is compared with the current content of the file.
+     */
+    pub fn is_hot(&self, file: &SqliteFile) -> bool {
+        let records = self.valid_records();
+        if records.is_empty() {
+            return false
+        }
+        if let Some(counter) = self.original_change_counter() {
+            if counter != file.header.file_change_counter {
+                return true
+            }
+        }
+        let page_size = self.segments[0].header.page_size as usize;
+        if file.as_bytes().len() != self.initial_size() as usize * page_size {
+            return true
+        }
+        records.iter()
+            .filter(|r| r.page_number >= 1 && r.page_number <= self.initial_size())
+            .any(|r| {
+                let start = (r.page_number as usize - 1) * page_size;
+                file.as_bytes().get(start..start + page_size).is_none_or(|x| x != &r.data[..])
+            })
+    }
+
+    /*
+     * Present the database the way SQLite would see it after rolling back
+     * the journal on the next open.
+     */
+    pub fn rollback(&self, file: &SqliteFile) -> Result<SqliteFile, String> {
+        let page_size = match self.segments.first() {
+            Some(segment) => segment.header.page_size as usize,
+            None => return Err("Journal has no segments".to_string()),
+        };
+        let mut buffer = file.as_bytes().to_vec();
+        for record in self.valid_records() {
+            if record.page_number == 0 {
+                return Err("Journal record for page 0".to_string())
+            }
+            if record.page_number > self.initial_size() {
+                continue
+            }
+            let start = (record.page_number as usize - 1) * page_size;
+            if buffer.len() < start + page_size {
+                buffer.resize(start + page_size, 0);
+            }
+            buffer[start..start + page_size].copy_from_slice(&record.data);
+        }
+        buffer.resize(self.initial_size() as usize * page_size, 0);
+        let header = parse_header(&buffer)?;
+        Ok(SqliteFile::new(header, buffer))
+    }
+}
+
+#[derive(Debug,Copy,Clone)]
+pub struct WalHeader {
+    pub magic: u32,
+    pub format_version: u32,
+    pub page_size: u32,
+    pub checkpoint_sequence: u32,
+    pub salt: (u32, u32),
+    pub checksum: (u32, u32),
+}
+
+#[derive(Debug,Clone)]
+pub struct WalFrame {
+    pub page_number: u32,
+    pub commit_size: u32,
+    pub salt: (u32, u32),
+    pub checksum: (u32, u32),
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug,Clone)]
+pub struct Wal {
+    pub header: WalHeader,
+    pub frames: Vec<WalFrame>,
+}
+
+impl WalHeader {
+    pub fn big_endian_checksums(&self) -> bool {
+        self.magic & 1 == 1
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let bytes = self.to_bytes();
+        wal_checksum(self.big_endian_checksums(), &bytes[..24], (0, 0)) == self.checksum
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        let fields = [self.magic, self.format_version, self.page_size, self.checkpoint_sequence,
+            self.salt.0, self.salt.1, self.checksum.0, self.checksum.1];
+        for (i, value) in fields.iter().enumerate() {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&value.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+impl WalFrame {
+    pub fn is_commit(&self) -> bool {
+        self.commit_size != 0
+    }
+}
+
+impl Wal {
+    /*
+     * Number of frames SQLite would accept. A frame is valid if its salt
+     * matches the header and the checksum chain, which covers every frame
+     * before it, is intact. Frames after the first invalid one are left over
+     * from before the last WAL restart.
+     */
+    pub fn valid_frame_count(&self) -> usize {
+        let big_endian = self.header.big_endian_checksums();
+        let mut checksum = self.header.checksum;
+        for (i, frame) in self.frames.iter().enumerate() {
+            if frame.salt != self.header.salt {
+                return i
+            }
+            let mut frame_header = [0; 8];
+            frame_header[..4].copy_from_slice(&frame.page_number.to_be_bytes());
+            frame_header[4..].copy_from_slice(&frame.commit_size.to_be_bytes());
+            checksum = wal_checksum(big_endian, &frame_header, checksum);
+            checksum = wal_checksum(big_endian, &frame.data, checksum);
+            if checksum != frame.checksum {
+                return i
+            }
+        }
+        self.frames.len()
+    }
+
+    /*
+     * Frames up to and including the last valid commit frame, which is
+     * what a reader sees when opening the database.
+     */
+    pub fn committed_frames(&self) -> &[WalFrame] {
+        let valid = &self.frames[..self.valid_frame_count()];
+        match valid.iter().rposition(|f| f.is_commit()) {
+            Some(i) => &valid[..i + 1],
+            None => &[],
+        }
+    }
+
+    /*
+     * Present the database the way a reader sees it: the latest committed
+     * version of each page in the WAL replaces the one in the file, and the
+     * last commit frame gives the database size.
+     */
+    pub fn checkpoint(&self, file: &SqliteFile) -> Result<SqliteFile, String> {
+        let frames = self.committed_frames();
+        let commit_size = match frames.last() {
+            Some(frame) => frame.commit_size as usize,
+            None => return Ok(SqliteFile::new(file.header, file.as_bytes().to_vec())),
+        };
+        let page_size = self.header.page_size as usize;
+        if page_size != file.header.page_size as usize {
+            return Err(format!("WAL page size {} differs from the database", page_size))
+        }
+        let mut buffer = file.as_bytes().to_vec();
+        buffer.resize(commit_size * page_size, 0);
+        for frame in frames {
+            if frame.page_number == 0 {
+                return Err("WAL frame for page 0".to_string())
+            }
+            let page_number = frame.page_number as usize;
+            if page_number <= commit_size {
+                buffer[(page_number - 1) * page_size..page_number * page_size].copy_from_slice(&frame.data);
+            }
+        }
+        let header = parse_header(&buffer)?;
+        Ok(SqliteFile::new(header, buffer))
+    }
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum ChangeOperation {
+    Insert,
+    Delete,
+    Update,
+}
+
+/*
+ * One row of a changeset. Values that are not part of the change are None:
+ * the columns an UPDATE left alone, and in a patchset everything but the
+ * primary key of a DELETE and the old values of an UPDATE.
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct Change {
+    pub operation: ChangeOperation,
+    // Made by a trigger or foreign key action rather than the application
+    pub indirect: bool,
+    pub old: Vec<Option<Value>>,
+    pub new: Vec<Option<Value>>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct ChangesetTable {
+    pub name: String,
+    pub primary_key: Vec<bool>,
+    pub changes: Vec<Change>,
+}
+
+/*
+ * The output of the session extension. A patchset leaves out the old values
+ * that a changeset keeps so it can be inverted and checked for conflicts.
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct Changeset {
+    pub patchset: bool,
+    pub tables: Vec<ChangesetTable>,
+}
+
+impl ChangeOperation {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ChangeOperation::Insert => 18,
+            ChangeOperation::Delete => 9,
+            ChangeOperation::Update => 23,
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/*
+ * TEXT is a JSON string and a BLOB an object with its bytes in hex, so the
+ * two can be told apart
+ */
+fn json_value(value: &Value) -> String {
+    match *value {
+        Value::Null => "null".to_string(),
+        Value::Integer(x) => x.to_string(),
+        Value::Real(x) if x.is_nan() => "null".to_string(),
+        Value::Real(x) if x.is_infinite() => if x < 0.0 { "-1e999" } else { "1e999" }.to_string(),
+        Value::Real(x) if x.fract() == 0.0 && x.abs() < 1e15 => format!("{:.1}", x),
+        Value::Real(x) => format!("{:?}", x),
+        Value::Text(ref x) => json_string(x),
+        Value::Blob(ref x) => {
+            let hex: String = x.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{{\"blob\":\"{}\"}}", hex)
+        },
+    }
+}
+
+/*
+ * Values of a change by column number, leaving out those that are not part
+ * of it
+ */
+fn json_values(values: &[Option<Value>]) -> String {
+    let fields: Vec<String> = values.iter().enumerate()
+        .filter_map(|(i, x)| x.as_ref().map(|x| format!("\"{}\":{}", i, json_value(x))))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+impl Changeset {
+    /*
+     * Serialise in the format of sqlite3session_changeset() or
+     * sqlite3session_patchset()
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        let value = |bytes: &mut Vec<u8>, value: &Option<Value>| match *value {
+            None => bytes.push(0),
+            Some(Value::Null) => bytes.push(5),
+            Some(Value::Integer(x)) => { bytes.push(1); bytes.extend_from_slice(&x.to_be_bytes()) },
+            Some(Value::Real(x)) => { bytes.push(2); bytes.extend_from_slice(&x.to_bits().to_be_bytes()) },
+            Some(Value::Text(ref x)) => {
+                bytes.push(3);
+                bytes.extend(encode_varint(x.len() as u64));
+                bytes.extend_from_slice(x.as_bytes());
+            },
+            Some(Value::Blob(ref x)) => {
+                bytes.push(4);
+                bytes.extend(encode_varint(x.len() as u64));
+                bytes.extend_from_slice(x);
+            },
+        };
+        for table in &self.tables {
+            bytes.push(if self.patchset { b'P' } else { b'T' });
+            bytes.extend(encode_varint(table.primary_key.len() as u64));
+            bytes.extend(table.primary_key.iter().map(|&x| x as u8));
+            bytes.extend_from_slice(table.name.as_bytes());
+            bytes.push(0);
+            for change in &table.changes {
+                bytes.push(change.operation.to_u8());
+                bytes.push(change.indirect as u8);
+                let primary_key = change.old.iter().zip(&table.primary_key).filter(|x| *x.1).map(|x| x.0);
+                match (change.operation, self.patchset) {
+                    (ChangeOperation::Delete, true) => primary_key.for_each(|x| value(&mut bytes, x)),
+                    (ChangeOperation::Update, true) => (),
+                    _ => change.old.iter().for_each(|x| value(&mut bytes, x)),
+                }
+                change.new.iter().for_each(|x| value(&mut bytes, x));
+            }
         }
+        bytes
+    }
+
+    pub fn to_json(&self) -> String {
+        let tables: Vec<String> = self.tables.iter().map(|table| {
+            let changes: Vec<String> = table.changes.iter().map(|change| {
+                format!("{{\"operation\":\"{}\",\"indirect\":{},\"old\":{},\"new\":{}}}",
+                        change.operation, change.indirect, json_values(&change.old), json_values(&change.new))
+            }).collect();
+            let primary_key: Vec<String> = table.primary_key.iter().map(|x| x.to_string()).collect();
+            format!("{{\"name\":{},\"primary_key\":[{}],\"changes\":[{}]}}",
+                    json_string(&table.name), primary_key.join(","), changes.join(","))
+        }).collect();
+        format!("{{\"patchset\":{},\"tables\":[{}]}}", self.patchset, tables.join(","))
     }
 }
 
@@ -120,6 +1549,12 @@ impl std::fmt::Display for Header {
     }
 }
 
+impl std::fmt::Display for SqliteVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
 impl std::fmt::Display for SqliteFile {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f,
@@ -130,9 +1565,156 @@ impl std::fmt::Display for SqliteFile {
 }
 
 
+impl std::fmt::Display for JournalHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "JournalHeader {{ \
+            record_count: {}, \
+            nonce: {}, \
+            initial_size: {}, \
+            sector_size: {}, \
+            page_size: {} \
+            }}",
+            self.record_count,
+            self.nonce,
+            self.initial_size,
+            self.sector_size,
+            self.page_size,
+        )
+    }
+}
+
+/*
+ * Values as SQL literals
+ */
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(x) => write!(f, "{}", x),
+            // Keep a fractional part so the value reads back as REAL
+            Value::Real(x) if x.is_finite() && x.fract() == 0.0 && x.abs() < 1e15 => write!(f, "{:.1}", x),
+            Value::Real(x) if x.is_nan() => write!(f, "NULL"),
+            // Too large for a double, which SQLite reads back as infinity
+            Value::Real(x) if x.is_infinite() => write!(f, "{}9.0e+999", if x < 0.0 { "-" } else { "" }),
+            Value::Real(x) => write!(f, "{:?}", x),
+            Value::Text(ref x) => write!(f, "'{}'", x.replace('\'', "''")),
+            Value::Blob(ref x) => {
+                write!(f, "X'")?;
+                for byte in x {
+                    write!(f, "{:02X}", byte)?;
+                }
+                write!(f, "'")
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ChangeOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   ChangeOperation::Insert => "INSERT",
+                   ChangeOperation::Delete => "DELETE",
+                   ChangeOperation::Update => "UPDATE",
+               })
+    }
+}
+
+/*
+ * Values that are not part of a change are shown as -
+ */
+fn value_tuple(values: &[Option<Value>]) -> String {
+    let values: Vec<String> = values.iter()
+        .map(|x| x.as_ref().map_or("-".to_string(), |x| x.to_string()))
+        .collect();
+    format!("({})", values.join(", "))
+}
+
+impl std::fmt::Display for ChangesetTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let primary_key: Vec<String> = self.primary_key.iter().enumerate()
+            .filter(|x| *x.1)
+            .map(|x| x.0.to_string())
+            .collect();
+        write!(f, "Table {}: {} columns, primary key ({})", self.name, self.primary_key.len(),
+               primary_key.join(", "))?;
+        for change in &self.changes {
+            write!(f, "\n  {} ", change.operation)?;
+            match change.operation {
+                ChangeOperation::Insert => write!(f, "{}", value_tuple(&change.new))?,
+                ChangeOperation::Delete => write!(f, "{}", value_tuple(&change.old))?,
+                ChangeOperation::Update if change.old.is_empty() => {
+                    write!(f, "{}", value_tuple(&change.new))?
+                },
+                ChangeOperation::Update => {
+                    write!(f, "{} -> {}", value_tuple(&change.old), value_tuple(&change.new))?
+                },
+            }
+            if change.indirect {
+                write!(f, " (indirect)")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Changeset {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let changes: usize = self.tables.iter().map(|x| x.changes.len()).sum();
+        write!(f, "{} with {} changes", if self.patchset { "Patchset" } else { "Changeset" }, changes)?;
+        for table in &self.tables {
+            write!(f, "\n{}", table)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use enums;
+    use parser::parse_create_table;
+
+    #[test]
+    fn auto_indexes_in_creation_order() {
+        let table = parse_create_table("CREATE TABLE u(x TEXT UNIQUE COLLATE nocase, \
+                                        y INTEGER PRIMARY KEY DESC, z, UNIQUE(z, x), UNIQUE(x))").unwrap();
+        let names = |key: &Vec<super::KeyColumn>| -> Vec<(String, String, bool)> {
+            key.iter()
+                .map(|x| (x.name.clone().unwrap(), x.collation.to_string(), x.descending))
+                .collect()
+        };
+        let indexes: Vec<_> = table.auto_indexes().iter().map(names).collect();
+        assert_eq!(vec![
+            vec![("x".to_string(), "NOCASE".to_string(), false)],
+            vec![("y".to_string(), "BINARY".to_string(), true)],
+            vec![("z".to_string(), "BINARY".to_string(), false),
+                 ("x".to_string(), "NOCASE".to_string(), false)],
+        ], indexes);
+
+        // An INTEGER PRIMARY KEY is the rowid and has no index
+        let table = parse_create_table("CREATE TABLE t(id integer, a, PRIMARY KEY(id DESC), UNIQUE(a))")
+            .unwrap();
+        assert_eq!(1, table.auto_indexes().len());
+    }
+
+    #[test]
+    fn value_order() {
+        use super::Value::*;
+        let binary = enums::Collation::Binary;
+        let values = [Null, Integer(-1), Real(0.5), Integer(1), Text("A".to_string()),
+                      Text("a".to_string()), Blob(vec![0])];
+        for pair in values.windows(2) {
+            assert_eq!(Some(::std::cmp::Ordering::Less),
+                       pair[0].compare(&pair[1], &binary, enums::TextEncoding::UTF8));
+        }
+        assert_eq!(Some(::std::cmp::Ordering::Equal),
+                   values[4].compare(&values[5], &enums::Collation::NoCase, enums::TextEncoding::UTF8));
+        let mine = enums::Collation::from("mine");
+        assert_eq!(None, values[4].compare(&values[5], &mine, enums::TextEncoding::UTF8));
+    }
     #[test]
     fn test_header_print() {
         let header = super::Header {
@@ -155,6 +1737,7 @@ mod tests {
             user_version: 123123,
             incremental_vacuum_mode: true,
             application_id: 111_111,
+            reserved: [0; 20],
             version_valid_for: 1234567,
             sqlite_version: 3008008,
         };
@@ -185,4 +1768,217 @@ mod tests {
             sqlite_version: 3008008 }"
         )
     }
-}
\ No newline at end of file
+
+    fn valid_header() -> super::Header {
+        super::Header {
+            page_size: 4096,
+            read_version: enums::ReadVersion::Legacy,
+            write_version: enums::WriteVersion::Legacy,
+            reserved_space: 0,
+            max_embedded_payload_fraction: 64,
+            min_embedded_payload_fraction: 32,
+            leaf_payload_fraction: 32,
+            file_change_counter: 3,
+            database_size: 10,
+            freelist_trunk_page: 0,
+            freelist_count: 0,
+            schema_cookie: 1,
+            schema_format: enums::SchemaFormat::Format4,
+            default_page_cache_size: 0,
+            largest_root_page: 0,
+            text_encoding: enums::TextEncoding::UTF8,
+            user_version: 0,
+            incremental_vacuum_mode: false,
+            application_id: 0,
+            reserved: [0; 20],
+            version_valid_for: 3,
+            sqlite_version: 3008008,
+        }
+    }
+
+    #[test]
+    fn test_header_validate() {
+        use enums::HeaderWarning;
+        assert_eq!(valid_header().validate(), vec![]);
+
+        let mut header = valid_header();
+        header.page_size = 1000;
+        header.leaf_payload_fraction = 33;
+        header.reserved[3] = 1;
+        header.version_valid_for = 2;
+        assert_eq!(header.validate(), vec![
+            HeaderWarning::InvalidPageSize(1000),
+            HeaderWarning::InvalidPayloadFractions(64, 32, 33),
+            HeaderWarning::NonZeroReservedBytes,
+            HeaderWarning::StaleDatabaseSize(2, 3),
+        ]);
+
+        let mut header = valid_header();
+        header.freelist_count = 12;
+        header.freelist_trunk_page = 11;
+        assert_eq!(header.validate(), vec![
+            HeaderWarning::FreelistCountExceedsPageCount(12, 10),
+            HeaderWarning::FreelistTrunkOutOfRange(11, 10),
+        ]);
+    }
+
+    #[test]
+    fn test_page_count() {
+        let buffer = vec![0; 4096 * 12];
+        let file = super::SqliteFile::new(valid_header(), buffer.clone());
+        assert_eq!(10, file.page_count());
+
+        let mut header = valid_header();
+        header.version_valid_for = 2;
+        let file = super::SqliteFile::new(header, buffer.clone());
+        assert_eq!(12, file.page_count());
+
+        let mut header = valid_header();
+        header.database_size = 0;
+        let file = super::SqliteFile::new(header, buffer.clone());
+        assert_eq!(12, file.page_count());
+
+        // A size beyond the end of the file is never trusted
+        let mut header = valid_header();
+        header.database_size = 0x1000_0000;
+        let file = super::SqliteFile::new(header, buffer);
+        assert_eq!(12, file.page_count());
+        assert!(file.validate().contains(&enums::HeaderWarning::DatabaseSizeExceedsFile(0x1000_0000, 12)));
+    }
+
+    #[test]
+    fn test_invalid_page_layout() {
+        let mut header = valid_header();
+        header.page_size = 200;
+        header.reserved_space = 210;
+        assert_eq!(0, header.usable_size());
+        assert!(!header.page_layout_is_valid());
+        let file = super::SqliteFile::new(header, vec![0; 4096]);
+        assert!(file.page_bytes(1).is_err());
+        assert!(file.schema().is_err());
+    }
+
+    #[test]
+    fn test_update_header() {
+        let mut buffer = vec![0; 4096 * 10];
+        buffer[..100].copy_from_slice(&valid_header().to_bytes());
+        let mut file = super::SqliteFile::new(valid_header(), buffer);
+        file.update_header(|h| {
+            h.set_field("user_version", "-1")?;
+            h.set_field("write_version", "wal")
+        }).unwrap();
+        assert_eq!(0xFFFF_FFFF, file.header.user_version);
+        assert_eq!(enums::WriteVersion::WAL, file.header.write_version);
+        assert_eq!(4, file.header.file_change_counter);
+        assert_eq!(4, file.header.version_valid_for);
+        assert_eq!(&file.header.to_bytes()[..], &file.as_bytes()[..100]);
+
+        assert!(file.update_header(|h| h.set_field("page_size", "1024")).is_err());
+        assert!(file.update_header(|h| h.set_field("incremental_vacuum_mode", "1")).is_err());
+        assert_eq!(4, file.header.file_change_counter);
+    }
+
+    #[test]
+    fn test_sqlite_version() {
+        use super::SqliteVersion;
+        use enums::{Feature, HeaderWarning};
+        let version = SqliteVersion::from(3_008_008);
+        assert_eq!(SqliteVersion { major: 3, minor: 8, patch: 8 }, version);
+        assert_eq!("3.8.8", format!("{}", version));
+        assert_eq!(3_008_008, version.to_u32());
+        assert!(version < SqliteVersion::from(3_037_000));
+        assert!(version > SqliteVersion::from(3_007_017));
+        assert!(version.could_produce(Feature::WithoutRowid));
+        assert!(!version.could_produce(Feature::StrictTables));
+
+        let mut header = valid_header();
+        header.sqlite_version = 3_002_000;
+        assert_eq!(header.validate(), vec![
+            HeaderWarning::FeatureNewerThanWriter(Feature::DescendingIndexes, header.writer_version()),
+        ]);
+
+        // Without a version, only features from before 3.7.0 can be in use
+        header.sqlite_version = 0;
+        assert!(header.validate().is_empty());
+        header.write_version = enums::WriteVersion::WAL;
+        header.read_version = enums::ReadVersion::WAL;
+        assert_eq!(header.validate(),
+                   vec![HeaderWarning::FeatureWithoutWriterVersion(Feature::WriteAheadLog)]);
+    }
+
+    #[test]
+    fn test_schema_features() {
+        use enums::{Feature, HeaderWarning};
+        use writer::DatabaseBuilder;
+        use Parser;
+
+        let mut builder = DatabaseBuilder::new();
+        builder.add_table("s", "CREATE TABLE s(a INTEGER, b INTEGER AS (a + 1)) STRICT", vec![]).unwrap();
+        let mut buffer = builder.build().unwrap();
+        let file = super::SqliteFile::from_vec(&buffer).unwrap();
+        assert_eq!(vec![Feature::StrictTables, Feature::GeneratedColumns], file.schema_features());
+        assert_eq!(3_037_000, file.header.sqlite_version);
+        assert!(file.validate().is_empty());
+
+        buffer[96..100].copy_from_slice(&3_031_000u32.to_be_bytes());
+        let file = super::SqliteFile::from_vec(&buffer).unwrap();
+        let version = file.header.writer_version();
+        assert_eq!(vec![HeaderWarning::FeatureNewerThanWriter(Feature::StrictTables, version)],
+                   file.validate());
+
+        buffer[96..100].copy_from_slice(&[0; 4]);
+        let file = super::SqliteFile::from_vec(&buffer).unwrap();
+        assert_eq!(vec![HeaderWarning::FeatureWithoutWriterVersion(Feature::StrictTables),
+                        HeaderWarning::FeatureWithoutWriterVersion(Feature::GeneratedColumns)],
+                   file.validate());
+    }
+
+    #[test]
+    fn test_record_integer_widths() {
+        use super::{Record, Value};
+        use parser::parse_record;
+
+        let cases: [(i64, u64); 28] = [
+            (0, 8), (1, 9), (2, 1), (-1, 1),
+            (127, 1), (128, 2), (-128, 1), (-129, 2),
+            (32767, 2), (32768, 3), (-32768, 2), (-32769, 3),
+            (8388607, 3), (8388608, 4), (-8388608, 3), (-8388609, 4),
+            (2147483647, 4), (2147483648, 5), (-2147483648, 4), (-2147483649, 5),
+            (140737488355327, 5), (140737488355328, 6),
+            (-140737488355328, 5), (-140737488355329, 6),
+            (i64::MAX, 6), (i64::MIN, 6), (255, 2), (-256, 2),
+        ];
+        for &(x, serial_type) in cases.iter() {
+            let values = vec![Value::Integer(x), Value::Text("a".to_string())];
+            let bytes = Record::encode(&values);
+            let record = parse_record(&bytes, enums::TextEncoding::UTF8).unwrap();
+            assert_eq!(vec![serial_type, 15], record.serial_types, "serial type of {}", x);
+            assert_eq!(values, record.values);
+        }
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        use super::{Record, Value};
+        use parser::parse_record;
+
+        let values = vec![
+            Value::Null,
+            Value::Real(-0.5),
+            Value::Text("h\u{e9}llo".to_string()),
+            Value::Blob(vec![0, 1, 2]),
+            Value::Integer(-7),
+        ];
+        for &encoding in &[enums::TextEncoding::UTF8, enums::TextEncoding::UTF16le,
+                           enums::TextEncoding::UTF16be] {
+            let bytes = Record::encode_with_encoding(&values, encoding);
+            let record = parse_record(&bytes, encoding).unwrap();
+            assert_eq!(values, record.values);
+        }
+        // A header longer than 127 bytes needs a two byte size varint
+        let values = vec![Value::Integer(5); 200];
+        let bytes = Record::encode(&values);
+        assert_eq!(&[0x81, 0x4a], &bytes[..2]);
+        assert_eq!(values, parse_record(&bytes, enums::TextEncoding::UTF8).unwrap().values);
+    }
+}
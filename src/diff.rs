@@ -0,0 +1,815 @@
+/*
+ * Comparing two databases. Schema objects are matched by type and name, and
+ * the rows of tables both have by primary key, or by rowid for tables that
+ * don't declare one. Tables whose key differs between the two can only be
+ * compared through their schema.
+ */
+extern crate std;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use data_structures::{ColumnDefinition, Header, IndexedColumn, KeyColumn, SchemaEntry, SqliteFile,
+                      TableDefinition, Value};
+use enums::{Affinity, Collation};
+use parser::{parse_create_table, parse_record};
+use writer::compare_keys;
+
+// The words sqlite3_keyword_check() knows
+const KEYWORDS: &[&str] = &[
+    "ABORT", "ACTION", "ADD", "AFTER", "ALL", "ALTER", "ALWAYS", "ANALYZE", "AND", "AS", "ASC", "ATTACH",
+    "AUTOINCREMENT", "BEFORE", "BEGIN", "BETWEEN", "BY", "CASCADE", "CASE", "CAST", "CHECK", "COLLATE",
+    "COLUMN", "COMMIT", "CONFLICT", "CONSTRAINT", "CREATE", "CROSS", "CURRENT", "CURRENT_DATE",
+    "CURRENT_TIME", "CURRENT_TIMESTAMP", "DATABASE", "DEFAULT", "DEFERRABLE", "DEFERRED", "DELETE", "DESC",
+    "DETACH", "DISTINCT", "DO", "DROP", "EACH", "ELSE", "END", "ESCAPE", "EXCEPT", "EXCLUDE", "EXCLUSIVE",
+    "EXISTS", "EXPLAIN", "FAIL", "FILTER", "FIRST", "FOLLOWING", "FOR", "FOREIGN", "FROM", "FULL",
+    "GENERATED", "GLOB", "GROUP", "GROUPS", "HAVING", "IF", "IGNORE", "IMMEDIATE", "IN", "INDEX", "INDEXED",
+    "INITIALLY", "INNER", "INSERT", "INSTEAD", "INTERSECT", "INTO", "IS", "ISNULL", "JOIN", "KEY", "LAST",
+    "LEFT", "LIKE", "LIMIT", "MATCH", "MATERIALIZED", "NATURAL", "NO", "NOT", "NOTHING", "NOTNULL", "NULL",
+    "NULLS", "OF", "OFFSET", "ON", "OR", "ORDER", "OTHERS", "OUTER", "OVER", "PARTITION", "PLAN", "PRAGMA",
+    "PRECEDING", "PRIMARY", "QUERY", "RAISE", "RANGE", "RECURSIVE", "REFERENCES", "REGEXP", "REINDEX",
+    "RELEASE", "RENAME", "REPLACE", "RESTRICT", "RETURNING", "RIGHT", "ROLLBACK", "ROW", "ROWS", "SAVEPOINT",
+    "SELECT", "SET", "TABLE", "TEMP", "TEMPORARY", "THEN", "TIES", "TO", "TRANSACTION", "TRIGGER",
+    "UNBOUNDED", "UNION", "UNIQUE", "UPDATE", "USING", "VACUUM", "VALUES", "VIEW", "VIRTUAL", "WHEN", "WHERE",
+    "WINDOW", "WITH", "WITHOUT",
+];
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum SchemaChange {
+    Added(SchemaEntry),
+    Dropped(SchemaEntry),
+    // Same type and name, different SQL
+    Changed(SchemaEntry, SchemaEntry),
+    ColumnAdded(String, String),
+    ColumnDropped(String, String),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct ColumnChange {
+    pub column: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+/*
+ * Rows are given in the column order of the database they are from
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub enum RowChange {
+    Inserted(Vec<Value>),
+    Deleted(Vec<Value>),
+    Updated(Vec<ColumnChange>),
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct RowDiff {
+    pub key: Vec<Value>,
+    pub change: RowChange,
+}
+
+/*
+ * The rows that differ in a table both databases have. The key is the
+ * primary key, or just rowid, and the columns are those of the second
+ * database, in order.
+ */
+#[derive(Debug,Clone)]
+pub struct TableDiff {
+    pub name: String,
+    pub key: Vec<String>,
+    pub columns: Vec<String>,
+    pub rows: Vec<RowDiff>,
+}
+
+#[derive(Debug,Clone)]
+pub struct Diff {
+    pub schema: Vec<SchemaChange>,
+    pub tables: Vec<TableDiff>,
+}
+
+/*
+ * What a page is used for. B-trees are those of tables and indexes, and
+ * overflow pages belong to the B-tree of the cell they continue.
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub enum PageOwner {
+    BTree(String),
+    Overflow(String),
+    Freelist,
+    PointerMap,
+}
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum PageChange {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug,Clone)]
+pub struct ChangedPage {
+    pub page_number: u32,
+    pub change: PageChange,
+    pub old_owner: Option<PageOwner>,
+    pub new_owner: Option<PageOwner>,
+}
+
+#[derive(Debug,Clone)]
+pub struct PageDiff {
+    pub old_header: Header,
+    pub new_header: Header,
+    pub pages: Vec<ChangedPage>,
+}
+
+/*
+ * A table with its values in the order of its columns, and the key of each
+ * row in front. Records written before an ALTER TABLE ADD COLUMN are
+ * shorter, the missing columns read as their DEFAULT.
+ */
+struct Table {
+    definition: TableDefinition,
+    rows: Vec<(Vec<Value>, Vec<Value>)>,
+}
+
+fn column_names(definition: &TableDefinition) -> Vec<String> {
+    definition.columns.iter().map(|x| x.name.clone()).collect()
+}
+
+fn key_names(definition: &TableDefinition) -> Vec<String> {
+    match definition.primary_key().into_iter().map(|x| x.name).collect::<Option<Vec<String>>>() {
+        Some(ref names) if names.is_empty() => vec!["rowid".to_string()],
+        Some(names) => names,
+        None => vec![],
+    }
+}
+
+fn position(names: &[String], name: &str) -> Option<usize> {
+    names.iter().position(|x| x.eq_ignore_ascii_case(name))
+}
+
+/*
+ * What a column reads as when the record is too short for it, because the
+ * column was added after the row was written
+ */
+fn defaults(definition: &TableDefinition) -> Vec<Value> {
+    definition.columns.iter().map(|x| x.default.clone().unwrap_or(Value::Null)).collect()
+}
+
+fn read_table(file: &SqliteFile, entry: &SchemaEntry, key: &[String]) -> Result<Table, String> {
+    let definition = parse_create_table(entry.sql.as_ref().map_or("", |x| x.as_str()))?;
+    let columns = column_names(&definition);
+    let mut rows = vec![];
+    if definition.without_rowid {
+        let record_columns = definition.record_columns();
+        for cell in file.btree_cells(entry.rootpage)? {
+            let record = parse_record(&file.read_payload(&cell)?, file.header.text_encoding)?;
+            let mut values = defaults(&definition);
+            for (name, value) in record_columns.iter().zip(record.values) {
+                if let Some(i) = position(&columns, name) {
+                    values[i] = value;
+                }
+            }
+            rows.push((vec![], values));
+        }
+    } else {
+        for (rowid, record) in file.table_rows(entry.rootpage)? {
+            let mut values = record.values;
+            let count = values.len();
+            values.extend(defaults(&definition).into_iter().skip(count));
+            if let Some(alias) = definition.rowid_alias() {
+                values[alias] = Value::Integer(rowid);
+            }
+            rows.push((vec![Value::Integer(rowid)], values));
+        }
+    }
+    // REAL columns store whole numbers as integers
+    for (i, column) in definition.columns.iter().enumerate() {
+        if column.affinity() == Affinity::Real {
+            for row in &mut rows {
+                if let Value::Integer(x) = row.1[i] {
+                    row.1[i] = Value::Real(x as f64);
+                }
+            }
+        }
+    }
+    for row in &mut rows {
+        let mut values = vec![];
+        for name in key {
+            match position(&columns, name) {
+                Some(i) => values.push(row.1[i].clone()),
+                None if name == "rowid" => values.extend(row.0.first().cloned()),
+                None => return Err(format!("Table {} has no column {}", entry.name, name)),
+            }
+        }
+        row.0 = values;
+    }
+    Ok(Table { definition, rows })
+}
+
+/*
+ * Columns added and dropped, then the SQL if the columns are the same
+ */
+fn schema_changes(old: &[SchemaEntry], new: &[SchemaEntry]) -> Vec<SchemaChange> {
+    let find = |entries: &[SchemaEntry], entry: &SchemaEntry| {
+        entries.iter().find(|x| x.entry_type == entry.entry_type && x.name.eq_ignore_ascii_case(&entry.name))
+            .cloned()
+    };
+    let mut changes = vec![];
+    for entry in old {
+        if find(new, entry).is_none() {
+            changes.push(SchemaChange::Dropped(entry.clone()));
+        }
+    }
+    for entry in new {
+        let old_entry = match find(old, entry) {
+            Some(x) => x,
+            None => { changes.push(SchemaChange::Added(entry.clone())); continue },
+        };
+        if old_entry.sql == entry.sql {
+            continue
+        }
+        let definitions = (old_entry.sql.as_ref().and_then(|x| parse_create_table(x).ok()),
+                           entry.sql.as_ref().and_then(|x| parse_create_table(x).ok()));
+        let mut column_changes = vec![];
+        if let (Some(a), Some(b)) = definitions {
+            let (a, b) = (column_names(&a), column_names(&b));
+            for column in a.iter().filter(|x| position(&b, x).is_none()) {
+                column_changes.push(SchemaChange::ColumnDropped(entry.name.clone(), column.clone()));
+            }
+            for column in b.iter().filter(|x| position(&a, x).is_none()) {
+                column_changes.push(SchemaChange::ColumnAdded(entry.name.clone(), column.clone()));
+            }
+        }
+        if column_changes.is_empty() {
+            changes.push(SchemaChange::Changed(old_entry, entry.clone()));
+        }
+        changes.extend(column_changes);
+    }
+    changes
+}
+
+/*
+ * The rows of two versions of a table that differ, in key order. Columns
+ * the first version lacks have their DEFAULT there, like after ALTER TABLE
+ * ADD COLUMN, and those the second version lacks are not compared.
+ */
+fn diff_table(old: &Table, new: &Table, key: &[KeyColumn], file: &SqliteFile) -> Vec<RowDiff> {
+    let encoding = file.header.text_encoding;
+    let (old_columns, new_columns) = (column_names(&old.definition), column_names(&new.definition));
+    let columns: Vec<(Option<usize>, usize)> = new_columns.iter().enumerate()
+        .map(|(j, name)| (position(&old_columns, name), j))
+        .collect();
+    let added = defaults(&new.definition);
+    let (mut a, mut b): (Vec<_>, Vec<_>) = (old.rows.iter().collect(), new.rows.iter().collect());
+    a.sort_by(|x, y| compare_keys(&x.0, &y.0, key, encoding));
+    b.sort_by(|x, y| compare_keys(&x.0, &y.0, key, encoding));
+    let mut rows = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        let order = match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) => compare_keys(&x.0, &y.0, key, encoding),
+            (Some(_), None) => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+        match order {
+            Ordering::Less => {
+                rows.push(RowDiff { key: a[i].0.clone(), change: RowChange::Deleted(a[i].1.clone()) });
+                i += 1;
+            },
+            Ordering::Greater => {
+                rows.push(RowDiff { key: b[j].0.clone(), change: RowChange::Inserted(b[j].1.clone()) });
+                j += 1;
+            },
+            Ordering::Equal => {
+                let changes: Vec<ColumnChange> = columns.iter()
+                    .map(|&(x, y)| (y, x.map_or(added[y].clone(), |x| a[i].1[x].clone())))
+                    .filter(|&(y, ref old)| *old != b[j].1[y])
+                    .map(|(y, old)| {
+                        ColumnChange { column: new_columns[y].clone(), old, new: b[j].1[y].clone() }
+                    })
+                    .collect();
+                if !changes.is_empty() {
+                    rows.push(RowDiff { key: b[j].0.clone(), change: RowChange::Updated(changes) });
+                }
+                i += 1;
+                j += 1;
+            },
+        }
+    }
+    rows
+}
+
+/*
+ * What changed from the first database to the second. Internal sqlite_
+ * tables and virtual tables are left out of the row comparison.
+ */
+pub fn diff(old: &SqliteFile, new: &SqliteFile) -> Result<Diff, String> {
+    let (old_schema, new_schema) = (old.schema()?, new.schema()?);
+    let mut tables = vec![];
+    for entry in &new_schema {
+        let internal = entry.name.to_lowercase().starts_with("sqlite_");
+        if entry.entry_type != "table" || entry.rootpage == 0 || internal {
+            continue
+        }
+        let old_entry = match old_schema.iter()
+            .find(|x| x.entry_type == "table" && x.rootpage > 0 && x.name.eq_ignore_ascii_case(&entry.name)) {
+            Some(x) => x,
+            None => continue,
+        };
+        let definitions = (old_entry.sql.as_ref().and_then(|x| parse_create_table(x).ok()),
+                           entry.sql.as_ref().and_then(|x| parse_create_table(x).ok()));
+        let (old_definition, new_definition) = match definitions {
+            (Some(a), Some(b)) => (a, b),
+            _ => continue,
+        };
+        let key = key_names(&new_definition);
+        let same_key = key_names(&old_definition).iter().map(|x| x.to_lowercase())
+            .eq(key.iter().map(|x| x.to_lowercase()));
+        if key.is_empty() || !same_key {
+            continue
+        }
+        let key_columns = match key.as_slice() {
+            [rowid] if rowid == "rowid" => vec![KeyColumn { name: None, collation: Collation::Binary,
+                                                            descending: false }],
+            _ => new_definition.primary_key(),
+        };
+        let rows = diff_table(&read_table(old, old_entry, &key)?, &read_table(new, entry, &key)?,
+                              &key_columns, new);
+        let columns = column_names(&new_definition);
+        tables.push(TableDiff { name: entry.name.clone(), key, columns, rows });
+    }
+    Ok(Diff { schema: schema_changes(&old_schema, &new_schema), tables })
+}
+
+/*
+ * The owner of every page that can be told: the pages of each B-tree in
+ * the schema and the overflow chains of their cells, the freelist and the
+ * pointer map of auto-vacuum databases
+ */
+pub fn page_owners(file: &SqliteFile) -> HashMap<u32, PageOwner> {
+    let mut owners = HashMap::new();
+    let mut roots = vec![(1, "sqlite_schema".to_string())];
+    roots.extend(file.schema().unwrap_or_default().into_iter()
+                 .filter(|x| x.rootpage > 0)
+                 .map(|x| (x.rootpage, x.name)));
+    for (root, name) in roots {
+        for page_id in file.btree_pages(root).unwrap_or_default() {
+            owners.entry(page_id).or_insert_with(|| PageOwner::BTree(name.clone()));
+        }
+        for cell in file.btree_cells(root).unwrap_or_default() {
+            let mut next = cell.overflow_page.unwrap_or(0);
+            let mut visited = 0;
+            while next != 0 && visited < file.page_count() {
+                let page = match file.page_bytes(next as usize) {
+                    Ok(x) => x,
+                    Err(_) => break,
+                };
+                owners.entry(next).or_insert_with(|| PageOwner::Overflow(name.clone()));
+                next = u32::from_be_bytes([page[0], page[1], page[2], page[3]]);
+                visited += 1;
+            }
+        }
+    }
+    for (page_id, _) in file.freelist_pages().unwrap_or_default() {
+        owners.entry(page_id).or_insert(PageOwner::Freelist);
+    }
+    if file.header.largest_root_page != 0 {
+        let step = file.header.usable_size() / 5 + 1;
+        for page_id in (0..).map(|x| 2 + x * step).take_while(|&x| x <= file.page_count()) {
+            owners.insert(page_id, PageOwner::PointerMap);
+        }
+    }
+    owners
+}
+
+/*
+ * The pages that differ between two versions of a database, with what they
+ * were used for before and after
+ */
+pub fn diff_pages(old: &SqliteFile, new: &SqliteFile) -> Result<PageDiff, String> {
+    if old.header.page_size != new.header.page_size {
+        return Err(format!("Page sizes differ: {} and {}", old.header.page_size, new.header.page_size))
+    }
+    let (old_owners, new_owners) = (page_owners(old), page_owners(new));
+    let mut pages = vec![];
+    for page_number in 1..=old.page_count().max(new.page_count()) {
+        let change = match (old.page_bytes(page_number as usize), new.page_bytes(page_number as usize)) {
+            (Ok(a), Ok(b)) if a == b => continue,
+            (Ok(_), Ok(_)) => PageChange::Changed,
+            (Err(_), Ok(_)) => PageChange::Added,
+            (Ok(_), Err(_)) => PageChange::Removed,
+            (Err(_), Err(_)) => continue,
+        };
+        pages.push(ChangedPage { page_number, change, old_owner: old_owners.get(&page_number).cloned(),
+                                 new_owner: new_owners.get(&page_number).cloned() });
+    }
+    Ok(PageDiff { old_header: old.header, new_header: new.header, pages })
+}
+
+/*
+ * A name the way sqldiff writes it, quoted only if it is a keyword or has
+ * characters other than letters, digits and underscores
+ */
+fn safe_id(name: &str) -> String {
+    let plain = name.starts_with(|x: char| x.is_ascii_alphabetic() || x == '_')
+        && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+        && !KEYWORDS.iter().any(|x| x.eq_ignore_ascii_case(name));
+    if plain { name.to_string() } else { format!("\"{}\"", name.replace('"', "\"\"")) }
+}
+
+fn list<T: ToString>(items: &[T], separator: &str) -> String {
+    items.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(separator)
+}
+
+fn condition(key: &[String], values: &[Value]) -> String {
+    let terms: Vec<String> = key.iter().zip(values).map(|(k, v)| format!("{}={}", safe_id(k), v)).collect();
+    terms.join(" AND ")
+}
+
+/*
+ * Rows of tables without a primary key keep their rowid
+ */
+fn insert(table: &str, key: &[String], columns: &[String], key_values: &[Value], values: &[Value]) -> String {
+    let (mut names, mut row) = (vec![], vec![]);
+    if key == ["rowid"] {
+        names.push("rowid".to_string());
+        row.extend(key_values.iter().cloned());
+    }
+    names.extend(columns.iter().map(|x| safe_id(x)));
+    row.extend(values.iter().cloned());
+    format!("INSERT INTO {}({}) VALUES({});", safe_id(table), names.join(","), list(&row, ","))
+}
+
+fn create_table(file: &SqliteFile, entry: &SchemaEntry, sql: &mut Vec<String>) -> Result<(), String> {
+    sql.push(format!("{};", entry.sql.as_ref().map_or("", |x| x.as_str())));
+    if entry.rootpage == 0 {
+        return Ok(())
+    }
+    let definition = parse_create_table(entry.sql.as_ref().map_or("", |x| x.as_str()))?;
+    let key = key_names(&definition);
+    let columns = column_names(&definition);
+    for (key_values, values) in read_table(file, entry, &key)?.rows {
+        sql.push(insert(&entry.name, &key, &columns, &key_values, &values));
+    }
+    Ok(())
+}
+
+impl TableDiff {
+    /*
+     * The INSERT, UPDATE and DELETE statements that turn the rows of the
+     * first version into those of the second
+     */
+    pub fn to_sql(&self) -> Vec<String> {
+        self.rows.iter().map(|row| match row.change {
+            RowChange::Inserted(ref values) => insert(&self.name, &self.key, &self.columns, &row.key, values),
+            RowChange::Deleted(_) => {
+                format!("DELETE FROM {} WHERE {};", safe_id(&self.name), condition(&self.key, &row.key))
+            },
+            RowChange::Updated(ref changes) => {
+                let assignments: Vec<String> = changes.iter()
+                    .map(|x| format!("{}={}", safe_id(&x.column), x.new))
+                    .collect();
+                format!("UPDATE {} SET {} WHERE {};", safe_id(&self.name), assignments.join(", "),
+                        condition(&self.key, &row.key))
+            },
+        }).collect()
+    }
+}
+
+/*
+ * Whether ALTER TABLE ADD COLUMN accepts the column. It can't add a key, or
+ * a NOT NULL column without a value for the rows that are already there.
+ */
+fn can_add_column(table: &TableDefinition, column: &ColumnDefinition) -> bool {
+    let is_column = |x: &IndexedColumn| x.name.as_ref().is_some_and(|x| x.eq_ignore_ascii_case(&column.name));
+    let key = table.constraints.iter().any(|x| x.columns.iter().any(is_column));
+    let default = column.default.as_ref().is_some_and(|x| *x != Value::Null);
+    !key && (default || !column.not_null)
+}
+
+/*
+ * A script that turns the first database into the second, like sqldiff
+ * writes. Tables are handled in the order of their names: dropped, created
+ * with all of their rows, given new columns with ALTER TABLE when they were
+ * only added at the end, and otherwise recreated if their schema changed.
+ * Indexes, views and triggers that changed are dropped before and created
+ * after the tables, along with those of recreated tables.
+ */
+pub fn diff_sql(old: &SqliteFile, new: &SqliteFile) -> Result<String, String> {
+    let diff = diff(old, new)?;
+    let (old_schema, new_schema) = (old.schema()?, new.schema()?);
+    let find = |entries: &[SchemaEntry], entry_type: &str, name: &str| {
+        entries.iter().find(|x| x.entry_type == entry_type && x.name.eq_ignore_ascii_case(name)).cloned()
+    };
+    let changed = |entry: &SchemaEntry| diff.schema.iter().any(|x| match *x {
+        SchemaChange::Added(ref x) | SchemaChange::Dropped(ref x) | SchemaChange::Changed(_, ref x) => {
+            x.entry_type == entry.entry_type && x.name.eq_ignore_ascii_case(&entry.name)
+        },
+        _ => false,
+    });
+    let is_table = |x: &SchemaEntry| x.entry_type == "table" && !x.name.to_lowercase().starts_with("sqlite_");
+    let is_other = |x: &SchemaEntry| x.entry_type != "table" && x.sql.is_some();
+
+    let mut sql = vec![];
+    for entry in old_schema.iter().filter(|x| is_other(x) && changed(x)) {
+        sql.push(format!("DROP {} {};", entry.entry_type.to_uppercase(), safe_id(&entry.name)));
+    }
+
+    let mut names: Vec<String> = old_schema.iter().chain(&new_schema)
+        .filter(|x| is_table(x))
+        .map(|x| x.name.clone())
+        .collect();
+    names.sort_by_key(|x| x.to_lowercase());
+    names.dedup_by_key(|x| x.to_lowercase());
+    let mut recreated = vec![];
+    for name in &names {
+        let entries = (find(&old_schema, "table", name), find(&new_schema, "table", name));
+        let (old_entry, new_entry) = match entries {
+            (Some(_), None) => { sql.push(format!("DROP TABLE {};", safe_id(name))); continue },
+            (None, Some(entry)) => { create_table(new, &entry, &mut sql)?; continue },
+            (Some(a), Some(b)) => (a, b),
+            (None, None) => continue,
+        };
+        let table = diff.tables.iter().find(|x| x.name.eq_ignore_ascii_case(name));
+        let definitions = (old_entry.sql.as_ref().and_then(|x| parse_create_table(x).ok()),
+                           new_entry.sql.as_ref().and_then(|x| parse_create_table(x).ok()));
+        let appended = match definitions {
+            (Some(ref a), Some(ref b)) if a.columns.len() <= b.columns.len() => {
+                let prefix = a.columns.iter().zip(&b.columns)
+                    .all(|(x, y)| x.name.eq_ignore_ascii_case(&y.name));
+                let columns = &b.columns[a.columns.len()..];
+                match prefix && columns.iter().all(|x| can_add_column(b, x)) {
+                    true => Some(columns.to_vec()),
+                    false => None,
+                }
+            },
+            _ => None,
+        };
+        let altered = old_entry.sql != new_entry.sql;
+        match (table, appended) {
+            (Some(table), Some(ref columns)) if !altered || !columns.is_empty() => {
+                for column in columns {
+                    sql.push(format!("ALTER TABLE {} ADD COLUMN {};", safe_id(name), column.sql));
+                }
+                sql.extend(table.to_sql());
+            },
+            _ => {
+                sql.push(format!("DROP TABLE {};", safe_id(name)));
+                create_table(new, &new_entry, &mut sql)?;
+                recreated.push(new_entry.name.clone());
+            },
+        }
+    }
+
+    for entry in new_schema.iter().filter(|x| is_other(x)) {
+        if changed(entry) || recreated.iter().any(|x| x.eq_ignore_ascii_case(&entry.tbl_name)) {
+            sql.push(format!("{};", entry.sql.as_ref().map_or("", |x| x.as_str())));
+        }
+    }
+    Ok(sql.iter().map(|x| format!("{}\n", x)).collect())
+}
+
+impl std::fmt::Display for SchemaChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            SchemaChange::Added(ref x) => write!(f, "Added {} {}", x.entry_type, x.name),
+            SchemaChange::Dropped(ref x) => write!(f, "Dropped {} {}", x.entry_type, x.name),
+            SchemaChange::Changed(_, ref x) => write!(f, "Changed {} {}", x.entry_type, x.name),
+            SchemaChange::ColumnAdded(ref table, ref column) => {
+                write!(f, "Added column {}.{}", table, column)
+            },
+            SchemaChange::ColumnDropped(ref table, ref column) => {
+                write!(f, "Dropped column {}.{}", table, column)
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ColumnChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} -> {}", self.column, self.old, self.new)
+    }
+}
+
+impl std::fmt::Display for TableDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let list = |values: &[Value]| values.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ");
+        for (i, row) in self.rows.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match self.key.as_slice() {
+                [name] => write!(f, "{} {} {}: ", self.name, name, list(&row.key))?,
+                names => write!(f, "{} ({}) ({}): ", self.name, names.join(", "), list(&row.key))?,
+            }
+            match row.change {
+                RowChange::Inserted(ref values) => write!(f, "inserted ({})", list(values))?,
+                RowChange::Deleted(ref values) => write!(f, "deleted ({})", list(values))?,
+                RowChange::Updated(ref changes) => {
+                    let changes: Vec<String> = changes.iter().map(|x| x.to_string()).collect();
+                    write!(f, "updated {}", changes.join(", "))?
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for PageOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            PageOwner::BTree(ref name) => write!(f, "{}", name),
+            PageOwner::Overflow(ref name) => write!(f, "{} overflow", name),
+            PageOwner::Freelist => write!(f, "freelist"),
+            PageOwner::PointerMap => write!(f, "pointer map"),
+        }
+    }
+}
+
+impl std::fmt::Display for ChangedPage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let owner = |x: &Option<PageOwner>| x.as_ref().map_or("?".to_string(), |x| x.to_string());
+        write!(f, "Page {} (", self.page_number)?;
+        match self.change {
+            PageChange::Added => write!(f, "{}): added", owner(&self.new_owner)),
+            PageChange::Removed => write!(f, "{}): removed", owner(&self.old_owner)),
+            PageChange::Changed if self.old_owner == self.new_owner => {
+                write!(f, "{}): changed", owner(&self.new_owner))
+            },
+            PageChange::Changed => {
+                write!(f, "{} -> {}): changed", owner(&self.old_owner), owner(&self.new_owner))
+            },
+        }
+    }
+}
+
+/*
+ * How the header fields that every write moves changed, then the pages
+ */
+impl std::fmt::Display for PageDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (a, b) = (&self.old_header, &self.new_header);
+        let fields = [("file_change_counter", a.file_change_counter, b.file_change_counter),
+                      ("schema_cookie", a.schema_cookie, b.schema_cookie),
+                      ("freelist_count", a.freelist_count, b.freelist_count),
+                      ("database_size", a.database_size, b.database_size)];
+        for (i, &(name, old, new)) in fields.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match new as i64 - old as i64 {
+                0 => write!(f, "{}: {}", name, new)?,
+                delta => write!(f, "{}: {} -> {} ({:+})", name, old, new, delta)?,
+            }
+        }
+        for page in &self.pages {
+            write!(f, "\n{}", page)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::{self, Command};
+
+    use super::{PageChange, PageOwner};
+    use data_structures::{SqliteFile, Value};
+    use transaction::Transaction;
+    use writer::DatabaseBuilder;
+    use Parser;
+
+    fn row(i: i64) -> (i64, Vec<Value>) {
+        (i, vec![Value::Null, Value::Text(format!("name {}", i))])
+    }
+
+    fn databases() -> (SqliteFile, SqliteFile) {
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        let sql = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT)";
+        builder.add_table("t", sql, (1..200).map(row)).unwrap();
+        builder.add_table("gone", "CREATE TABLE gone(x)", vec![]).unwrap();
+        let old = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        let sql = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, n)";
+        builder.add_table("t", sql, (1..200).map(row)).unwrap();
+        builder.add_table("new", "CREATE TABLE new(x)", vec![(1, vec![Value::Real(0.5)])]).unwrap();
+        let new = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+        let mut transaction = Transaction::new(new).unwrap();
+        transaction.delete("t", 5).unwrap();
+        let seven = [Value::Null, Value::Text("seven".to_string()), Value::Integer(1)];
+        transaction.update("t", 7, &seven).unwrap();
+        transaction.insert("t", Some(500), &[Value::Null, Value::Text("it's".to_string())]).unwrap();
+        (old, transaction.into_file().unwrap())
+    }
+
+    #[test]
+    fn rows_and_schema_are_compared() {
+        let (old, new) = databases();
+        let diff = super::diff(&old, &new).unwrap();
+        let schema: Vec<String> = diff.schema.iter().map(|x| x.to_string()).collect();
+        assert_eq!(vec!["Dropped table gone", "Added column t.n", "Added table new"], schema);
+        assert_eq!(1, diff.tables.len());
+        assert_eq!(vec!["id"], diff.tables[0].key);
+        assert_eq!("t id 5: deleted (5, 'name 5')\n\
+                    t id 7: updated name 'name 7' -> 'seven', n NULL -> 1\n\
+                    t id 500: inserted (500, 'it''s', NULL)", diff.tables[0].to_string());
+    }
+
+    #[test]
+    fn short_records_read_the_default() {
+        let sql = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, n INTEGER NOT NULL DEFAULT 5)";
+        let database = |rows: Vec<(i64, Vec<Value>)>| {
+            let mut builder = DatabaseBuilder::new().page_size(1024);
+            builder.add_table("t", sql, rows).unwrap();
+            SqliteFile::from_vec(&builder.build().unwrap()).unwrap()
+        };
+        // Written before the column was added
+        let short = |i: i64| (i, vec![Value::Null, Value::Text(format!("name {}", i))]);
+        let full = |i: i64, n: i64| {
+            (i, vec![Value::Null, Value::Text(format!("name {}", i)), Value::Integer(n)])
+        };
+        let old = database(vec![short(1), short(2), short(3)]);
+        let new = database(vec![full(1, 5), full(2, 6), short(3)]);
+        let diff = super::diff(&old, &new).unwrap();
+        assert_eq!("t id 2: updated n 5 -> 6", diff.tables[0].to_string());
+    }
+
+    #[test]
+    fn sql_turns_one_database_into_the_other() {
+        let (old, new) = databases();
+        let sql = super::diff_sql(&old, &new).unwrap();
+        assert_eq!("DROP TABLE gone;\n\
+                    CREATE TABLE new(x);\n\
+                    INSERT INTO new(rowid,x) VALUES(1,0.5);\n\
+                    ALTER TABLE t ADD COLUMN n;\n\
+                    DELETE FROM t WHERE id=5;\n\
+                    UPDATE t SET name='seven', n=1 WHERE id=7;\n\
+                    INSERT INTO t(id,name,n) VALUES(500,'it''s',NULL);\n", sql);
+        assert_eq!("\"order\"", super::safe_id("order"));
+        assert_eq!("\"a \"\"b\"\"\"", super::safe_id("a \"b\""));
+    }
+
+    #[test]
+    fn sqlite_applies_the_sql() {
+        let path = |name: &str| -> PathBuf {
+            env::temp_dir().join(format!("litereader-diff-{}-{}.db", name, process::id()))
+        };
+        let (a, b) = (path("a"), path("b"));
+        // What sqlite3 prints, errors included
+        let sqlite = |path: &Path, sql: &str| {
+            let output = Command::new("sqlite3").arg(path).arg(sql).output().ok()?;
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Some(String::from_utf8_lossy(&output.stdout).into_owned() + &stderr)
+        };
+        let create = "CREATE TABLE t(id INTEGER PRIMARY KEY, b TEXT, r REAL); \
+                      INSERT INTO t VALUES(1, 'x', 1.5), (2, 'y', NULL);";
+        if sqlite(&a, create).is_none() {
+            // Without sqlite3 there is nothing to check against
+            return
+        }
+        sqlite(&b, create);
+        sqlite(&b, "ALTER TABLE t ADD COLUMN n INTEGER NOT NULL DEFAULT 5; \
+                    ALTER TABLE t ADD COLUMN c TEXT COLLATE nocase REFERENCES t(id); \
+                    UPDATE t SET c = 'Z' WHERE id = 2; INSERT INTO t VALUES(3, 'z', NULL, 7, 'q');");
+
+        let script = super::diff_sql(&SqliteFile::from_file(a.to_str().unwrap()).unwrap(),
+                                     &SqliteFile::from_file(b.to_str().unwrap()).unwrap()).unwrap();
+        assert!(script.contains("ALTER TABLE t ADD COLUMN n INTEGER NOT NULL DEFAULT 5;\n"));
+        assert_eq!(Some(String::new()), sqlite(&a, &script));
+        let query = "SELECT * FROM t ORDER BY id; SELECT c FROM t WHERE c = 'z'; \
+                     SELECT sql FROM sqlite_schema;";
+        let (applied, expected) = (sqlite(&a, query), sqlite(&b, query));
+        fs::remove_file(&a).unwrap();
+        fs::remove_file(&b).unwrap();
+        assert_eq!(expected, applied);
+        assert!(applied.unwrap().starts_with("1|x|1.5|5|\n2|y||5|Z\n3|z||7|q\nZ\n"));
+    }
+
+    #[test]
+    fn pages_changed_by_a_transaction() {
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        let sql = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT)";
+        builder.add_table("t", sql, (1..200).map(row)).unwrap();
+        let bytes = builder.build().unwrap();
+        let old = SqliteFile::from_vec(&bytes).unwrap();
+        let mut transaction = Transaction::new(SqliteFile::from_vec(&bytes).unwrap()).unwrap();
+        transaction.update("t", 7, &[Value::Null, Value::Text("x".repeat(3000))]).unwrap();
+        let new = transaction.into_file().unwrap();
+
+        let diff = super::diff_pages(&old, &new).unwrap();
+        assert!(diff.to_string().starts_with("file_change_counter: 1 -> 2 (+1)\n\
+                                               schema_cookie: 1\n\
+                                               freelist_count: 0\n\
+                                               database_size: 5 -> 9 (+4)\n\
+                                               Page 1 (sqlite_schema): changed\n"));
+        let overflow: Vec<String> = diff.pages.iter()
+            .filter(|x| x.new_owner == Some(PageOwner::Overflow("t".to_string())))
+            .map(|x| x.to_string()).collect();
+        assert_eq!(vec!["Page 6 (t overflow): added", "Page 7 (t overflow): added"], overflow);
+        assert!(diff.pages.iter().all(|x| x.change != PageChange::Removed));
+    }
+}
@@ -1,8 +1,13 @@
 extern crate std;
 
+use data_structures::SqliteVersion;
+use parser::encode_text;
 
-#[derive(Debug,Copy,Clone)]
+
+#[derive(Debug,Copy,Clone,PartialEq)]
 pub enum TextEncoding {
+    // Nothing has been written to a new database yet, SQLite reads it as UTF-8
+    Unset,
     UTF8,
     UTF16le,
     UTF16be,
@@ -12,17 +17,154 @@ impl std::fmt::Display for TextEncoding {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f,
                "{}",
-               match self {
-                   &TextEncoding::UTF8 => "UTF-8",
-                   &TextEncoding::UTF16le => "UTF-16le",
-                   &TextEncoding::UTF16be => "UTF-16be",
+               match *self {
+                   TextEncoding::Unset => "UTF-8 (not set)",
+                   TextEncoding::UTF8 => "UTF-8",
+                   TextEncoding::UTF16le => "UTF-16le",
+                   TextEncoding::UTF16be => "UTF-16be",
+               })
+    }
+}
+
+impl TextEncoding {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            TextEncoding::Unset => 0,
+            TextEncoding::UTF8 => 1,
+            TextEncoding::UTF16le => 2,
+            TextEncoding::UTF16be => 3,
+        }
+    }
+
+    /*
+     * The encoding text is actually stored in
+     */
+    pub fn effective(self) -> TextEncoding {
+        match self {
+            TextEncoding::Unset => TextEncoding::UTF8,
+            x => x,
+        }
+    }
+}
+
+impl std::str::FromStr for TextEncoding {
+    type Err = String;
+
+    /*
+     * Accepts the names PRAGMA encoding uses, with or without the dash
+     */
+    fn from_str(s: &str) -> Result<TextEncoding, String> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "utf8" => Ok(TextEncoding::UTF8),
+            "utf16le" => Ok(TextEncoding::UTF16le),
+            "utf16be" => Ok(TextEncoding::UTF16be),
+            _ => Err(format!("Unknown text encoding {}", s)),
+        }
+    }
+}
+
+
+/*
+ * Collating sequences of index columns. Only BINARY compares the stored
+ * bytes; SQLite runs NOCASE and RTRIM on UTF-8 whatever the database
+ * encoding is.
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub enum Collation {
+    Binary,
+    NoCase,
+    RTrim,
+    Other(String),
+}
+
+impl From<&str> for Collation {
+    fn from(name: &str) -> Collation {
+        match name.to_ascii_uppercase().as_str() {
+            "BINARY" => Collation::Binary,
+            "NOCASE" => Collation::NoCase,
+            "RTRIM" => Collation::RTrim,
+            _ => Collation::Other(name.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Collation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Collation::Binary => write!(f, "BINARY"),
+            Collation::NoCase => write!(f, "NOCASE"),
+            Collation::RTrim => write!(f, "RTRIM"),
+            Collation::Other(ref x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl Collation {
+    /*
+     * None for application-defined collations, which are not known here
+     */
+    pub fn compare(&self, a: &str, b: &str, encoding: TextEncoding) -> Option<std::cmp::Ordering> {
+        match *self {
+            Collation::Binary => Some(encode_text(a, encoding).cmp(&encode_text(b, encoding))),
+            Collation::NoCase => Some(a.bytes().map(|x| x.to_ascii_lowercase())
+                                      .cmp(b.bytes().map(|x| x.to_ascii_lowercase()))),
+            Collation::RTrim => Some(a.trim_end_matches(' ').cmp(b.trim_end_matches(' '))),
+            Collation::Other(_) => None,
+        }
+    }
+}
+
+
+/*
+ * Type affinity of a column, derived from its declared type by the rules
+ * in section 3.1 of SQLite's datatype documentation
+ */
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Affinity {
+    Integer,
+    Text,
+    Blob,
+    Real,
+    Numeric,
+}
+
+impl Affinity {
+    pub fn from_declared_type(declared_type: &str) -> Affinity {
+        let declared_type = declared_type.to_ascii_uppercase();
+        let has = |x: &str| declared_type.contains(x);
+        if has("INT") {
+            Affinity::Integer
+        } else if has("CHAR") || has("CLOB") || has("TEXT") {
+            Affinity::Text
+        } else if has("BLOB") || declared_type.is_empty() {
+            Affinity::Blob
+        } else if has("REAL") || has("FLOA") || has("DOUB") {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+}
+
+impl std::fmt::Display for Affinity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   Affinity::Integer => "INTEGER",
+                   Affinity::Text => "TEXT",
+                   Affinity::Blob => "BLOB",
+                   Affinity::Real => "REAL",
+                   Affinity::Numeric => "NUMERIC",
                })
     }
 }
 
 
-#[derive(Debug,Copy,Clone)]
+#[derive(Debug,Copy,Clone,PartialEq)]
 pub enum SchemaFormat {
+    // Nothing has been written to a new database yet
+    Empty,
     Format1,
     Format2,
     Format3,
@@ -33,17 +175,31 @@ impl std::fmt::Display for SchemaFormat {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f,
                "{}",
-               match self {
-                   &SchemaFormat::Format1 => "1",
-                   &SchemaFormat::Format2 => "2",
-                   &SchemaFormat::Format3 => "3",
-                   &SchemaFormat::Format4 => "4",
+               match *self {
+                   SchemaFormat::Empty => "0",
+                   SchemaFormat::Format1 => "1",
+                   SchemaFormat::Format2 => "2",
+                   SchemaFormat::Format3 => "3",
+                   SchemaFormat::Format4 => "4",
                })
     }
 }
 
+impl SchemaFormat {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            SchemaFormat::Empty => 0,
+            SchemaFormat::Format1 => 1,
+            SchemaFormat::Format2 => 2,
+            SchemaFormat::Format3 => 3,
+            SchemaFormat::Format4 => 4,
+        }
+    }
+}
+
 
-#[derive(Debug,Copy,Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug,Copy,Clone,PartialEq)]
 pub enum WriteVersion {
     Legacy,
     WAL,
@@ -53,15 +209,25 @@ impl std::fmt::Display for WriteVersion {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f,
                "{}",
-               match self {
-                   &WriteVersion::Legacy => "Legacy",
-                   &WriteVersion::WAL => "WAL",
+               match *self {
+                   WriteVersion::Legacy => "Legacy",
+                   WriteVersion::WAL => "WAL",
                })
     }
 }
 
+impl WriteVersion {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            WriteVersion::Legacy => 1,
+            WriteVersion::WAL => 2,
+        }
+    }
+}
+
 
-#[derive(Debug,Copy,Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug,Copy,Clone,PartialEq)]
 pub enum ReadVersion {
     Legacy,
     WAL,
@@ -71,9 +237,208 @@ impl std::fmt::Display for ReadVersion {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f,
                "{}",
-               match self {
-                   &ReadVersion::Legacy => "Legacy",
-                   &ReadVersion::WAL => "WAL",
+               match *self {
+                   ReadVersion::Legacy => "Legacy",
+                   ReadVersion::WAL => "WAL",
                })
     }
 }
+
+impl ReadVersion {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ReadVersion::Legacy => 1,
+            ReadVersion::WAL => 2,
+        }
+    }
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Feature {
+    DescendingIndexes,
+    WriteAheadLog,
+    PartialIndexes,
+    WithoutRowid,
+    GeneratedColumns,
+    StrictTables,
+}
+
+impl Feature {
+    /*
+     * First SQLite release that can write the feature
+     */
+    pub fn introduced_in(self) -> SqliteVersion {
+        let (major, minor, patch) = match self {
+            Feature::DescendingIndexes => (3, 3, 0),
+            Feature::WriteAheadLog => (3, 7, 0),
+            Feature::PartialIndexes => (3, 8, 0),
+            Feature::WithoutRowid => (3, 8, 2),
+            Feature::GeneratedColumns => (3, 31, 0),
+            Feature::StrictTables => (3, 37, 0),
+        };
+        SqliteVersion { major, minor, patch }
+    }
+}
+
+impl std::fmt::Display for Feature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   Feature::DescendingIndexes => "DESC indexes",
+                   Feature::WriteAheadLog => "WAL mode",
+                   Feature::PartialIndexes => "partial indexes",
+                   Feature::WithoutRowid => "WITHOUT ROWID tables",
+                   Feature::GeneratedColumns => "generated columns",
+                   Feature::StrictTables => "STRICT tables",
+               })
+    }
+}
+
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum HeaderWarning {
+    InvalidPageSize(u32),
+    InvalidPayloadFractions(u8, u8, u8),
+    ReservedSpaceTooLarge(u8),
+    NonZeroReservedBytes,
+    StaleDatabaseSize(u32, u32),
+    FreelistCountExceedsPageCount(u32, u32),
+    FreelistTrunkOutOfRange(u32, u32),
+    FreelistTrunkMissing(u32),
+    LargestRootPageOutOfRange(u32, u32),
+    IncrementalVacuumWithoutAutoVacuum,
+    MixedFileFormatVersions,
+    FileSizeNotPageAligned(usize),
+    DatabaseSizeExceedsFile(u32, u32),
+    FeatureNewerThanWriter(Feature, SqliteVersion),
+    FeatureWithoutWriterVersion(Feature),
+}
+
+impl std::fmt::Display for HeaderWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            HeaderWarning::InvalidPageSize(x) =>
+                write!(f, "page size {} is not a power of two between 512 and 65536", x),
+            HeaderWarning::InvalidPayloadFractions(max, min, leaf) =>
+                write!(f, "payload fractions are {}/{}/{} instead of 64/32/32", max, min, leaf),
+            HeaderWarning::ReservedSpaceTooLarge(x) =>
+                write!(f, "reserved space of {} bytes leaves less than 480 usable bytes per page", x),
+            HeaderWarning::NonZeroReservedBytes =>
+                write!(f, "reserved header bytes are not zero"),
+            HeaderWarning::StaleDatabaseSize(valid_for, counter) =>
+                write!(f, "version_valid_for {} does not match file_change_counter {}, \
+                           database_size is unreliable", valid_for, counter),
+            HeaderWarning::FreelistCountExceedsPageCount(count, pages) =>
+                write!(f, "freelist count {} exceeds the page count {}", count, pages),
+            HeaderWarning::FreelistTrunkOutOfRange(trunk, pages) =>
+                write!(f, "freelist trunk page {} is beyond the page count {}", trunk, pages),
+            HeaderWarning::FreelistTrunkMissing(count) =>
+                write!(f, "freelist count is {} but there is no trunk page", count),
+            HeaderWarning::LargestRootPageOutOfRange(root, pages) =>
+                write!(f, "largest root page {} is beyond the page count {}", root, pages),
+            HeaderWarning::IncrementalVacuumWithoutAutoVacuum =>
+                write!(f, "incremental vacuum is enabled but auto-vacuum is not"),
+            HeaderWarning::MixedFileFormatVersions =>
+                write!(f, "read and write versions disagree about WAL mode"),
+            HeaderWarning::FileSizeNotPageAligned(x) =>
+                write!(f, "file size {} is not a multiple of the page size", x),
+            HeaderWarning::DatabaseSizeExceedsFile(size, pages) =>
+                write!(f, "database size {} exceeds the {} pages in the file", size, pages),
+            HeaderWarning::FeatureNewerThanWriter(feature, version) =>
+                write!(f, "file uses {} but was last written by SQLite {}", feature, version),
+            HeaderWarning::FeatureWithoutWriterVersion(feature) =>
+                write!(f, "file uses {} but has no writer version, which SQLite stores since 3.7.0",
+                       feature),
+        }
+    }
+}
+
+
+/*
+ * Well-known values from SQLite's magic.txt
+ */
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum ApplicationId {
+    None,
+    FossilRepository,
+    FossilCheckout,
+    FossilGlobalConfiguration,
+    BeSQLiteDatabase,
+    BeSQLiteLocalization,
+    GeoPackage,
+    GeoPackage10,
+    GeoPackage11,
+    EsriSpatialDatabase,
+    MBTiles,
+    Unknown(u32),
+}
+
+const APPLICATION_IDS: [(u32, ApplicationId); 11] = [
+    (0x0000_0000, ApplicationId::None),
+    (0x0f05_5111, ApplicationId::FossilRepository),
+    (0x0f05_5112, ApplicationId::FossilCheckout),
+    (0x0f05_5113, ApplicationId::FossilGlobalConfiguration),
+    (0x4265_4462, ApplicationId::BeSQLiteDatabase),
+    (0x4265_4c6e, ApplicationId::BeSQLiteLocalization),
+    (0x4750_4b47, ApplicationId::GeoPackage),
+    (0x4750_3130, ApplicationId::GeoPackage10),
+    (0x4750_3131, ApplicationId::GeoPackage11),
+    (0x4573_7269, ApplicationId::EsriSpatialDatabase),
+    (0x4d50_4258, ApplicationId::MBTiles),
+];
+
+impl From<u32> for ApplicationId {
+    fn from(x: u32) -> ApplicationId {
+        APPLICATION_IDS.iter()
+            .find(|&&(id, _)| id == x)
+            .map_or(ApplicationId::Unknown(x), |&(_, app)| app)
+    }
+}
+
+impl ApplicationId {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            ApplicationId::Unknown(x) => x,
+            app => APPLICATION_IDS.iter().find(|&&(_, a)| a == app).map_or(0, |&(id, _)| id),
+        }
+    }
+}
+
+impl std::fmt::Display for ApplicationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ApplicationId::None => write!(f, "None"),
+            ApplicationId::FossilRepository => write!(f, "Fossil repository"),
+            ApplicationId::FossilCheckout => write!(f, "Fossil checkout"),
+            ApplicationId::FossilGlobalConfiguration => write!(f, "Fossil global configuration"),
+            ApplicationId::BeSQLiteDatabase => write!(f, "Bentley Systems BeSQLite Database"),
+            ApplicationId::BeSQLiteLocalization => write!(f, "Bentley Systems Localization File"),
+            ApplicationId::GeoPackage => write!(f, "OGC GeoPackage file"),
+            ApplicationId::GeoPackage10 => write!(f, "OGC GeoPackage version 1.0 file"),
+            ApplicationId::GeoPackage11 => write!(f, "OGC GeoPackage version 1.1 file"),
+            ApplicationId::EsriSpatialDatabase => write!(f, "Esri Spatially-Enabled Database"),
+            ApplicationId::MBTiles => write!(f, "MBTiles tileset"),
+            ApplicationId::Unknown(x) => write!(f, "Unknown (0x{:08x})", x),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::ApplicationId;
+
+    #[test]
+    fn application_id_registry() {
+        assert_eq!(ApplicationId::GeoPackage, ApplicationId::from(0x4750_4b47));
+        assert_eq!(ApplicationId::Unknown(111_111), ApplicationId::from(111_111));
+        for &(id, app) in super::APPLICATION_IDS.iter() {
+            assert_eq!(id, app.to_u32());
+        }
+        assert_eq!(111_111, ApplicationId::Unknown(111_111).to_u32());
+        assert_eq!("MBTiles tileset", format!("{}", ApplicationId::MBTiles));
+        assert_eq!("Unknown (0x0001b207)", format!("{}", ApplicationId::from(111_111)));
+    }
+}
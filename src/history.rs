@@ -0,0 +1,485 @@
+/*
+ * The history of pages in the WAL. Every frame holds a version of a page and
+ * frames are only overwritten when the WAL restarts from the beginning, so
+ * the frames behind the last one written still hold versions from earlier
+ * generations, recognisable by their old salt. The rows of consecutive
+ * versions of a page show what each transaction inserted, changed or deleted.
+ */
+extern crate std;
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use checksum::wal_checksum;
+use data_structures::{BTreeCell, BTreePage, BTreePageType, Record, SqliteFile, Wal, WalFrame};
+use diff::{self, PageOwner};
+use parser::{parse_btree_page, parse_cells, parse_record};
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum FrameState {
+    // The page in the database file, as of the last checkpoint
+    Database,
+    Committed,
+    // Valid, but behind the last commit frame
+    Uncommitted,
+    // Left over from before the last WAL restart
+    Stale,
+    // Behind a broken checksum, or breaking the checksum chain of its generation
+    Invalid,
+}
+
+/*
+ * One version of a page. The transaction is identified by its commit frame,
+ * which is only known if it is still in the WAL.
+ */
+#[derive(Debug,Clone)]
+pub struct PageVersion<'a> {
+    pub page_number: u32,
+    // Numbered from 1 like SQLite does, None for the database file
+    pub frame: Option<usize>,
+    pub salt: Option<(u32, u32)>,
+    pub transaction: Option<usize>,
+    pub state: FrameState,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum ChangeKind {
+    Inserted(Record),
+    Deleted(Record),
+    Updated(Record, Record),
+}
+
+/*
+ * A row that differs from the previous version of its page
+ */
+#[derive(Debug,Clone)]
+pub struct RowChange<'a> {
+    pub table: Option<String>,
+    pub rowid: i64,
+    pub kind: ChangeKind,
+    pub version: PageVersion<'a>,
+}
+
+/*
+ * Whether a frame continues the checksum chain of the one before it
+ */
+fn follows(big_endian: bool, previous: &WalFrame, frame: &WalFrame) -> bool {
+    let mut frame_header = [0; 8];
+    frame_header[..4].copy_from_slice(&frame.page_number.to_be_bytes());
+    frame_header[4..].copy_from_slice(&frame.commit_size.to_be_bytes());
+    let checksum = wal_checksum(big_endian, &frame_header, previous.checksum);
+    wal_checksum(big_endian, &frame.data, checksum) == frame.checksum
+}
+
+/*
+ * Every version of every page in the WAL, oldest first, together with the
+ * version in the database file of each of those pages. The first salt goes
+ * up by one with every restart, which orders the stale generations, and all
+ * of them were checkpointed into the database file before the current one
+ * started.
+ */
+pub fn page_versions<'a>(file: &'a SqliteFile, wal: &'a Wal) -> Vec<PageVersion<'a>> {
+    let valid = wal.valid_frame_count();
+    let committed = wal.committed_frames().len();
+    let big_endian = wal.header.big_endian_checksums();
+    let mut versions = vec![];
+    for (i, frame) in wal.frames.iter().enumerate() {
+        let current = frame.salt == wal.header.salt;
+        let state = if i < committed {
+            FrameState::Committed
+        } else if i < valid {
+            FrameState::Uncommitted
+        } else if current || (i > 0 && wal.frames[i - 1].salt == frame.salt
+                              && !follows(big_endian, &wal.frames[i - 1], frame)) {
+            FrameState::Invalid
+        } else {
+            FrameState::Stale
+        };
+        let transaction = match state {
+            FrameState::Committed | FrameState::Stale => wal.frames[i..].iter()
+                .take_while(|x| x.salt == frame.salt)
+                .position(|x| x.is_commit())
+                .map(|x| i + x + 1),
+            _ => None,
+        };
+        let age = if current { 0 } else { wal.header.salt.0.wrapping_sub(frame.salt.0).max(1) };
+        let version = PageVersion { page_number: frame.page_number, frame: Some(i + 1),
+                                    salt: Some(frame.salt), transaction, state, data: &frame.data };
+        versions.push(((if current { 2 } else { 0 }, Reverse(age), i), version));
+    }
+
+    let mut pages: Vec<u32> = wal.frames.iter().map(|x| x.page_number).collect();
+    pages.sort();
+    pages.dedup();
+    for page_number in pages {
+        if let Ok(data) = file.page_bytes(page_number as usize) {
+            let version = PageVersion { page_number, frame: None, salt: None, transaction: None,
+                                        state: FrameState::Database, data };
+            versions.push(((1, Reverse(0), page_number as usize), version));
+        }
+    }
+    versions.sort_by_key(|x| x.0);
+    versions.into_iter().map(|x| x.1).collect()
+}
+
+/*
+ * The pages of the database as of some point in its history, falling back
+ * to the database file for pages that were not written since
+ */
+struct Snapshot<'a> {
+    file: &'a SqliteFile,
+    pages: HashMap<u32, &'a [u8]>,
+}
+
+impl<'a> Snapshot<'a> {
+    fn page(&self, page_id: u32) -> Option<&'a [u8]> {
+        match self.pages.get(&page_id) {
+            Some(page) => Some(page),
+            None => self.file.page_bytes(page_id as usize).ok(),
+        }
+    }
+
+    fn payload(&self, cell: &BTreeCell) -> Option<Vec<u8>> {
+        let usable_size = self.file.header.usable_size() as usize;
+        let mut payload = cell.payload.clone();
+        let mut next_page = cell.overflow_page;
+        let mut visited = 0;
+        while let Some(page_id) = next_page {
+            if payload.len() as u64 >= cell.payload_size {
+                break
+            }
+            visited += 1;
+            if visited > self.file.page_count() as usize + self.pages.len() {
+                return None
+            }
+            let page = self.page(page_id)?;
+            let remaining = cell.payload_size as usize - payload.len();
+            payload.extend_from_slice(&page[4..4 + remaining.min(usable_size - 4)]);
+            let next = u32::from_be_bytes([page[0], page[1], page[2], page[3]]);
+            next_page = if next == 0 { None } else { Some(next) };
+        }
+        if (payload.len() as u64) < cell.payload_size { None } else { Some(payload) }
+    }
+
+    /*
+     * The rows of a table leaf page, none for any other kind of page
+     */
+    fn rows(&self, page: &[u8]) -> Vec<(i64, Record)> {
+        let btree_page = match parse_btree_page(page) {
+            Ok(x) if x.header.page_type == BTreePageType::LeafTablePage => x,
+            _ => return vec![],
+        };
+        // One cell at a time, so a bad cell pointer loses only its own row
+        let mut rows: Vec<(i64, Record)> = btree_page.cell_pointer.iter()
+            .filter_map(|&pointer| {
+                let single = BTreePage { header: btree_page.header, cell_pointer: vec![pointer] };
+                let cell = parse_cells(page, &single, self.file.header.usable_size()).ok()?.remove(0);
+                let record = parse_record(&self.payload(&cell)?, self.file.header.text_encoding).ok()?;
+                Some((cell.rowid.unwrap_or(0), record))
+            })
+            .collect();
+        rows.sort_by_key(|x| x.0);
+        rows
+    }
+}
+
+/*
+ * The rows that differ between two versions of a page, both sorted by rowid
+ */
+pub fn diff_rows(old: &[(i64, Record)], new: &[(i64, Record)]) -> Vec<(i64, ChangeKind)> {
+    let mut changes = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if j == new.len() || (i < old.len() && old[i].0 < new[j].0) {
+            changes.push((old[i].0, ChangeKind::Deleted(old[i].1.clone())));
+            i += 1;
+        } else if i == old.len() || new[j].0 < old[i].0 {
+            changes.push((new[j].0, ChangeKind::Inserted(new[j].1.clone())));
+            j += 1;
+        } else {
+            if old[i].1 != new[j].1 {
+                changes.push((old[i].0, ChangeKind::Updated(old[i].1.clone(), new[j].1.clone())));
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    changes
+}
+
+/*
+ * The table or index each page belongs to, from the database as the WAL leaves it
+ * and then as the file has it
+ */
+fn page_owners(file: &SqliteFile, wal: &Wal) -> HashMap<u32, String> {
+    let mut owners = HashMap::new();
+    let checkpointed = wal.checkpoint(file).ok();
+    for file in checkpointed.iter().chain(Some(file)) {
+        for (page_id, owner) in diff::page_owners(file) {
+            if let PageOwner::BTree(name) = owner {
+                owners.entry(page_id).or_insert(name);
+            }
+        }
+    }
+    owners
+}
+
+/*
+ * A row that leaves one page and shows up on another in the same
+ * transaction was moved by a page split or merge, and only its values can
+ * have changed
+ */
+fn merge_moves(changes: Vec<RowChange>) -> Vec<RowChange> {
+    let mut inserted = HashMap::new();
+    for (i, change) in changes.iter().enumerate() {
+        if let ChangeKind::Inserted(_) = change.kind {
+            inserted.insert((change.table.clone(), change.rowid), i);
+        }
+    }
+    let mut moves = HashMap::new();
+    for (i, change) in changes.iter().enumerate() {
+        if let ChangeKind::Deleted(_) = change.kind {
+            if let Some(&j) = inserted.get(&(change.table.clone(), change.rowid)) {
+                moves.insert(j, i);
+            }
+        }
+    }
+    let deleted: HashSet<usize> = moves.values().cloned().collect();
+    let mut merged = vec![];
+    for (i, change) in changes.iter().enumerate() {
+        if deleted.contains(&i) {
+            continue
+        }
+        let mut change = change.clone();
+        if let Some(&k) = moves.get(&i) {
+            match (&changes[k].kind, &change.kind) {
+                (ChangeKind::Deleted(old), ChangeKind::Inserted(new)) if old != new => {
+                    change.kind = ChangeKind::Updated(old.clone(), new.clone());
+                },
+                _ => continue,
+            }
+        }
+        merged.push(change);
+    }
+    merged
+}
+
+/*
+ * The rows each version of a table leaf page changed, transaction by
+ * transaction. The first version of a page in an earlier generation has
+ * nothing to be compared with, neither has the version in the database file
+ * if no stale frame holds the page, while pages the current generation adds
+ * to the database start out empty. Overflow pages are read as of the same
+ * transaction, for stale generations they may be missing from the WAL and
+ * are then taken from the newer database file.
+ */
+pub fn history<'a>(file: &'a SqliteFile, wal: &'a Wal) -> Result<Vec<RowChange<'a>>, String> {
+    if wal.header.page_size != file.header.page_size {
+        return Err(format!("WAL page size {} differs from the database", wal.header.page_size))
+    }
+    if !file.header.page_layout_is_valid() {
+        return Err(format!("Invalid page size {} with {} reserved bytes",
+                           file.header.page_size, file.header.reserved_space))
+    }
+    let owners = page_owners(file, wal);
+    let versions = page_versions(file, wal);
+    let mut snapshot = Snapshot { file, pages: HashMap::new() };
+    let mut previous: HashMap<u32, Vec<(i64, Record)>> = HashMap::new();
+    let mut changes = vec![];
+    let mut start = 0;
+    while start < versions.len() {
+        let first = &versions[start];
+        let end = start + versions[start..].iter()
+            .take_while(|x| (x.salt, x.transaction, x.state) == (first.salt, first.transaction, first.state))
+            .count();
+        let group = &versions[start..end];
+        start = end;
+        if first.state == FrameState::Database {
+            snapshot.pages.clear();
+        } else {
+            snapshot.pages.extend(group.iter().map(|x| (x.page_number, x.data)));
+        }
+
+        let mut group_changes = vec![];
+        for version in group {
+            let rows = snapshot.rows(version.data);
+            let current = version.salt.is_some() && version.salt == Some(wal.header.salt);
+            let old = match previous.get(&version.page_number) {
+                Some(old) => Some(old.as_slice()),
+                None if current => Some(&[][..]),
+                None => None,
+            };
+            if let Some(old) = old {
+                for (rowid, kind) in diff_rows(old, &rows) {
+                    let table = owners.get(&version.page_number).cloned();
+                    group_changes.push(RowChange { table, rowid, kind, version: version.clone() });
+                }
+            }
+            previous.insert(version.page_number, rows);
+        }
+        changes.extend(merge_moves(group_changes));
+    }
+    Ok(changes)
+}
+
+impl std::fmt::Display for FrameState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   FrameState::Database => "database file",
+                   FrameState::Committed => "committed",
+                   FrameState::Uncommitted => "uncommitted",
+                   FrameState::Stale => "stale",
+                   FrameState::Invalid => "invalid",
+               })
+    }
+}
+
+impl<'a> std::fmt::Display for PageVersion<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "page {}, ", self.page_number)?;
+        let frame = match self.frame {
+            Some(frame) => frame,
+            None => return write!(f, "{}", self.state),
+        };
+        match (self.state, self.transaction) {
+            (FrameState::Committed, Some(commit)) => {
+                write!(f, "frame {} (committed by frame {})", frame, commit)
+            },
+            (state, Some(commit)) => write!(f, "frame {} ({}, committed by frame {})", frame, state, commit),
+            (state, None) => write!(f, "frame {} ({})", frame, state),
+        }
+    }
+}
+
+impl std::fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let values = |record: &Record| {
+            record.values.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")
+        };
+        match *self {
+            ChangeKind::Inserted(ref record) => write!(f, "inserted ({})", values(record)),
+            ChangeKind::Deleted(ref record) => write!(f, "deleted ({})", values(record)),
+            ChangeKind::Updated(ref old, ref new) => {
+                write!(f, "updated ({}) -> ({})", values(old), values(new))
+            },
+        }
+    }
+}
+
+impl<'a> std::fmt::Display for RowChange<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} rowid {}: {} [{}]",
+               self.table.as_ref().map_or("?", |x| x.as_str()), self.rowid, self.kind, self.version)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use checksum::wal_checksum;
+    use data_structures::{Record, SqliteFile, Value, Wal, WalFrame, WalHeader};
+    use enums::TextEncoding;
+    use parser::parse_record;
+    use transaction::Transaction;
+    use writer::DatabaseBuilder;
+    use Parser;
+
+    fn row(i: i64, n: i64) -> (i64, Vec<Value>) {
+        (i, vec![Value::Text(format!("name {}", i)), Value::Integer(n)])
+    }
+
+    /*
+     * Run a transaction and write the pages it changed to the WAL from frame
+     * index on, chained to the frame before or to the header
+     */
+    fn commit<F: FnOnce(&mut Transaction)>(file: SqliteFile, wal: &mut Wal, index: usize, f: F)
+                                           -> SqliteFile {
+        let before = file.as_bytes().to_vec();
+        let mut transaction = Transaction::new(file).unwrap();
+        f(&mut transaction);
+        let after = transaction.into_file().unwrap();
+        let page_size = wal.header.page_size as usize;
+        let page = |i: usize| &after.as_bytes()[i * page_size..(i + 1) * page_size];
+        let pages: Vec<usize> = (0..after.as_bytes().len() / page_size)
+            .filter(|&i| before.get(i * page_size..(i + 1) * page_size) != Some(page(i)))
+            .collect();
+        let big_endian = wal.header.big_endian_checksums();
+        for (n, &i) in pages.iter().enumerate() {
+            let mut frame = WalFrame { page_number: i as u32 + 1, commit_size: 0, salt: wal.header.salt,
+                                       checksum: (0, 0), data: page(i).to_vec() };
+            if n + 1 == pages.len() {
+                frame.commit_size = after.page_count();
+            }
+            let seed = match index + n {
+                0 => wal.header.checksum,
+                k => wal.frames[k - 1].checksum,
+            };
+            let mut frame_header = [0; 8];
+            frame_header[..4].copy_from_slice(&frame.page_number.to_be_bytes());
+            frame_header[4..].copy_from_slice(&frame.commit_size.to_be_bytes());
+            let seed = wal_checksum(big_endian, &frame_header, seed);
+            frame.checksum = wal_checksum(big_endian, &frame.data, seed);
+            match wal.frames.get_mut(index + n) {
+                Some(old) => *old = frame,
+                None => wal.frames.push(frame),
+            }
+        }
+        after
+    }
+
+    #[test]
+    fn row_changes_across_generations() {
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        builder.add_table("t", "CREATE TABLE t(name TEXT, n INTEGER)", (1..300).map(|i| row(i, 0))).unwrap();
+        let file = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+        let mut header = WalHeader { magic: 0x377f_0682, format_version: 3_007_000, page_size: 1024,
+                                     checkpoint_sequence: 0, salt: (7, 1234), checksum: (0, 0) };
+        header.checksum = wal_checksum(false, &header.to_bytes()[..24], (0, 0));
+        let mut wal = Wal { header, frames: vec![] };
+
+        // Three transactions on the same leaf, checkpointed before the WAL restarts
+        let file = commit(file, &mut wal, 0, |t| t.update("t", 5, &row(5, 1).1).unwrap());
+        let file = commit(file, &mut wal, 2, |t| t.update("t", 5, &row(5, 2).1).unwrap());
+        let file = commit(file, &mut wal, 4, |t| { t.delete("t", 6).unwrap(); });
+        wal.header.salt = (8, 4321);
+        wal.header.checksum = wal_checksum(false, &wal.header.to_bytes()[..24], (0, 0));
+        let database = SqliteFile::from_vec(file.as_bytes()).unwrap();
+        commit(file, &mut wal, 0, |t| { t.delete("t", 250).unwrap(); });
+
+        assert_eq!(2, wal.committed_frames().len());
+        let versions = super::page_versions(&database, &wal);
+        let states: Vec<_> = versions.iter().map(|x| (x.frame, x.state)).collect();
+        assert_eq!(vec![(Some(3), super::FrameState::Stale), (Some(4), super::FrameState::Stale),
+                        (Some(5), super::FrameState::Stale), (Some(6), super::FrameState::Stale),
+                        (None, super::FrameState::Database), (None, super::FrameState::Database),
+                        (None, super::FrameState::Database), (Some(1), super::FrameState::Committed),
+                        (Some(2), super::FrameState::Committed)], states);
+
+        let changes = super::history(&database, &wal).unwrap();
+        let summary: Vec<_> = changes.iter()
+            .map(|x| (x.table.as_deref(), x.rowid, x.version.transaction, x.version.state))
+            .collect();
+        assert_eq!(vec![(Some("t"), 6, Some(6), super::FrameState::Stale),
+                        (Some("t"), 250, Some(2), super::FrameState::Committed)], summary);
+        let deleted = parse_record(&Record::encode(&row(6, 0).1), TextEncoding::UTF8).unwrap();
+        assert_eq!(super::ChangeKind::Deleted(deleted), changes[0].kind);
+    }
+
+    #[test]
+    fn bad_cell_pointer_loses_one_row() {
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        builder.add_table("t", "CREATE TABLE t(name TEXT, n INTEGER)", (1..4).map(|i| row(i, 0))).unwrap();
+        let file = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+        let mut page = file.page_bytes(2).unwrap().to_vec();
+        // The second cell pointer points past the end of the page
+        page[10..12].copy_from_slice(&2000u16.to_be_bytes());
+        let snapshot = super::Snapshot { file: &file, pages: HashMap::new() };
+        let rowids: Vec<i64> = snapshot.rows(&page).iter().map(|x| x.0).collect();
+        assert_eq!(vec![1, 3], rowids);
+    }
+}
@@ -1,60 +1,111 @@
 #[macro_use]
 extern crate nom;
+#[cfg(test)]
+extern crate proptest;
+#[cfg(feature = "sqlcipher")]
+extern crate aes;
+#[cfg(feature = "sqlcipher")]
+extern crate cbc;
+#[cfg(feature = "sqlcipher")]
+extern crate hmac;
+#[cfg(feature = "sqlcipher")]
+extern crate pbkdf2;
+#[cfg(feature = "sqlcipher")]
+extern crate sha1;
+#[cfg(feature = "sqlcipher")]
+extern crate sha2;
 
 pub mod parser;
 pub mod data_structures;
+pub mod checksum;
+pub mod writer;
+pub mod transaction;
+pub mod recovery;
+pub mod carving;
+pub mod salvage;
+pub mod history;
+pub mod diff;
+#[cfg(feature = "sqlcipher")]
+pub mod sqlcipher;
 mod enums;
 
+use std::fs;
 use std::fs::File;
-use std::io::prelude::Read;
+use std::io::prelude::{Read, Write};
 use std::path::Path;
-use std::error::Error;
+use std::process;
 
 use nom::{ErrorKind, IResult};
 
-pub use data_structures::{Header, BTreePageHeader, BTreePage, SqliteFile};
-use parser::header_parser;
+pub use data_structures::{Header, BTreePageHeader, BTreePage, BTreeCell, SqliteFile, SqliteVersion,
+                          Journal, Wal, Changeset, Value, Record, SchemaEntry};
+pub use enums::{Affinity, ApplicationId, Collation, Feature, HeaderWarning, TextEncoding};
+use parser::{header_parser, parse_btree_page};
+
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    let path = Path::new(path);
+
+    let mut file = match File::open(path) {
+        Err(why) => return Err(format!("couldn't open file {}", why)),
+        Ok(file) => file,
+    };
+
+    let mut buffer = Vec::<u8>::new();
+    match file.read_to_end(&mut buffer) {
+        Ok(_) => Ok(buffer),
+        Err(why) => Err(format!("couldn't read file {}", why)),
+    }
+}
+
+/*
+ * Write to a temporary file next to the target and rename it over the
+ * target, so readers never see a partially written file.
+ */
+pub fn write_file_atomic(path: &str, buffer: &[u8]) -> Result<(), String> {
+    let tmp_path = format!("{}.{}.tmp", path, process::id());
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(buffer)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+    match result {
+        Ok(_) => Ok(()),
+        Err(why) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(format!("couldn't write file {}", why))
+        }
+    }
+}
 
 
 // TODO: Only parse header and provide access functions for the rest.
 // Doesn't need to be generic
 pub trait Parser<T> {
     fn from_file(path: &str) -> Result<T, String>;
-    fn from_vec(buffer: &Vec<u8>) -> Result<T, String>;
+    fn from_vec(buffer: &[u8]) -> Result<T, String>;
     fn is_valid(&self) -> bool;
-    fn get_page(&self, usize) -> Result<BTreePage, String>;
+    fn get_page(&self, page_id: usize) -> Result<BTreePage, String>;
 }
 
 
 impl Parser<SqliteFile> for SqliteFile {
     fn from_file(path: &str) -> Result<SqliteFile, String> {
-        let path = Path::new(path);
-
-        println!("Path: {:?}", path);
-        let mut file = match File::open(&path) {
-            // The `description` method of `io::Error` returns a string that
-            // describes the error
-            Err(why) => panic!("couldn't open file {}", why.description()),
-            Ok(file) => file,
-        };
-
-        let mut buffer = Vec::<u8>::with_capacity(100);
-        let count = match file.read_to_end(&mut buffer) {
-            Ok(n) => n,
-            Err(why) => panic!("couldn't read header {}", why.description()),
-        };
-        assert!(count > 100);
+        let buffer = read_file(path)?;
+        if buffer.len() < 100 {
+            return Err("Incomplete header".to_string())
+        }
         SqliteFile::from_vec(&buffer)
     }
 
-    fn from_vec(buffer: &Vec<u8>) -> Result<SqliteFile, String> {
+    fn from_vec(buffer: &[u8]) -> Result<SqliteFile, String> {
         let (_, file_header) = match header_parser(buffer) {
             IResult::Done(x, y) => (x, y),
             IResult::Error(ErrorKind::Tag) => {
                 return Err("File is not SQLite Database".to_string())
             },
-            IResult::Error(x) => {
-                println!("{:?}", x);
+            IResult::Error(_) => {
                 return Err("Error parsing header".to_string())
             },
             IResult::Incomplete(_) => { return Err("Incomplete header".to_string()) },
@@ -64,11 +115,11 @@ impl Parser<SqliteFile> for SqliteFile {
     }
 
     fn is_valid(&self) -> bool {
-        unimplemented!()
+        self.validate().is_empty()
     }
 
     fn get_page(&self, page_id: usize) -> Result<BTreePage, String> {
-        unimplemented!()
+        parse_btree_page(self.page_bytes(page_id)?)
     }
 }
 
@@ -0,0 +1,176 @@
+use nom::{IResult, be_u32};
+
+use data_structures::{BTreeCell, BTreePage, BTreePageType};
+use parser::generic::parse_varint;
+
+/*
+ * Number of payload bytes stored on the B-tree page itself, the rest goes to
+ * overflow pages. All thresholds depend on the usable size, not the page size.
+ * Usable sizes below SQLite's minimum of 480 give nonsense, but no panic.
+ */
+pub fn local_payload_size(payload_size: u64, usable_size: u32, page_type: BTreePageType) -> usize {
+    let usable_size = usable_size as u64;
+    let max_local = match page_type {
+        BTreePageType::LeafTablePage => usable_size.saturating_sub(35),
+        _ => (usable_size.saturating_sub(12) * 64 / 255).saturating_sub(23),
+    };
+    if payload_size <= max_local {
+        return payload_size as usize
+    }
+    let min_local = (usable_size.saturating_sub(12) * 32 / 255).saturating_sub(23);
+    let local = min_local + (payload_size - min_local) % usable_size.saturating_sub(4).max(1);
+    if local <= max_local {
+        local as usize
+    } else {
+        min_local as usize
+    }
+}
+
+fn payload_parser(i: &[u8], payload_size: u64, usable_size: u32, page_type: BTreePageType)
+                  -> IResult<&[u8], (Vec<u8>, Option<u32>)> {
+    let local = local_payload_size(payload_size, usable_size, page_type);
+    if local as u64 == payload_size {
+        do_parse!(i,
+            payload: take!(local) >>
+            ((payload.to_vec(), None))
+        )
+    } else {
+        do_parse!(i,
+            payload: take!(local) >>
+            overflow_page: be_u32 >>
+            ((payload.to_vec(), Some(overflow_page)))
+        )
+    }
+}
+
+pub fn cell_parser(i: &[u8], usable_size: u32, page_type: BTreePageType)
+                   -> IResult<&[u8], BTreeCell> {
+    match page_type {
+        BTreePageType::LeafTablePage => do_parse!(i,
+            payload_size: parse_varint >>
+            rowid: parse_varint >>
+            payload: apply!(payload_parser, payload_size, usable_size, page_type) >>
+            (BTreeCell {
+                left_child: None,
+                rowid: Some(rowid as i64),
+                payload_size,
+                payload: payload.0,
+                overflow_page: payload.1,
+            })
+        ),
+        BTreePageType::InteriorTablePage => do_parse!(i,
+            left_child: be_u32 >>
+            rowid: parse_varint >>
+            (BTreeCell {
+                left_child: Some(left_child),
+                rowid: Some(rowid as i64),
+                payload_size: 0,
+                payload: vec![],
+                overflow_page: None,
+            })
+        ),
+        BTreePageType::LeafIndexPage => do_parse!(i,
+            payload_size: parse_varint >>
+            payload: apply!(payload_parser, payload_size, usable_size, page_type) >>
+            (BTreeCell {
+                left_child: None,
+                rowid: None,
+                payload_size,
+                payload: payload.0,
+                overflow_page: payload.1,
+            })
+        ),
+        BTreePageType::InteriorIndexPage => do_parse!(i,
+            left_child: be_u32 >>
+            payload_size: parse_varint >>
+            payload: apply!(payload_parser, payload_size, usable_size, page_type) >>
+            (BTreeCell {
+                left_child: Some(left_child),
+                rowid: None,
+                payload_size,
+                payload: payload.0,
+                overflow_page: payload.1,
+            })
+        ),
+        BTreePageType::NullPage => IResult::Done(i, BTreeCell {
+            left_child: None,
+            rowid: None,
+            payload_size: 0,
+            payload: vec![],
+            overflow_page: None,
+        }),
+    }
+}
+
+/*
+ * Bytes a cell occupies on its page. SQLite never allocates less than four
+ * bytes, so that a freed cell can always become a freeblock.
+ */
+pub fn cell_size(cell: &[u8], usable_size: u32, page_type: BTreePageType) -> Result<usize, String> {
+    match cell_parser(cell, usable_size, page_type) {
+        IResult::Done(rest, _) => Ok((cell.len() - rest.len()).max(4)),
+        _ => Err("Error parsing cell".to_string()),
+    }
+}
+
+/*
+ * Parse every cell of a page. Cells have to lie within the cell content area
+ * and must not reach into the reserved space at the end of the page.
+ */
+pub fn parse_cells(page: &[u8], btree_page: &BTreePage, usable_size: u32)
+                   -> Result<Vec<BTreeCell>, String> {
+    let usable = &page[..(usable_size as usize).min(page.len())];
+    let mut cells = vec![];
+    for &pointer in &btree_page.cell_pointer {
+        let pointer = pointer as usize;
+        if (pointer as u32) < btree_page.header.cell_content_offset || pointer >= usable.len() {
+            return Err(format!("Cell pointer {} is outside the cell content area", pointer))
+        }
+        match cell_parser(&usable[pointer..], usable_size, btree_page.header.page_type) {
+            IResult::Done(_, cell) => cells.push(cell),
+            IResult::Error(_) => return Err(format!("Error parsing cell at {}", pointer)),
+            IResult::Incomplete(_) => {
+                return Err(format!("Cell at {} extends beyond the usable size", pointer))
+            },
+        }
+    }
+    Ok(cells)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_structures::BTreePageType;
+
+    #[test]
+    fn local_payload_thresholds() {
+        // Usable size 4096: X = 4061 for table leaves, 1002 for index pages, M = 489
+        assert_eq!(4061, super::local_payload_size(4061, 4096, BTreePageType::LeafTablePage));
+        assert_eq!(489, super::local_payload_size(4062, 4096, BTreePageType::LeafTablePage));
+        assert_eq!(908, super::local_payload_size(5000, 4096, BTreePageType::LeafTablePage));
+        assert_eq!(1002, super::local_payload_size(1002, 4096, BTreePageType::LeafIndexPage));
+        assert_eq!(489, super::local_payload_size(3000, 4096, BTreePageType::LeafIndexPage));
+        // 8 reserved bytes shrink every threshold
+        assert_eq!(4053, super::local_payload_size(4053, 4088, BTreePageType::LeafTablePage));
+        assert_eq!(916, super::local_payload_size(5000, 4088, BTreePageType::LeafTablePage));
+        // Below the minimum usable size of 480 the result is meaningless, but computed
+        assert_eq!(0, super::local_payload_size(1000, 30, BTreePageType::LeafTablePage));
+        assert_eq!(0, super::local_payload_size(1000, 4, BTreePageType::InteriorIndexPage));
+    }
+
+    #[test]
+    fn table_leaf_cell_with_overflow() {
+        let usable_size = 512;
+        let payload_size = 600u64;
+        let local = super::local_payload_size(payload_size, usable_size, BTreePageType::LeafTablePage);
+        let mut buffer = vec![0x84, 0x58, 0x07];
+        buffer.extend(vec![0xAB; local]);
+        buffer.extend_from_slice(&[0, 0, 0, 9]);
+        let (rest, cell) = super::cell_parser(&buffer, usable_size, BTreePageType::LeafTablePage).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(Some(7), cell.rowid);
+        assert_eq!(600, cell.payload_size);
+        assert_eq!(local, cell.payload.len());
+        assert_eq!(Some(9), cell.overflow_page);
+    }
+}
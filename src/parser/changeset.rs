@@ -0,0 +1,240 @@
+use nom::{IResult, ErrorKind, Needed, be_i64, be_u64};
+
+use data_structures::{Change, ChangeOperation, Changeset, ChangesetTable, Value};
+use parser::generic::parse_varint;
+
+/*
+ * A value of a changeset record: a type byte, then eight bytes for numbers
+ * or a varint length and the bytes for TEXT and BLOB. Type 0 marks a value
+ * that is not part of the change and has no data.
+ */
+fn value_parser(i: &[u8]) -> IResult<&[u8], Option<Value>> {
+    let (&value_type, rest) = match i.split_first() {
+        Some(x) => x,
+        None => return IResult::Incomplete(Needed::Size(1)),
+    };
+    match value_type {
+        0 => IResult::Done(rest, None),
+        5 => IResult::Done(rest, Some(Value::Null)),
+        1 => be_i64(rest).map(|x| Some(Value::Integer(x))),
+        2 => be_u64(rest).map(|x| Some(Value::Real(f64::from_bits(x)))),
+        3 | 4 => {
+            let (rest, length) = match parse_varint(rest) {
+                IResult::Done(rest, x) => (rest, x as usize),
+                IResult::Error(e) => return IResult::Error(e),
+                IResult::Incomplete(n) => return IResult::Incomplete(n),
+            };
+            if rest.len() < length {
+                return IResult::Incomplete(Needed::Size(length - rest.len()))
+            }
+            let (data, rest) = rest.split_at(length);
+            match value_type {
+                3 => IResult::Done(rest, Some(Value::Text(String::from_utf8_lossy(data).into_owned()))),
+                _ => IResult::Done(rest, Some(Value::Blob(data.to_vec()))),
+            }
+        },
+        _ => IResult::Error(ErrorKind::Custom(2)),
+    }
+}
+
+/*
+ * A value for every column where fields is true
+ */
+fn values_parser<'a>(mut i: &'a [u8], fields: &[bool]) -> IResult<&'a [u8], Vec<Option<Value>>> {
+    let mut values = vec![];
+    for &field in fields {
+        if !field {
+            values.push(None);
+            continue
+        }
+        match value_parser(i) {
+            IResult::Done(rest, x) => {
+                values.push(x);
+                i = rest;
+            },
+            IResult::Error(e) => return IResult::Error(e),
+            IResult::Incomplete(n) => return IResult::Incomplete(n),
+        }
+    }
+    IResult::Done(i, values)
+}
+
+/*
+ * 'T' for a changeset or 'P' for a patchset, the number of columns, a byte
+ * per column that is 1 for primary key columns, and the table name
+ */
+fn table_header_parser(i: &[u8]) -> IResult<&[u8], (bool, ChangesetTable)> {
+    let patchset = match i.first() {
+        Some(b'T') => false,
+        Some(b'P') => true,
+        Some(_) => return IResult::Error(ErrorKind::Custom(0)),
+        None => return IResult::Incomplete(Needed::Size(1)),
+    };
+    let (rest, column_count) = match parse_varint(&i[1..]) {
+        IResult::Done(rest, x) => (rest, x as usize),
+        IResult::Error(e) => return IResult::Error(e),
+        IResult::Incomplete(n) => return IResult::Incomplete(n),
+    };
+    if rest.len() < column_count {
+        return IResult::Incomplete(Needed::Size(column_count - rest.len()))
+    }
+    let primary_key = rest[..column_count].iter().map(|&x| x != 0).collect();
+    let rest = &rest[column_count..];
+    let end = match rest.iter().position(|&x| x == 0) {
+        Some(x) => x,
+        None => return IResult::Incomplete(Needed::Unknown),
+    };
+    let name = String::from_utf8_lossy(&rest[..end]).into_owned();
+    IResult::Done(&rest[end + 1..], (patchset, ChangesetTable { name, primary_key, changes: vec![] }))
+}
+
+/*
+ * The operation, the indirect flag and the records. A changeset has the old
+ * values of DELETE and UPDATE and the new values of INSERT and UPDATE. A
+ * patchset only has the primary key of a DELETE and no old values for an
+ * UPDATE.
+ */
+fn change_parser<'a>(i: &'a [u8], patchset: bool, primary_key: &[bool]) -> IResult<&'a [u8], Change> {
+    if i.len() < 2 {
+        return IResult::Incomplete(Needed::Size(2 - i.len()))
+    }
+    let operation = match i[0] {
+        18 => ChangeOperation::Insert,
+        9 => ChangeOperation::Delete,
+        23 => ChangeOperation::Update,
+        _ => return IResult::Error(ErrorKind::Custom(1)),
+    };
+    let indirect = i[1] != 0;
+    let all = vec![true; primary_key.len()];
+    let (old_fields, new_fields): (&[bool], &[bool]) = match (operation, patchset) {
+        (ChangeOperation::Insert, _) => (&[], &all),
+        (ChangeOperation::Delete, false) => (&all, &[]),
+        (ChangeOperation::Delete, true) => (primary_key, &[]),
+        (ChangeOperation::Update, false) => (&all, &all),
+        (ChangeOperation::Update, true) => (&[], &all),
+    };
+    let (rest, old) = match values_parser(&i[2..], old_fields) {
+        IResult::Done(rest, x) => (rest, x),
+        IResult::Error(e) => return IResult::Error(e),
+        IResult::Incomplete(n) => return IResult::Incomplete(n),
+    };
+    let (rest, new) = match values_parser(rest, new_fields) {
+        IResult::Done(rest, x) => (rest, x),
+        IResult::Error(e) => return IResult::Error(e),
+        IResult::Incomplete(n) => return IResult::Incomplete(n),
+    };
+    IResult::Done(rest, Change { operation, indirect, old, new })
+}
+
+/*
+ * Tables one after the other, each a header followed by its changes. A
+ * table can appear more than once if changesets were concatenated.
+ */
+pub fn changeset_parser(mut i: &[u8]) -> IResult<&[u8], Changeset> {
+    let mut changeset = Changeset { patchset: i.first() == Some(&b'P'), tables: vec![] };
+    while !i.is_empty() {
+        if i[0] == b'T' || i[0] == b'P' {
+            match table_header_parser(i) {
+                IResult::Done(_, (patchset, _)) if patchset != changeset.patchset => {
+                    return IResult::Error(ErrorKind::Custom(3))
+                },
+                IResult::Done(rest, (_, table)) => {
+                    changeset.tables.push(table);
+                    i = rest;
+                },
+                IResult::Error(e) => return IResult::Error(e),
+                IResult::Incomplete(n) => return IResult::Incomplete(n),
+            }
+            continue
+        }
+        let table = match changeset.tables.last_mut() {
+            Some(x) => x,
+            None => return IResult::Error(ErrorKind::Custom(0)),
+        };
+        match change_parser(i, changeset.patchset, &table.primary_key) {
+            IResult::Done(rest, change) => {
+                table.changes.push(change);
+                i = rest;
+            },
+            IResult::Error(e) => return IResult::Error(e),
+            IResult::Incomplete(n) => return IResult::Incomplete(n),
+        }
+    }
+    IResult::Done(i, changeset)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_structures::{Change, ChangeOperation, Changeset, ChangesetTable, Value};
+
+    fn integer(x: i64) -> Vec<u8> {
+        let mut bytes = vec![1];
+        bytes.extend_from_slice(&x.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn changeset_records() {
+        let mut buffer = vec![b'T', 2, 1, 0, b't', 0];
+        buffer.extend(&[18, 0]);
+        buffer.extend(integer(1));
+        buffer.extend(&[3, 1, b'a']);
+        buffer.extend(&[23, 1]);
+        buffer.extend(integer(1));
+        buffer.extend(&[3, 1, b'a', 0, 3, 1, b'b']);
+        buffer.extend(&[9, 0]);
+        buffer.extend(integer(2));
+        buffer.push(5);
+
+        let (rest, changeset) = super::changeset_parser(&buffer).unwrap();
+        assert!(rest.is_empty());
+        assert!(!changeset.patchset);
+        assert_eq!(vec![true, false], changeset.tables[0].primary_key);
+        let text = |x: &str| Some(Value::Text(x.to_string()));
+        assert_eq!(Change { operation: ChangeOperation::Update, indirect: true,
+                            old: vec![Some(Value::Integer(1)), text("a")], new: vec![None, text("b")] },
+                   changeset.tables[0].changes[1]);
+        assert_eq!(buffer, changeset.to_bytes());
+        assert_eq!("Changeset with 3 changes\n\
+                    Table t: 2 columns, primary key (0)\n  \
+                    INSERT (1, 'a')\n  \
+                    UPDATE (1, 'a') -> (-, 'b') (indirect)\n  \
+                    DELETE (2, NULL)", changeset.to_string());
+        let tables = r#"{"patchset":false,"tables":[{"name":"t","primary_key":[true,false],"changes":["#;
+        assert_eq!(tables.to_string()
+                   + r#"{"operation":"INSERT","indirect":false,"old":{},"new":{"0":1,"1":"a"}},"#
+                   + r#"{"operation":"UPDATE","indirect":true,"old":{"0":1,"1":"a"},"new":{"1":"b"}},"#
+                   + r#"{"operation":"DELETE","indirect":false,"old":{"0":2,"1":null},"new":{}}]}]}"#,
+                   changeset.to_json());
+    }
+
+    #[test]
+    fn patchset_round_trip() {
+        let values = vec![Some(Value::Text("k\"\n".to_string())), Some(Value::Integer(7)),
+                          Some(Value::Real(-2.5)), Some(Value::Blob(vec![0, 255]))];
+        let changeset = Changeset {
+            patchset: true,
+            tables: vec![ChangesetTable {
+                name: "wide".to_string(),
+                primary_key: vec![true, true, false, false],
+                changes: vec![
+                    Change { operation: ChangeOperation::Insert, indirect: false, old: vec![],
+                             new: values.clone() },
+                    Change { operation: ChangeOperation::Update, indirect: false, old: vec![],
+                             new: vec![values[0].clone(), values[1].clone(), None, Some(Value::Null)] },
+                    Change { operation: ChangeOperation::Delete, indirect: false,
+                             old: vec![values[0].clone(), values[1].clone(), None, None], new: vec![] },
+                ],
+            }],
+        };
+        let bytes = changeset.to_bytes();
+        // The patchset DELETE has the primary key only
+        assert!(bytes.ends_with(&[9, 0, 3, 3, b'k', b'"', b'\n', 1, 0, 0, 0, 0, 0, 0, 0, 7]));
+        let (rest, parsed) = super::changeset_parser(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(changeset, parsed);
+        assert!(parsed.to_json().contains(r#""new":{"0":"k\"\n","1":7,"2":-2.5,"3":{"blob":"00ff"}}"#));
+        assert!(super::changeset_parser(&bytes[..bytes.len() - 1]).is_incomplete());
+    }
+}
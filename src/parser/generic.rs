@@ -1,8 +1,7 @@
 use nom::{IResult, Needed};
 
 
-#[warn(dead_code)]
-fn parse_varint(i: &[u8]) -> IResult<&[u8], u64> {
+pub fn parse_varint(i: &[u8]) -> IResult<&[u8], u64> {
     let mut content: u64 = 0;
     let mut count: usize = 0;
     for &c in i {
@@ -18,11 +17,38 @@ fn parse_varint(i: &[u8]) -> IResult<&[u8], u64> {
         }
         count += 1;
     }
-    return IResult::Incomplete(Needed::Size(9 - count))
+    IResult::Incomplete(Needed::Size(9 - count))
+}
+
+/*
+ * Inverse of parse_varint. Values that need more than 56 bits use the 9 byte
+ * form, where the last byte contributes all 8 bits.
+ */
+pub fn encode_varint(value: u64) -> Vec<u8> {
+    if value > 0x00ff_ffff_ffff_ffff {
+        let mut bytes = vec![0u8; 9];
+        bytes[8] = value as u8;
+        let mut rest = value >> 8;
+        for i in (0..8).rev() {
+            bytes[i] = (rest as u8 & 0x7f) | 0x80;
+            rest >>= 7;
+        }
+        return bytes
+    }
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut rest = value >> 7;
+    while rest != 0 {
+        bytes.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    bytes.reverse();
+    bytes
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     #[test]
     fn varint_simple() {
         let buffer: [u8; 16] = [0x81, 0x3e, 0x05, 0x07, 0x17, 0x29, 0x29, 0x01, 0x82,
@@ -31,4 +57,41 @@ mod tests {
         assert_eq!(190, res);
         assert_eq!(&buffer[2..], i)
     }
+
+    #[test]
+    fn varint_boundaries() {
+        let cases: [(u64, usize); 12] = [
+            (0, 1),
+            (0x7f, 1),
+            (0x80, 2),
+            (0x3fff, 2),
+            (0x4000, 3),
+            (0x1f_ffff, 3),
+            (0x20_0000, 4),
+            (0x00ff_ffff_ffff_ffff, 8),
+            (0x0100_0000_0000_0000, 9),
+            (0x7fff_ffff_ffff_ffff, 9),
+            (0xffff_ffff_ffff_ffff, 9),
+            (190, 2),
+        ];
+        for &(value, size) in cases.iter() {
+            let bytes = super::encode_varint(value);
+            assert_eq!(size, bytes.len(), "size of {}", value);
+            let (rest, decoded) = super::parse_varint(&bytes).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(value, decoded);
+        }
+        assert_eq!(vec![0x81, 0x3e], super::encode_varint(190));
+        assert_eq!(vec![0xff; 9], super::encode_varint(0xffff_ffff_ffff_ffff));
+    }
+
+    proptest! {
+        #[test]
+        fn varint_round_trip(value in any::<u64>()) {
+            let bytes = super::encode_varint(value);
+            let (rest, decoded) = super::parse_varint(&bytes).unwrap();
+            prop_assert!(rest.is_empty());
+            prop_assert_eq!(value, decoded);
+        }
+    }
 }
@@ -7,54 +7,59 @@ use parser::{ParserError, PAGE_SIZE_MAX, HEADER_STRING};
 
 
 pub fn header_parser(i: &[u8]) -> IResult<&[u8], Header> {
-    chain!(i,
-        tag!(HEADER_STRING) ~
-        page_size: page_size_parser ~
-        read_version: read_version_parser ~
-        write_version: write_version_parser ~
-        reserved_space: be_u8 ~
-        max_embedded_payload_fraction: be_u8 ~
-        min_embedded_payload_fraction: be_u8 ~
-        leaf_payload_fraction: be_u8 ~
-        file_change_counter: be_u32 ~
-        database_size: be_u32 ~
-        freelist_trunk_page: be_u32 ~
-        freelist_count: be_u32 ~
-        schema_cookie: be_u32 ~
-        schema_format: schema_format_parser ~
-        default_page_cache_size: be_u32 ~
-        largest_root_page: be_u32 ~
-        text_encoding: text_encoding_parser ~
-        user_version: be_u32 ~
-        incremental_vacuum_mode: vacuum_mode_parser ~
-        application_id: be_u32 ~
-        take!(20) ~
-        version_valid: be_u32 ~
-        sqlite_version: be_u32,
-
-        || Header {
-            page_size: page_size,
-            read_version: read_version,
-            write_version: write_version,
-            reserved_space: reserved_space,
-            max_embedded_payload_fraction: max_embedded_payload_fraction,
-            min_embedded_payload_fraction: min_embedded_payload_fraction,
-            leaf_payload_fraction: leaf_payload_fraction,
-            file_change_counter: file_change_counter,
-            database_size: database_size,
-            freelist_trunk_page: freelist_trunk_page,
-            freelist_count: freelist_count,
-            schema_cookie: schema_cookie,
-            schema_format: schema_format,
-            default_page_cache_size: default_page_cache_size,
-            largest_root_page: largest_root_page,
-            text_encoding: text_encoding,
-            user_version: user_version,
-            incremental_vacuum_mode: incremental_vacuum_mode,
-            application_id: application_id,
-            version_valid_for: version_valid,
-            sqlite_version: sqlite_version,
-        }
+    do_parse!(i,
+        tag!(HEADER_STRING) >>
+        page_size: page_size_parser >>
+        write_version: write_version_parser >>
+        read_version: read_version_parser >>
+        reserved_space: be_u8 >>
+        max_embedded_payload_fraction: be_u8 >>
+        min_embedded_payload_fraction: be_u8 >>
+        leaf_payload_fraction: be_u8 >>
+        file_change_counter: be_u32 >>
+        database_size: be_u32 >>
+        freelist_trunk_page: be_u32 >>
+        freelist_count: be_u32 >>
+        schema_cookie: be_u32 >>
+        schema_format: schema_format_parser >>
+        default_page_cache_size: be_u32 >>
+        largest_root_page: be_u32 >>
+        text_encoding: text_encoding_parser >>
+        user_version: be_u32 >>
+        incremental_vacuum_mode: vacuum_mode_parser >>
+        application_id: be_u32 >>
+        reserved: take!(20) >>
+        version_valid_for: be_u32 >>
+        sqlite_version: be_u32 >>
+
+        (Header {
+            page_size,
+            read_version,
+            write_version,
+            reserved_space,
+            max_embedded_payload_fraction,
+            min_embedded_payload_fraction,
+            leaf_payload_fraction,
+            file_change_counter,
+            database_size,
+            freelist_trunk_page,
+            freelist_count,
+            schema_cookie,
+            schema_format,
+            default_page_cache_size,
+            largest_root_page,
+            text_encoding,
+            user_version,
+            incremental_vacuum_mode,
+            application_id,
+            reserved: {
+                let mut bytes = [0; 20];
+                bytes.copy_from_slice(reserved);
+                bytes
+            },
+            version_valid_for,
+            sqlite_version,
+        })
     )
 }
 
@@ -101,6 +106,7 @@ named!(schema_format_parser<enums::SchemaFormat>,
         be_u32,
         |x: u32| -> Result<enums::SchemaFormat, ParserError> {
             match x {
+                0 => Ok(enums::SchemaFormat::Empty),
                 1 => Ok(enums::SchemaFormat::Format1),
                 2 => Ok(enums::SchemaFormat::Format2),
                 3 => Ok(enums::SchemaFormat::Format3),
@@ -116,6 +122,8 @@ named!(text_encoding_parser<enums::TextEncoding>,
         be_u32,
         |x: u32| -> Result<enums::TextEncoding, ParserError> {
             match x {
+                // The encoding of an empty database isn't set yet
+                0 => Ok(enums::TextEncoding::Unset),
                 1 => Ok(enums::TextEncoding::UTF8),
                 2 => Ok(enums::TextEncoding::UTF16le),
                 3 => Ok(enums::TextEncoding::UTF16be),
@@ -136,3 +144,106 @@ named!(vacuum_mode_parser<bool>,
     )
 );
 
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use enums;
+    use data_structures::Header;
+
+    prop_compose! {
+        fn arb_header()((page_size, write_version, read_version, reserved_space, fractions)
+                         in (9u32..17, 1u8..3, 1u8..3, any::<u8>(), any::<(u8, u8, u8)>()),
+                        (file_change_counter, database_size, freelist_trunk_page, freelist_count,
+                         schema_cookie, schema_format, default_page_cache_size, largest_root_page)
+                         in any::<(u32, u32, u32, u32, u32)>().prop_flat_map(|x|
+                             (Just(x.0), Just(x.1), Just(x.2), Just(x.3), Just(x.4),
+                              1u32..5, any::<u32>(), any::<u32>())),
+                        (text_encoding, user_version, incremental_vacuum_mode, application_id,
+                         reserved, version_valid_for, sqlite_version)
+                         in (0u32..4, any::<u32>(), any::<bool>(), any::<u32>(),
+                             prop::array::uniform20(any::<u8>()), any::<u32>(), any::<u32>()))
+                        -> Header {
+            let read_version = match read_version {
+                1 => enums::ReadVersion::Legacy,
+                _ => enums::ReadVersion::WAL,
+            };
+            let write_version = match write_version {
+                1 => enums::WriteVersion::Legacy,
+                _ => enums::WriteVersion::WAL,
+            };
+            let schema_format = match schema_format {
+                1 => enums::SchemaFormat::Format1,
+                2 => enums::SchemaFormat::Format2,
+                3 => enums::SchemaFormat::Format3,
+                _ => enums::SchemaFormat::Format4,
+            };
+            let text_encoding = match text_encoding {
+                0 => enums::TextEncoding::Unset,
+                1 => enums::TextEncoding::UTF8,
+                2 => enums::TextEncoding::UTF16le,
+                _ => enums::TextEncoding::UTF16be,
+            };
+            Header {
+                page_size: 1 << page_size,
+                read_version,
+                write_version,
+                reserved_space,
+                max_embedded_payload_fraction: fractions.0,
+                min_embedded_payload_fraction: fractions.1,
+                leaf_payload_fraction: fractions.2,
+                file_change_counter,
+                database_size,
+                freelist_trunk_page,
+                freelist_count,
+                schema_cookie,
+                schema_format,
+                default_page_cache_size,
+                largest_root_page,
+                text_encoding,
+                user_version,
+                incremental_vacuum_mode,
+                application_id,
+                reserved,
+                version_valid_for,
+                sqlite_version,
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn header_round_trip(header in arb_header()) {
+            let bytes = header.to_bytes();
+            let (rest, parsed) = super::header_parser(&bytes).unwrap();
+            prop_assert!(rest.is_empty());
+            prop_assert_eq!(header, parsed);
+            prop_assert_eq!(&bytes[..], &parsed.to_bytes()[..]);
+        }
+    }
+
+    #[test]
+    fn header_max_page_size() {
+        let mut bytes = [0u8; 100];
+        bytes[0..16].copy_from_slice(b"SQLite format 3\0");
+        bytes[16..24].copy_from_slice(&[0, 1, 1, 1, 0, 64, 32, 32]);
+        bytes[47] = 4;
+        bytes[59] = 1;
+        let (_, header) = super::header_parser(&bytes).unwrap();
+        assert_eq!(65536, header.page_size);
+        assert_eq!(&bytes[..], &header.to_bytes()[..]);
+    }
+
+    #[test]
+    fn header_of_empty_database() {
+        // A database switched to WAL before anything was written to it
+        let mut bytes = [0u8; 100];
+        bytes[0..16].copy_from_slice(b"SQLite format 3\0");
+        bytes[16..24].copy_from_slice(&[16, 0, 2, 2, 0, 64, 32, 32]);
+        let (_, header) = super::header_parser(&bytes).unwrap();
+        assert_eq!(enums::SchemaFormat::Empty, header.schema_format);
+        assert_eq!(enums::TextEncoding::Unset, header.text_encoding);
+        assert_eq!(&bytes[..], &header.to_bytes()[..]);
+    }
+}
@@ -0,0 +1,172 @@
+use nom::{IResult, Needed, be_u32};
+
+use data_structures::{Journal, JournalHeader, JournalRecord, JournalSegment};
+use parser::JOURNAL_MAGIC;
+
+const RECORD_COUNT_TO_END: u32 = 0xFFFF_FFFF;
+
+named!(pub journal_header_parser<JournalHeader>,
+    do_parse!(
+        tag!(JOURNAL_MAGIC) >>
+        record_count: be_u32 >>
+        nonce: be_u32 >>
+        initial_size: be_u32 >>
+        sector_size: be_u32 >>
+        page_size: be_u32 >>
+
+        (JournalHeader {
+            record_count,
+            nonce,
+            initial_size,
+            sector_size,
+            page_size,
+        })
+    )
+);
+
+pub fn journal_record_parser(i: &[u8], page_size: usize) -> IResult<&[u8], JournalRecord> {
+    do_parse!(i,
+        page_number: be_u32 >>
+        data: take!(page_size) >>
+        checksum: be_u32 >>
+
+        (JournalRecord {
+            page_number,
+            data: data.to_vec(),
+            checksum,
+        })
+    )
+}
+
+/*
+ * A journal consists of one or more segments, each starting with a header
+ * padded to the sector size. Parsing stops at the first offset that doesn't
+ * hold a valid header, like SQLite does during playback.
+ */
+pub fn journal_parser(i: &[u8]) -> IResult<&[u8], Journal> {
+    let mut segments = vec![];
+    let mut offset = 0;
+    while offset < i.len() {
+        let header = match journal_header_parser(&i[offset..]) {
+            IResult::Done(_, header) => header,
+            IResult::Error(e) => {
+                if segments.is_empty() {
+                    return IResult::Error(e)
+                }
+                break
+            },
+            IResult::Incomplete(n) => {
+                if segments.is_empty() {
+                    return IResult::Incomplete(n)
+                }
+                break
+            },
+        };
+        if header.sector_size == 0 || header.page_size == 0 {
+            if segments.is_empty() {
+                return IResult::Incomplete(Needed::Unknown)
+            }
+            break
+        }
+        let sector_size = header.sector_size as usize;
+        let record_size = header.page_size as usize + 8;
+        offset += sector_size;
+        let record_count = match header.record_count {
+            RECORD_COUNT_TO_END => i.len().saturating_sub(offset) / record_size,
+            x => x as usize,
+        };
+        let mut records = vec![];
+        for _ in 0..record_count {
+            // A journal cut short ends inside the header sector or a record
+            let rest = match i.get(offset..) {
+                Some(x) => x,
+                None => break,
+            };
+            match journal_record_parser(rest, header.page_size as usize) {
+                IResult::Done(_, record) => records.push(record),
+                _ => break,
+            }
+            offset += record_size;
+        }
+        segments.push(JournalSegment { header, records });
+        // The next header starts on a sector boundary
+        offset = offset.div_ceil(sector_size) * sector_size;
+    }
+    IResult::Done(&i[i.len().min(offset)..], Journal { segments })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_structures::{JournalHeader, SqliteFile};
+    use parser::JOURNAL_MAGIC;
+    use writer::DatabaseBuilder;
+    use Parser;
+
+    fn build_journal(nonce: u32, pages: &[(u32, u8)]) -> Vec<u8> {
+        let mut buffer = JOURNAL_MAGIC.to_vec();
+        for x in &[pages.len() as u32, nonce, 2, 512, 512] {
+            buffer.extend_from_slice(&[(x >> 24) as u8, (x >> 16) as u8, (x >> 8) as u8, *x as u8]);
+        }
+        buffer.resize(512, 0);
+        let header = JournalHeader {
+            record_count: 0,
+            nonce,
+            initial_size: 2,
+            sector_size: 512,
+            page_size: 512,
+        };
+        for &(page_number, fill) in pages {
+            let data = vec![fill; 512];
+            let checksum = header.checksum(&data);
+            buffer.extend_from_slice(&[0, 0, 0, page_number as u8]);
+            buffer.extend_from_slice(&data);
+            buffer.extend_from_slice(&[(checksum >> 24) as u8, (checksum >> 16) as u8,
+                (checksum >> 8) as u8, checksum as u8]);
+        }
+        buffer
+    }
+
+    #[test]
+    fn journal_segments() {
+        let buffer = build_journal(0x1234, &[(2, 0xAA), (1, 0x55)]);
+        let (_, journal) = super::journal_parser(&buffer).unwrap();
+        assert_eq!(1, journal.segments.len());
+        let segment = &journal.segments[0];
+        assert_eq!(0x1234, segment.header.nonce);
+        assert_eq!(2, segment.records.len());
+        assert_eq!(2, segment.records[0].page_number);
+        assert!(segment.records.iter().all(|r| r.is_valid(&segment.header)));
+        assert_eq!(2, journal.valid_records().len());
+    }
+
+    #[test]
+    fn journal_shorter_than_a_sector() {
+        let mut buffer = build_journal(7, &[(2, 0xAA)]);
+        // The sector size says the records start at 4096, but the file ends before
+        buffer[23] = 0x10;
+        buffer.truncate(512);
+        let (_, journal) = super::journal_parser(&buffer).unwrap();
+        assert_eq!(1, journal.segments.len());
+        assert!(journal.segments[0].records.is_empty());
+    }
+
+    #[test]
+    fn journal_record_for_page_zero() {
+        let mut builder = DatabaseBuilder::new().page_size(512);
+        builder.add_table("t", "CREATE TABLE t(x)", vec![]).unwrap();
+        let file = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+        let (_, journal) = super::journal_parser(&build_journal(7, &[(0, 0xAA)])).unwrap();
+        assert!(!journal.is_hot(&file));
+        assert!(journal.rollback(&file).is_err());
+    }
+
+    #[test]
+    fn journal_torn_record() {
+        let mut buffer = build_journal(7, &[(2, 0xAA), (1, 0x55)]);
+        let last = buffer.len() - 1;
+        buffer[last] ^= 0xFF;
+        let (_, journal) = super::journal_parser(&buffer).unwrap();
+        assert_eq!(1, journal.valid_records().len());
+    }
+}
@@ -1,15 +1,31 @@
+mod cell;
+mod changeset;
 mod generic;
 mod header;
+mod journal;
 mod page;
+mod record;
+mod sql;
+mod wal;
 
-use nom::{IResult, ErrorKind};
+use nom::IResult;
 
-use data_structures::{Header, BTreePageHeader};
+use enums::TextEncoding;
+use data_structures::{Header, BTreePageHeader, BTreePage, Changeset, Journal, Record, Wal};
+pub use parser::cell::{cell_size, local_payload_size, parse_cells};
+pub use parser::generic::{encode_varint, parse_varint};
+pub use parser::record::{decode_text, encode_text, serial_type_size};
+use parser::record::record_parser;
+pub use parser::sql::{parse_create_index, parse_create_table};
 pub use parser::header::header_parser;
+use parser::changeset::changeset_parser;
+use parser::journal::journal_parser;
 use parser::page::{btree_page_parser, btree_page_header_parser};
+use parser::wal::wal_parser;
 
-const HEADER_STRING: &'static str = "SQLite format 3\0";
-const PAGE_SIZE_MAX: u32 = 65536;
+pub const HEADER_STRING: &str = "SQLite format 3\0";
+pub const PAGE_SIZE_MAX: u32 = 65536;
+pub const JOURNAL_MAGIC: &[u8] = &[0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
 
 #[derive(Debug)]
 pub enum ParserError {
@@ -91,3 +107,43 @@ pub fn parse_btree_page_header(buffer: &[u8]) -> Result<BTreePageHeader, String>
     }
 }
 
+pub fn parse_btree_page(buffer: &[u8]) -> Result<BTreePage, String> {
+    match btree_page_parser(buffer) {
+        IResult::Done(_, Some(y)) => Ok(y),
+        IResult::Done(_, None) => Err("Not a B-tree page".to_string()),
+        IResult::Error(_) => Err("Error".to_string()),
+        IResult::Incomplete(_) => Err("Incomplete".to_string()),
+    }
+}
+
+pub fn parse_journal(buffer: &[u8]) -> Result<Journal, String> {
+    match journal_parser(buffer) {
+        IResult::Done(_, y) => Ok(y),
+        IResult::Error(_) => Err("File is not a rollback journal".to_string()),
+        IResult::Incomplete(_) => Err("Incomplete journal header".to_string()),
+    }
+}
+
+pub fn parse_wal(buffer: &[u8]) -> Result<Wal, String> {
+    match wal_parser(buffer) {
+        IResult::Done(_, y) => Ok(y),
+        IResult::Error(_) => Err("File is not a write-ahead log".to_string()),
+        IResult::Incomplete(_) => Err("Incomplete WAL header".to_string()),
+    }
+}
+
+pub fn parse_changeset(buffer: &[u8]) -> Result<Changeset, String> {
+    match changeset_parser(buffer) {
+        IResult::Done(_, y) => Ok(y),
+        IResult::Error(_) => Err("Not a changeset or patchset".to_string()),
+        IResult::Incomplete(_) => Err("Incomplete changeset".to_string()),
+    }
+}
+
+pub fn parse_record(buffer: &[u8], encoding: TextEncoding) -> Result<Record, String> {
+    match record_parser(buffer, encoding) {
+        IResult::Done(_, y) => Ok(y),
+        IResult::Error(_) => Err("Invalid record header".to_string()),
+        IResult::Incomplete(_) => Err("Incomplete record".to_string()),
+    }
+}
@@ -6,10 +6,10 @@ use data_structures::{BTreePageHeader, BTreePageType, BTreePage};
 const CONTENT_OFFSET_MAX: u32 = 65536;
 
 pub fn btree_page_header_parser(i: &[u8]) -> IResult<&[u8], BTreePageHeader> {
-    chain!(i,
-        page_type: btree_page_type_parser ~
-        freeblock_offset: freeblock_offset_parser ~
-        cell_count: be_u16 ~
+    do_parse!(i,
+        page_type: btree_page_type_parser >>
+        freeblock_offset: freeblock_offset_parser >>
+        cell_count: be_u16 >>
         cell_content_offset: map_res!(
             be_u16,
             |x: u16| -> Result<u32, ParserError> {
@@ -18,38 +18,37 @@ pub fn btree_page_header_parser(i: &[u8]) -> IResult<&[u8], BTreePageHeader> {
                     x => Ok(x as u32),
                 }
             }
-        ) ~
-        fragmented_free_byte_count: be_u8 ~
-        right_most_pointer: apply!(parse_right_most_pointer, &page_type),
+        ) >>
+        fragmented_free_byte_count: be_u8 >>
+        right_most_pointer: apply!(parse_right_most_pointer, &page_type) >>
 
-        || BTreePageHeader {
-            page_type: page_type,
-            freeblock_offset: freeblock_offset,
-            cell_count: cell_count,
-            cell_content_offset: cell_content_offset,
-            fragmented_free_byte_count: fragmented_free_byte_count,
-            right_most_pointer: right_most_pointer,
-        }
+        (BTreePageHeader {
+            page_type,
+            freeblock_offset,
+            cell_count,
+            cell_content_offset,
+            fragmented_free_byte_count,
+            right_most_pointer,
+        })
     )
 }
 
 pub fn btree_page_parser(i: &[u8]) -> IResult<&[u8], Option<BTreePage>> {
-    alt!(i, chain!(
-            tag!("\0\0"),
-            || None) |
-        chain!(
-            opt!(ignore_file_header) ~
-            header: btree_page_header_parser ~
-            cell_pointer: count!(be_u16, header.cell_count as usize),
-        || {
-                // let mut cell_pointer = cell_pointer.clone();
-                // cell_pointer.sort();
-                Some(BTreePage {
-                header: header,
-                cell_pointer: cell_pointer,
-                })
-        }
-    ))
+    alt!(i,
+        do_parse!(
+            tag!("\0\0") >>
+            (None)
+        ) |
+        do_parse!(
+            opt!(ignore_file_header) >>
+            header: btree_page_header_parser >>
+            cell_pointer: count!(be_u16, header.cell_count as usize) >>
+            (Some(BTreePage {
+                header,
+                cell_pointer,
+            }))
+        )
+    )
 }
 
 named!(ignore_file_header<()>,
@@ -0,0 +1,145 @@
+use nom::{IResult, ErrorKind, be_i8, be_i16, be_i32, be_i64, be_f64};
+
+use enums::TextEncoding;
+use data_structures::{Record, Value};
+use parser::generic::parse_varint;
+
+fn serial_types_parser(i: &[u8]) -> IResult<&[u8], Vec<u64>> {
+    let (rest, header_size) = match parse_varint(i) {
+        IResult::Done(rest, x) => (rest, x as usize),
+        IResult::Error(e) => return IResult::Error(e),
+        IResult::Incomplete(n) => return IResult::Incomplete(n),
+    };
+    let varint_size = i.len() - rest.len();
+    if header_size < varint_size || header_size > i.len() {
+        return IResult::Error(ErrorKind::Custom(0))
+    }
+    let mut header = &i[varint_size..header_size];
+    let mut serial_types = vec![];
+    while !header.is_empty() {
+        match parse_varint(header) {
+            IResult::Done(r, x) => {
+                serial_types.push(x);
+                header = r;
+            },
+            _ => return IResult::Error(ErrorKind::Custom(1)),
+        }
+    }
+    IResult::Done(&i[header_size..], serial_types)
+}
+
+pub fn decode_text(bytes: &[u8], encoding: TextEncoding) -> String {
+    match encoding {
+        TextEncoding::Unset | TextEncoding::UTF8 => String::from_utf8_lossy(bytes).into_owned(),
+        TextEncoding::UTF16le | TextEncoding::UTF16be => {
+            let units: Vec<u16> = bytes.chunks(2)
+                .filter(|c| c.len() == 2)
+                .map(|c| match encoding {
+                    TextEncoding::UTF16le => u16::from_le_bytes([c[0], c[1]]),
+                    _ => u16::from_be_bytes([c[0], c[1]]),
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        },
+    }
+}
+
+pub fn encode_text(text: &str, encoding: TextEncoding) -> Vec<u8> {
+    match encoding {
+        TextEncoding::Unset | TextEncoding::UTF8 => text.as_bytes().to_vec(),
+        TextEncoding::UTF16le => text.encode_utf16().flat_map(|u| u.to_le_bytes().to_vec()).collect(),
+        TextEncoding::UTF16be => text.encode_utf16().flat_map(|u| u.to_be_bytes().to_vec()).collect(),
+    }
+}
+
+pub fn serial_type_size(serial_type: u64) -> usize {
+    match serial_type {
+        0 | 8 | 9 | 10 | 11 => 0,
+        1 => 1,
+        2 => 2,
+        3 => 3,
+        4 => 4,
+        5 => 6,
+        6 | 7 => 8,
+        x => ((x - 12) / 2) as usize,
+    }
+}
+
+fn be_int(bytes: &[u8]) -> i64 {
+    // Sign extend from the first byte
+    let mut value = if bytes[0] & 0x80 != 0 { -1i64 } else { 0 };
+    for &b in bytes {
+        value = (value << 8) | b as i64;
+    }
+    value
+}
+
+pub fn value_parser(i: &[u8], serial_type: u64, encoding: TextEncoding) -> IResult<&[u8], Value> {
+    match serial_type {
+        0 => IResult::Done(i, Value::Null),
+        1 => map!(i, be_i8, |x| Value::Integer(x as i64)),
+        2 => map!(i, be_i16, |x| Value::Integer(x as i64)),
+        3 => map!(i, take!(3), |x| Value::Integer(be_int(x))),
+        4 => map!(i, be_i32, |x| Value::Integer(x as i64)),
+        5 => map!(i, take!(6), |x| Value::Integer(be_int(x))),
+        6 => map!(i, be_i64, Value::Integer),
+        7 => map!(i, be_f64, Value::Real),
+        8 => IResult::Done(i, Value::Integer(0)),
+        9 => IResult::Done(i, Value::Integer(1)),
+        10 | 11 => IResult::Error(ErrorKind::Custom(serial_type as u32)),
+        x if x % 2 == 0 => map!(i, take!(serial_type_size(x)), |b: &[u8]| Value::Blob(b.to_vec())),
+        x => map!(i, take!(serial_type_size(x)), |b| Value::Text(decode_text(b, encoding))),
+    }
+}
+
+pub fn record_parser(i: &[u8], encoding: TextEncoding) -> IResult<&[u8], Record> {
+    let (mut rest, serial_types) = match serial_types_parser(i) {
+        IResult::Done(rest, x) => (rest, x),
+        IResult::Error(e) => return IResult::Error(e),
+        IResult::Incomplete(n) => return IResult::Incomplete(n),
+    };
+    let mut values = vec![];
+    for &serial_type in &serial_types {
+        match value_parser(rest, serial_type, encoding) {
+            IResult::Done(r, value) => {
+                values.push(value);
+                rest = r;
+            },
+            IResult::Error(e) => return IResult::Error(e),
+            IResult::Incomplete(n) => return IResult::Incomplete(n),
+        }
+    }
+    IResult::Done(rest, Record { serial_types, values })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use enums::TextEncoding;
+    use data_structures::Value;
+
+    #[test]
+    fn record_values() {
+        // header: size 7, NULL, i8, i24, text(3), blob(1), 1
+        let buffer = [0x07, 0x00, 0x01, 0x03, 0x13, 0x0e, 0x09,
+            0xff, 0x80, 0x00, 0x01, b'a', b'b', b'c', 0x42];
+        let (rest, record) = super::record_parser(&buffer, TextEncoding::UTF8).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(vec![0, 1, 3, 19, 14, 9], record.serial_types);
+        assert_eq!(vec![
+            Value::Null,
+            Value::Integer(-1),
+            Value::Integer(-8_388_607),
+            Value::Text("abc".to_string()),
+            Value::Blob(vec![0x42]),
+            Value::Integer(1),
+        ], record.values);
+    }
+
+    #[test]
+    fn record_utf16_text() {
+        let buffer = [0x02, 0x15, b'h', 0, b'i', 0];
+        let (_, record) = super::record_parser(&buffer, TextEncoding::UTF16le).unwrap();
+        assert_eq!(vec![Value::Text("hi".to_string())], record.values);
+    }
+}
@@ -0,0 +1,405 @@
+use std::ops::Range;
+
+use data_structures::{ColumnDefinition, IndexDefinition, IndexedColumn, KeyConstraint, TableDefinition,
+                      Value};
+
+/*
+ * Just enough of SQLite's tokenizer to read the CREATE statements stored in
+ * sqlite_schema. Numbers and keywords are words, operators single symbols.
+ */
+#[derive(Debug,Clone,PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Literal(String),
+    Symbol(char),
+}
+
+// Words that end the type name of a column definition
+const COLUMN_CONSTRAINTS: &[&str] = &["CONSTRAINT", "PRIMARY", "NOT", "NULL", "UNIQUE", "CHECK",
+                                      "DEFAULT", "COLLATE", "REFERENCES", "GENERATED", "AS"];
+
+// The tokens and where each of them is in the SQL, in bytes
+type Tokens = (Vec<Token>, Vec<Range<usize>>);
+
+fn tokenize(sql: &str) -> Result<Tokens, String> {
+    let chars: Vec<char> = sql.chars().collect();
+    let offsets: Vec<usize> = sql.char_indices().map(|x| x.0).chain(Some(sql.len())).collect();
+    let mut tokens = vec![];
+    let mut spans = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let (token_start, count) = (i, tokens.len());
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '-' && chars.get(i + 1) == Some(&'-') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i += 2;
+        } else if c == '\'' || c == '"' || c == '`' || c == '[' {
+            let end = if c == '[' { ']' } else { c };
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(format!("Unterminated {} in SQL", c)),
+                    // Quotes are escaped by doubling them
+                    Some(&x) if x == end && end != ']' && chars.get(i + 1) == Some(&end) => {
+                        text.push(end);
+                        i += 2;
+                    },
+                    Some(&x) if x == end => {
+                        i += 1;
+                        break
+                    },
+                    Some(&x) => {
+                        text.push(x);
+                        i += 1;
+                    },
+                }
+            }
+            tokens.push(if c == '\'' { Token::Literal(text) } else { Token::Quoted(text) });
+        } else if c.is_alphanumeric() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$'
+                                      || (chars[i] == '.' && chars[start].is_ascii_digit())) {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        } else {
+            tokens.push(Token::Symbol(c));
+            i += 1;
+        }
+        if tokens.len() > count {
+            spans.push(offsets[token_start]..offsets[i.min(chars.len())]);
+        }
+    }
+    Ok((tokens, spans))
+}
+
+fn is_keyword(token: Option<&Token>, keyword: &str) -> bool {
+    matches!(token, Some(Token::Word(x)) if x.eq_ignore_ascii_case(keyword))
+}
+
+fn name(token: Option<&Token>) -> Option<String> {
+    match token {
+        Some(Token::Word(x)) | Some(Token::Quoted(x)) => Some(x.clone()),
+        // SQLite accepts string literals where it expects a name
+        Some(Token::Literal(x)) => Some(x.clone()),
+        _ => None,
+    }
+}
+
+/*
+ * Index of the parenthesis closing the one at start
+ */
+fn closing_paren(tokens: &[Token], start: usize) -> Result<usize, String> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        match *token {
+            Token::Symbol('(') => depth += 1,
+            Token::Symbol(')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(i)
+                }
+            },
+            _ => (),
+        }
+    }
+    Err("Unbalanced parentheses in SQL".to_string())
+}
+
+/*
+ * The comma separated items of a parenthesized list, without the commas
+ * nested in expressions
+ */
+fn split_list(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut items = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match *token {
+            Token::Symbol('(') => depth += 1,
+            Token::Symbol(')') => depth -= 1,
+            Token::Symbol(',') if depth == 0 => {
+                items.push(&tokens[start..i]);
+                start = i + 1;
+            },
+            _ => (),
+        }
+    }
+    items.push(&tokens[start..]);
+    items
+}
+
+// Tokens before, inside and after a parenthesized list
+type Split<'a> = (&'a [Token], &'a [Token], &'a [Token]);
+
+fn first_list(tokens: &[Token]) -> Result<Split<'_>, String> {
+    let open = tokens.iter()
+        .position(|x| *x == Token::Symbol('('))
+        .ok_or("Missing column list in SQL")?;
+    let close = closing_paren(tokens, open)?;
+    Ok((&tokens[..open], &tokens[open + 1..close], &tokens[close + 1..]))
+}
+
+fn indexed_columns(tokens: &[Token]) -> Vec<IndexedColumn> {
+    split_list(tokens).into_iter().map(|mut item| {
+        let mut descending = false;
+        if is_keyword(item.last(), "ASC") || is_keyword(item.last(), "DESC") {
+            descending = is_keyword(item.last(), "DESC");
+            item = &item[..item.len() - 1];
+        }
+        let mut collation = None;
+        if item.len() >= 2 && is_keyword(item.get(item.len() - 2), "COLLATE") {
+            collation = name(item.last());
+            item = &item[..item.len() - 2];
+        }
+        IndexedColumn {
+            name: if item.len() == 1 { name(item.first()) } else { None },
+            collation,
+            descending,
+        }
+    }).collect()
+}
+
+/*
+ * The value of a DEFAULT that is a literal, a signed number or one of those
+ * in parentheses. Expressions can't be evaluated here.
+ */
+fn constant(tokens: &[Token]) -> Option<Value> {
+    let number = |x: &str| x.parse::<i64>().map(Value::Integer).ok()
+        .or_else(|| x.parse::<f64>().ok().filter(|_| x.starts_with(|c: char| c.is_ascii_digit() || c == '.'))
+                 .map(Value::Real));
+    match tokens {
+        [Token::Symbol('('), inner @ .., Token::Symbol(')')] => constant(inner),
+        [Token::Literal(x)] => Some(Value::Text(x.clone())),
+        [Token::Word(x), Token::Literal(hex)] if x.eq_ignore_ascii_case("x") && hex.len() % 2 == 0 => {
+            (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>().map(Value::Blob)
+        },
+        [Token::Word(x)] if x.eq_ignore_ascii_case("NULL") => Some(Value::Null),
+        [Token::Word(x)] if x.eq_ignore_ascii_case("TRUE") => Some(Value::Integer(1)),
+        [Token::Word(x)] if x.eq_ignore_ascii_case("FALSE") => Some(Value::Integer(0)),
+        [Token::Word(x)] => number(x),
+        [Token::Symbol('+'), Token::Word(x)] => number(x),
+        [Token::Symbol('-'), Token::Word(x)] => match number(x)? {
+            Value::Integer(x) => Some(Value::Integer(x.wrapping_neg())),
+            Value::Real(x) => Some(Value::Real(-x)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn column_definition(item: &[Token], sql: &str, constraints: &mut Vec<KeyConstraint>)
+                     -> Result<ColumnDefinition, String> {
+    let column = name(item.first()).ok_or("Missing column name in SQL")?;
+    let mut declared_type = vec![];
+    let mut collation = None;
+    let mut default = None;
+    let mut not_null = false;
+    let mut generated = false;
+    let mut in_type = true;
+    let mut i = 1;
+    while i < item.len() {
+        if is_keyword(item.get(i), "DEFAULT") {
+            // The value runs up to the next constraint
+            let end = match item.get(i + 1) {
+                Some(Token::Symbol('(')) => closing_paren(item, i + 1)? + 1,
+                _ => (i + 1..item.len())
+                    .find(|&j| j > i + 1 && COLUMN_CONSTRAINTS.iter().any(|x| is_keyword(item.get(j), x)))
+                    .unwrap_or(item.len()),
+            };
+            default = constant(&item[i + 1..end]);
+            in_type = false;
+            i = end;
+            continue
+        }
+        if item[i] == Token::Symbol('(') {
+            let close = closing_paren(item, i)?;
+            if in_type {
+                declared_type.push("(".to_string());
+            }
+            i = close + 1;
+            continue
+        }
+        if COLUMN_CONSTRAINTS.iter().any(|x| is_keyword(item.get(i), x)) {
+            in_type = false;
+        }
+        if in_type {
+            declared_type.extend(name(item.get(i)));
+        } else if is_keyword(item.get(i), "COLLATE") {
+            collation = name(item.get(i + 1));
+            i += 1;
+        } else if is_keyword(item.get(i), "NOT") && is_keyword(item.get(i + 1), "NULL") {
+            not_null = true;
+            i += 1;
+        } else if is_keyword(item.get(i), "AS") {
+            generated = true;
+        } else if is_keyword(item.get(i), "PRIMARY") && is_keyword(item.get(i + 1), "KEY") {
+            constraints.push(KeyConstraint {
+                primary_key: true,
+                column_level: true,
+                columns: vec![IndexedColumn {
+                    name: Some(column.clone()),
+                    collation: None,
+                    descending: is_keyword(item.get(i + 2), "DESC"),
+                }],
+            });
+            i += 1;
+        } else if is_keyword(item.get(i), "UNIQUE") {
+            constraints.push(KeyConstraint {
+                primary_key: false,
+                column_level: true,
+                columns: vec![IndexedColumn {
+                    name: Some(column.clone()),
+                    collation: None,
+                    descending: false,
+                }],
+            });
+        }
+        i += 1;
+    }
+    Ok(ColumnDefinition {
+        name: column,
+        // Only the words count, VARCHAR(10) is a VARCHAR
+        declared_type: declared_type.into_iter().take_while(|x| x != "(").collect::<Vec<_>>().join(" "),
+        collation,
+        default,
+        not_null,
+        generated,
+        sql: sql.to_string(),
+    })
+}
+
+pub fn parse_create_table(sql: &str) -> Result<TableDefinition, String> {
+    let (tokens, spans) = tokenize(sql)?;
+    let (head, body, tail) = first_list(&tokens)?;
+    let table = name(head.iter().rev().find(|x| **x != Token::Symbol('.')))
+        .ok_or("Missing table name in SQL")?;
+    let mut definition = TableDefinition {
+        name: table,
+        columns: vec![],
+        constraints: vec![],
+        without_rowid: tail.windows(2)
+            .any(|x| is_keyword(x.first(), "WITHOUT") && is_keyword(x.get(1), "ROWID")),
+        strict: tail.iter().any(|x| is_keyword(Some(x), "STRICT")),
+    };
+    // CREATE TABLE ... AS SELECT has no constraints and BINARY columns
+    if head.iter().any(|x| is_keyword(Some(x), "AS")) {
+        return Ok(definition)
+    }
+    // Where the current item starts in tokens
+    let mut start = head.len() + 1;
+    for mut item in split_list(body) {
+        let text = match item.len() {
+            0 => "",
+            n => &sql[spans[start].start..spans[start + n - 1].end],
+        };
+        start += item.len() + 1;
+        if is_keyword(item.first(), "CONSTRAINT") {
+            item = &item[2.min(item.len())..];
+        }
+        let primary_key = is_keyword(item.first(), "PRIMARY");
+        if primary_key || is_keyword(item.first(), "UNIQUE") {
+            let (_, columns, _) = first_list(item)?;
+            definition.constraints.push(KeyConstraint {
+                primary_key,
+                column_level: false,
+                columns: indexed_columns(columns),
+            });
+        } else if !is_keyword(item.first(), "CHECK") && !is_keyword(item.first(), "FOREIGN") {
+            let column = column_definition(item, text, &mut definition.constraints)?;
+            definition.columns.push(column);
+        }
+    }
+    Ok(definition)
+}
+
+pub fn parse_create_index(sql: &str) -> Result<IndexDefinition, String> {
+    let (tokens, _) = tokenize(sql)?;
+    let (head, body, tail) = first_list(&tokens)?;
+    let on = head.iter()
+        .position(|x| is_keyword(Some(x), "ON"))
+        .ok_or("Missing ON in CREATE INDEX")?;
+    Ok(IndexDefinition {
+        name: name(head[..on].iter().rev().find(|x| **x != Token::Symbol('.')))
+            .ok_or("Missing index name in SQL")?,
+        table: name(head.last()).ok_or("Missing table name in SQL")?,
+        columns: indexed_columns(body),
+        partial: tail.iter().any(|x| is_keyword(Some(x), "WHERE")),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_structures::{IndexedColumn, Value};
+
+    fn column(name: Option<&str>, collation: Option<&str>, descending: bool) -> IndexedColumn {
+        IndexedColumn {
+            name: name.map(|x| x.to_string()),
+            collation: collation.map(|x| x.to_string()),
+            descending,
+        }
+    }
+
+    #[test]
+    fn create_table() {
+        let table = super::parse_create_table(
+            "CREATE TABLE \"my table\"(id INTEGER PRIMARY KEY, -- the rowid\n\
+             name VARCHAR(20) NOT NULL COLLATE nocase UNIQUE, \
+             [x y] DEFAULT (1 + 2) CHECK(x > 0), \
+             CONSTRAINT pk UNIQUE (name, `x y` COLLATE rtrim DESC)) WITHOUT ROWID, STRICT").unwrap();
+        assert_eq!("my table", table.name);
+        assert!(table.without_rowid && table.strict);
+        let columns: Vec<_> = table.columns.iter()
+            .map(|x| (x.name.as_str(), x.declared_type.as_str(), x.collation.as_deref()))
+            .collect();
+        assert_eq!(vec![("id", "INTEGER", None), ("name", "VARCHAR", Some("nocase")), ("x y", "", None)],
+                   columns);
+        assert_eq!(None, table.columns[2].default);
+        assert_eq!("name VARCHAR(20) NOT NULL COLLATE nocase UNIQUE", table.columns[1].sql);
+        assert!(table.columns[1].not_null && !table.columns[2].not_null);
+        assert!(table.columns.iter().all(|x| !x.generated));
+        assert_eq!(3, table.constraints.len());
+        assert!(table.constraints[0].primary_key && table.constraints[0].column_level);
+        assert_eq!(vec![column(Some("name"), None, false), column(Some("x y"), Some("rtrim"), true)],
+                   table.constraints[2].columns);
+
+        let table = super::parse_create_table(
+            "CREATE TABLE t(a DEFAULT 5 NOT NULL, b TEXT DEFAULT 'it''s', c DEFAULT (-1.5), \
+             d DEFAULT x'00ff', e DEFAULT NULL, f DEFAULT CURRENT_TIME)").unwrap();
+        let defaults: Vec<_> = table.columns.iter().map(|x| x.default.clone()).collect();
+        assert_eq!(vec![Some(Value::Integer(5)), Some(Value::Text("it's".to_string())),
+                        Some(Value::Real(-1.5)), Some(Value::Blob(vec![0, 255])), Some(Value::Null), None],
+                   defaults);
+
+        let table = super::parse_create_table(
+            "CREATE TABLE t(a INT, b INT GENERATED ALWAYS AS (a * 2) STORED, c AS (a + 1))").unwrap();
+        assert_eq!(vec![false, true, true], table.columns.iter().map(|x| x.generated).collect::<Vec<_>>());
+        assert!(!table.strict);
+    }
+
+    #[test]
+    fn create_index() {
+        let index = super::parse_create_index(
+            "CREATE UNIQUE INDEX IF NOT EXISTS main.i ON t(a, lower(b) DESC, c COLLATE NOCASE) \
+             WHERE a > 0").unwrap();
+        assert_eq!("i", index.name);
+        assert_eq!("t", index.table);
+        assert_eq!(vec![column(Some("a"), None, false), column(None, None, true),
+                        column(Some("c"), Some("NOCASE"), false)],
+                   index.columns);
+        assert!(index.partial);
+    }
+}
@@ -0,0 +1,119 @@
+use nom::{IResult, be_u32};
+
+use data_structures::{Wal, WalFrame, WalHeader};
+
+named!(pub wal_header_parser<WalHeader>,
+    do_parse!(
+        magic: verify!(be_u32, |x: u32| x == 0x377f_0682 || x == 0x377f_0683) >>
+        format_version: be_u32 >>
+        page_size: be_u32 >>
+        checkpoint_sequence: be_u32 >>
+        salt1: be_u32 >>
+        salt2: be_u32 >>
+        checksum1: be_u32 >>
+        checksum2: be_u32 >>
+
+        (WalHeader {
+            magic,
+            format_version,
+            page_size,
+            checkpoint_sequence,
+            salt: (salt1, salt2),
+            checksum: (checksum1, checksum2),
+        })
+    )
+);
+
+pub fn wal_frame_parser(i: &[u8], page_size: usize) -> IResult<&[u8], WalFrame> {
+    do_parse!(i,
+        page_number: be_u32 >>
+        commit_size: be_u32 >>
+        salt1: be_u32 >>
+        salt2: be_u32 >>
+        checksum1: be_u32 >>
+        checksum2: be_u32 >>
+        data: take!(page_size) >>
+
+        (WalFrame {
+            page_number,
+            commit_size,
+            salt: (salt1, salt2),
+            checksum: (checksum1, checksum2),
+            data: data.to_vec(),
+        })
+    )
+}
+
+/*
+ * All complete frames are returned, including invalid ones, so old page
+ * versions from before a WAL restart can be inspected.
+ */
+pub fn wal_parser(i: &[u8]) -> IResult<&[u8], Wal> {
+    let (mut rest, header) = match wal_header_parser(i) {
+        IResult::Done(rest, header) => (rest, header),
+        IResult::Error(e) => return IResult::Error(e),
+        IResult::Incomplete(n) => return IResult::Incomplete(n),
+    };
+    let mut frames = vec![];
+    while let IResult::Done(r, frame) = wal_frame_parser(rest, header.page_size as usize) {
+        frames.push(frame);
+        rest = r;
+    }
+    IResult::Done(rest, Wal { header, frames })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use checksum::wal_checksum;
+    use data_structures::WalHeader;
+
+    fn build_wal(frames: &[(u32, u32)]) -> Vec<u8> {
+        let mut header = WalHeader {
+            magic: 0x377f_0682,
+            format_version: 3_007_000,
+            page_size: 512,
+            checkpoint_sequence: 0,
+            salt: (0x1111, 0x2222),
+            checksum: (0, 0),
+        };
+        header.checksum = wal_checksum(false, &header.to_bytes()[..24], (0, 0));
+        let mut buffer = header.to_bytes().to_vec();
+        let mut checksum = header.checksum;
+        for &(page_number, commit_size) in frames {
+            let mut frame = vec![];
+            for x in &[page_number, commit_size, header.salt.0, header.salt.1] {
+                frame.extend_from_slice(&x.to_be_bytes());
+            }
+            let data = vec![page_number as u8; 512];
+            checksum = wal_checksum(false, &frame[..8], checksum);
+            checksum = wal_checksum(false, &data, checksum);
+            frame.extend_from_slice(&checksum.0.to_be_bytes());
+            frame.extend_from_slice(&checksum.1.to_be_bytes());
+            frame.extend_from_slice(&data);
+            buffer.extend_from_slice(&frame);
+        }
+        buffer
+    }
+
+    #[test]
+    fn wal_frames() {
+        let buffer = build_wal(&[(1, 0), (2, 2), (3, 0)]);
+        let (rest, wal) = super::wal_parser(&buffer).unwrap();
+        assert!(rest.is_empty());
+        assert!(wal.header.is_valid());
+        assert_eq!(3, wal.frames.len());
+        assert_eq!(3, wal.valid_frame_count());
+        assert_eq!(2, wal.committed_frames().len());
+    }
+
+    #[test]
+    fn wal_broken_chain() {
+        let mut buffer = build_wal(&[(1, 1), (2, 2), (3, 3)]);
+        // Corrupt the data of the second frame
+        buffer[32 + 536 + 100] ^= 1;
+        let (_, wal) = super::wal_parser(&buffer).unwrap();
+        assert_eq!(1, wal.valid_frame_count());
+        assert_eq!(1, wal.committed_frames().len());
+    }
+}
@@ -0,0 +1,831 @@
+/*
+ * Recovering rows that were deleted but not yet overwritten. A deleted cell
+ * stays on its page until the space is reused: inside a freeblock, where the
+ * freeblock header has replaced its first four bytes, or in the unallocated
+ * space in front of the cell content area. Candidate records are decoded at
+ * every offset of that free space and kept if they look like a row of one of
+ * the tables in the schema. Pages on the freelist are often whole leaf pages
+ * of deleted data, their cells are attributed by record shape alone.
+ */
+extern crate std;
+
+use std::cmp::Ordering;
+
+use nom::IResult;
+
+use data_structures::{BTreePageType, Record, SchemaEntry, SqliteFile, Value};
+use enums::{Affinity, TextEncoding};
+use parser::{encode_varint, parse_btree_page, parse_cells, parse_create_table, parse_record, parse_varint,
+             serial_type_size};
+
+// Below this, a recovered row is more likely noise than deleted data
+pub const MIN_CONFIDENCE: f64 = 0.5;
+
+// How much of the cell structure survived
+const INTACT_CELL: f64 = 1.0;
+const BARE_RECORD: f64 = 0.8;
+// Cells in unallocated space are intact, a record without one is mostly noise
+const STRAY_RECORD: f64 = 0.45;
+const LOST_HEADER_SIZE: f64 = 0.8;
+const LOST_HEADER_SIZE_SHORT: f64 = 0.6;
+const LOST_FIRST_COLUMN: f64 = 0.7;
+// How many of the following freeblock headers may end a cell
+const MERGED_CELLS: usize = 8;
+// Pages get reused by other tables, but rarely
+const OTHER_TABLE: f64 = 0.7;
+// A free page may have belonged to a table that was dropped since, rows that
+// fit no table this well stay unattributed
+const MATCHING_SHAPE: f64 = 0.9;
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum RecoverySource {
+    Freeblock,
+    Unallocated,
+    FreePage,
+}
+
+impl std::fmt::Display for RecoverySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   RecoverySource::Freeblock => "freeblock",
+                   RecoverySource::Unallocated => "unallocated space",
+                   RecoverySource::FreePage => "freelist page",
+               })
+    }
+}
+
+/*
+ * A deleted row. The rowid is only known if the whole cell survived, and the
+ * first column is guessed when its serial type was overwritten.
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct RecoveredRow {
+    pub page: u32,
+    pub offset: usize,
+    pub source: RecoverySource,
+    pub table: Option<String>,
+    pub rowid: Option<i64>,
+    pub values: Vec<Value>,
+    pub guessed_columns: usize,
+    pub confidence: f64,
+}
+
+/*
+ * What the records of a rowid table look like
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct TableShape {
+    pub name: String,
+    pub root: u32,
+    pub affinities: Vec<Affinity>,
+    pub rowid_alias: Option<usize>,
+}
+
+impl TableShape {
+    /*
+     * Every rowid table of the schema, starting with sqlite_schema itself.
+     * Tables whose SQL can't be read are left out.
+     */
+    pub fn from_schema(file: &SqliteFile) -> Result<Vec<TableShape>, String> {
+        Ok(TableShape::from_entries(&file.schema()?))
+    }
+
+    pub fn from_entries(entries: &[SchemaEntry]) -> Vec<TableShape> {
+        let mut shapes = vec![TableShape {
+            name: "sqlite_schema".to_string(),
+            root: 1,
+            affinities: vec![Affinity::Text, Affinity::Text, Affinity::Text, Affinity::Integer,
+                             Affinity::Text],
+            rowid_alias: None,
+        }];
+        for entry in entries {
+            if entry.entry_type != "table" || entry.rootpage == 0 {
+                continue
+            }
+            let table = match entry.sql.as_ref().map(|x| parse_create_table(x)) {
+                Some(Ok(table)) => table,
+                _ => continue,
+            };
+            if table.without_rowid || table.columns.is_empty() {
+                continue
+            }
+            shapes.push(TableShape {
+                name: entry.name.clone(),
+                root: entry.rootpage,
+                affinities: table.columns.iter().map(|x| x.affinity()).collect(),
+                rowid_alias: table.rowid_alias(),
+            });
+        }
+        shapes
+    }
+
+    /*
+     * How well a record matches the table, from 0 to 1
+     */
+    pub fn fit(&self, values: &[Value]) -> f64 {
+        let mut fit = self.type_fit(values) * self.column_count_fit(values);
+        if values.iter().all(|x| *x == Value::Null) {
+            fit *= 0.5;
+        }
+        fit
+    }
+
+    /*
+     * How well the values match the column affinities, whatever their number
+     */
+    pub fn type_fit(&self, values: &[Value]) -> f64 {
+        if values.is_empty() || values.len() > self.affinities.len() {
+            return 0.0
+        }
+        let total: f64 = values.iter().enumerate().map(|(i, value)| match self.rowid_alias {
+            // The record holds NULL, the value is the rowid
+            Some(alias) if alias == i => if *value == Value::Null { 1.0 } else { 0.0 },
+            _ => column_fit(value, self.affinities[i]),
+        }).sum();
+        total / values.len() as f64
+    }
+
+    /*
+     * Rows from before ALTER TABLE ADD COLUMN are shorter, but never longer
+     */
+    pub fn column_count_fit(&self, values: &[Value]) -> f64 {
+        match self.affinities.len().checked_sub(values.len()) {
+            Some(missing) if !values.is_empty() => 0.8f64.powi(missing as i32),
+            _ => 0.0,
+        }
+    }
+}
+
+fn column_fit(value: &Value, affinity: Affinity) -> f64 {
+    match (value, affinity) {
+        (&Value::Null, _) => 0.9,
+        (_, Affinity::Blob) => 1.0,
+        // TEXT affinity stores numbers as text
+        (&Value::Integer(_), Affinity::Text) | (&Value::Real(_), Affinity::Text) => 0.3,
+        (&Value::Integer(_), _) => 1.0,
+        (&Value::Real(_), Affinity::Integer) => 0.6,
+        (&Value::Real(_), _) => 1.0,
+        (&Value::Text(_), Affinity::Text) => 1.0,
+        (&Value::Text(_), _) | (&Value::Blob(_), _) => 0.5,
+    }
+}
+
+/*
+ * A record decoded from free space, before it is matched against the schema
+ */
+#[derive(Debug,Clone)]
+pub struct Candidate {
+    pub offset: usize,
+    pub end: usize,
+    pub rowid: Option<i64>,
+    pub record: Record,
+    pub guessed_columns: usize,
+    pub structure: f64,
+    // Reconstructed records only make sense for the table they were built for
+    pub shape: Option<usize>,
+}
+
+fn varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    match parse_varint(bytes) {
+        IResult::Done(rest, x) => Some((x, bytes.len() - rest.len())),
+        _ => None,
+    }
+}
+
+fn record_size(record: &Record, header_size: usize) -> usize {
+    header_size + record.serial_types.iter().map(|&x| serial_type_size(x)).sum::<usize>()
+}
+
+/*
+ * A record starting at offset that ends within the region
+ */
+pub fn record_at(region: &[u8], offset: usize, encoding: TextEncoding) -> Option<Candidate> {
+    let bytes = &region[offset..];
+    let (header_size, _) = varint(bytes)?;
+    if header_size < 2 || header_size as usize > bytes.len() {
+        return None
+    }
+    let record = parse_record(bytes, encoding).ok()?;
+    Some(Candidate {
+        offset,
+        end: offset + record_size(&record, header_size as usize),
+        rowid: None,
+        record,
+        guessed_columns: 0,
+        structure: BARE_RECORD,
+        shape: None,
+    })
+}
+
+/*
+ * A complete table leaf cell whose payload size matches its record exactly
+ */
+pub fn table_cell_at(region: &[u8], offset: usize, encoding: TextEncoding, max_local: usize)
+                     -> Option<Candidate> {
+    let (payload_size, a) = varint(&region[offset..])?;
+    let (rowid, b) = varint(&region[offset + a..])?;
+    let start = offset + a + b;
+    let end = start.checked_add(payload_size as usize)?;
+    if payload_size as usize > max_local || end > region.len() {
+        return None
+    }
+    let record = record_at(&region[..end], start, encoding)?;
+    if record.end != end {
+        return None
+    }
+    Some(Candidate { offset, rowid: Some(rowid as i64), structure: INTACT_CELL, ..record })
+}
+
+/*
+ * Serial types for a value of the given size, the way a column of this
+ * affinity would most likely have stored it
+ */
+fn guess_serial_types(size: usize, affinity: Affinity, encoding: TextEncoding) -> Vec<u64> {
+    let text = size as u64 * 2 + 13;
+    let blob = size as u64 * 2 + 12;
+    let utf16 = encoding.effective() != TextEncoding::UTF8;
+    let mut types = match (size, affinity) {
+        (0, _) => vec![0],
+        (_, Affinity::Text) => vec![text],
+        (1..=4, _) => vec![size as u64, text],
+        (6, _) => vec![5, text],
+        (8, Affinity::Real) => vec![7, 6],
+        (8, _) => vec![6, 7, text],
+        (_, Affinity::Blob) => vec![text, blob],
+        _ => vec![text],
+    };
+    // UTF-16 text has an even number of bytes
+    types.retain(|&x| !(utf16 && x == text && size % 2 == 1));
+    types
+}
+
+/*
+ * The freeblock header overwrote the payload size, the rowid and, for small
+ * cells, the start of the record header. With one byte serial types this is
+ * either just the header size or the header size and the first serial type.
+ * A lost serial type is derived from the bytes left over at the end of the
+ * freeblock.
+ */
+pub fn reconstruct_record(region: &[u8], offset: usize, shape_index: usize, shape: &TableShape,
+                          encoding: TextEncoding) -> Vec<Candidate> {
+    let columns = shape.affinities.len();
+    let mut candidates = vec![];
+    for lost in 0..2.min(columns) {
+        let mut serial_types = vec![];
+        let mut position = offset + 4;
+        while serial_types.len() < columns - lost {
+            match region.get(position..).and_then(varint) {
+                Some((x, length)) if x != 10 && x != 11 => {
+                    serial_types.push(x);
+                    position += length;
+                },
+                _ => break,
+            }
+        }
+        if serial_types.len() != columns - lost {
+            continue
+        }
+        let known: usize = serial_types.iter().map(|&x| serial_type_size(x)).sum();
+        let guesses = match lost {
+            0 => vec![None],
+            _ => match region.len().checked_sub(position + known) {
+                Some(left) => guess_serial_types(left, shape.affinities[0], encoding).into_iter()
+                    .map(Some)
+                    .collect(),
+                None => continue,
+            },
+        };
+        for guess in guesses {
+            let mut types = serial_types.clone();
+            types.splice(0..0, guess);
+            let mut header: Vec<u8> = types.iter().flat_map(|&x| encode_varint(x)).collect();
+            header.splice(0..0, encode_varint(header.len() as u64 + 1));
+            let body_size: usize = types.iter().map(|&x| serial_type_size(x)).sum();
+            if header.len() > 127 || position + body_size > region.len() {
+                continue
+            }
+            header.extend_from_slice(&region[position..position + body_size]);
+            if let Ok(record) = parse_record(&header, encoding) {
+                candidates.push(Candidate {
+                    offset,
+                    end: position + body_size,
+                    rowid: None,
+                    record,
+                    guessed_columns: lost,
+                    structure: match lost {
+                        // A freeblock of a single cell ends where the record does
+                        0 if position + body_size == region.len() => LOST_HEADER_SIZE,
+                        0 => LOST_HEADER_SIZE_SHORT,
+                        _ => LOST_FIRST_COLUMN,
+                    },
+                    shape: Some(shape_index),
+                });
+            }
+        }
+    }
+    candidates
+}
+
+/*
+ * Text that doesn't decode cleanly, absurd reals and zeroed space are
+ * typical for bytes that were never a record. UTF-16 read one byte off
+ * turns ASCII into code points like U+6100.
+ */
+fn plausibility(record: &Record, encoding: TextEncoding) -> f64 {
+    let utf16 = encoding.effective() != TextEncoding::UTF8;
+    let garbage_text = |x: &str, size: usize| {
+        let shifted = x.chars().filter(|&c| c as u32 > 0xff && c as u32 & 0xff == 0).count();
+        x.chars().any(|c| c == '\u{fffd}' || (c.is_control() && !"\t\n\r".contains(c)))
+            || (utf16 && (size % 2 == 1 || shifted * 2 > x.chars().count()))
+    };
+    let garbage = record.values.iter().zip(&record.serial_types).any(|(value, &serial_type)| match *value {
+        Value::Text(ref x) => garbage_text(x, serial_type_size(serial_type)),
+        Value::Real(x) => !x.is_finite() || x.abs() > 1e300 || (x != 0.0 && x.abs() < 1e-300),
+        // Zeroed space with a few bytes of something else
+        Value::Blob(ref x) => x.len() >= 4 && x.iter().filter(|&&b| b == 0).count() * 2 > x.len(),
+        _ => false,
+    });
+    let zeroed = record.values.iter().all(|value| match *value {
+        Value::Null | Value::Integer(0) => true,
+        Value::Real(x) => x == 0.0,
+        Value::Text(ref x) => x.chars().all(|c| c == '\0'),
+        Value::Blob(ref x) => x.iter().all(|&b| b == 0),
+        _ => false,
+    });
+    if garbage || zeroed { 0.3 } else { 1.0 }
+}
+
+/*
+ * Match candidates against the tables and keep the most confident ones that
+ * don't overlap
+ */
+pub fn select_rows(candidates: Vec<Candidate>, shapes: &[TableShape], owner: Option<usize>, page: u32,
+                   source: RecoverySource, encoding: TextEncoding, min_confidence: f64) -> Vec<RecoveredRow> {
+    let mut scored: Vec<(f64, Option<usize>, Candidate)> = candidates.into_iter().map(|candidate| {
+        let (shape, fit) = shapes.iter().enumerate()
+            .filter(|&(i, _)| candidate.shape.is_none_or(|x| x == i))
+            .map(|(i, shape)| {
+                let prior = if owner.is_none_or(|x| x == i) { 1.0 } else { OTHER_TABLE };
+                (Some(i), shape.fit(&candidate.record.values) * prior)
+            })
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+            .unwrap_or((None, 0.0));
+        let confidence = candidate.structure * fit * plausibility(&candidate.record, encoding);
+        (confidence, shape, candidate)
+    }).collect();
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal).then(a.2.offset.cmp(&b.2.offset)));
+
+    let mut taken: Vec<(usize, usize)> = vec![];
+    let mut rows = vec![];
+    for (confidence, shape, candidate) in scored {
+        if confidence < min_confidence {
+            break
+        }
+        if taken.iter().any(|&(start, end)| candidate.offset < end && start < candidate.end) {
+            continue
+        }
+        taken.push((candidate.offset, candidate.end));
+        rows.push(RecoveredRow {
+            page,
+            offset: candidate.offset,
+            source,
+            table: shape.map(|x| shapes[x].name.clone()),
+            rowid: candidate.rowid,
+            values: candidate.record.values,
+            guessed_columns: candidate.guessed_columns,
+            confidence,
+        });
+    }
+    rows.sort_by_key(|x| x.offset);
+    rows
+}
+
+/*
+ * Deleted rows in the free space of one table page. Interior pages are
+ * included: a root page that split keeps the cells it had as a leaf.
+ */
+pub fn recover_page(file: &SqliteFile, page_id: u32, shapes: &[TableShape], owner: Option<usize>,
+                    min_confidence: f64) -> Result<Vec<RecoveredRow>, String> {
+    let page = file.page_bytes(page_id as usize)?;
+    let header = parse_btree_page(page)?.header;
+    if !header.page_type.is_table() {
+        return Ok(vec![])
+    }
+    let encoding = file.header.text_encoding;
+    let usable = &page[..file.header.usable_size() as usize];
+    let max_local = usable.len() - 35;
+    let scan = |region: &[u8], start: usize, in_freeblock: bool| -> Vec<Candidate> {
+        let bare_record = if in_freeblock { BARE_RECORD } else { STRAY_RECORD };
+        let mut candidates = vec![];
+        for offset in start..region.len() {
+            candidates.extend(table_cell_at(region, offset, encoding, max_local));
+            candidates.extend(record_at(region, offset, encoding)
+                              .map(|x| Candidate { structure: bare_record, ..x }));
+        }
+        if !in_freeblock {
+            return candidates
+        }
+        // Freeblock headers, the current one and those left over from freeblocks that were merged
+        // later. The later merges grew the block in front, so a cell ends where the next header
+        // starts, or where its own header says.
+        let headers: Vec<(usize, usize)> = (start..region.len().saturating_sub(3))
+            .map(|x| (x, u16::from_be_bytes([region[x + 2], region[x + 3]]) as usize))
+            .filter(|&(x, size)| size >= 4 && x + size <= region.len())
+            .collect();
+        for (i, &(offset, size)) in headers.iter().enumerate() {
+            let ends = headers[i + 1..].iter()
+                .map(|&(x, _)| x)
+                .filter(|&x| x >= offset + 4)
+                .take(MERGED_CELLS)
+                .chain(Some(offset + size));
+            for end in ends {
+                for (j, shape) in shapes.iter().enumerate() {
+                    candidates.extend(reconstruct_record(&region[..end], offset, j, shape, encoding));
+                }
+            }
+        }
+        candidates
+    };
+
+    let mut rows = vec![];
+    let pointer_end = SqliteFile::btree_header_offset(page_id as usize)
+        + header.page_type.header_size()
+        + 2 * header.cell_count as usize;
+    let content_offset = (header.cell_content_offset as usize).min(usable.len());
+    if pointer_end < content_offset {
+        let candidates = scan(&usable[..content_offset], pointer_end, false);
+        rows.extend(select_rows(candidates, shapes, owner, page_id, RecoverySource::Unallocated, encoding,
+                                min_confidence));
+    }
+
+    let mut next = header.freeblock_offset.map(|x| x as usize);
+    while let Some(offset) = next {
+        if offset < content_offset || offset + 4 > usable.len() {
+            break
+        }
+        let size = u16::from_be_bytes([usable[offset + 2], usable[offset + 3]]) as usize;
+        let region = &usable[..(offset + size).min(usable.len())];
+        let candidates = scan(region, offset, true);
+        rows.extend(select_rows(candidates, shapes, owner, page_id, RecoverySource::Freeblock, encoding,
+                                min_confidence));
+        let following = u16::from_be_bytes([usable[offset], usable[offset + 1]]) as usize;
+        next = if following > offset { Some(following) } else { None };
+    }
+    Ok(rows)
+}
+
+/*
+ * Deleted rows on the pages of every table, each attributed to the table
+ * whose columns it fits best
+ */
+pub fn recover_deleted(file: &SqliteFile, min_confidence: f64) -> Result<Vec<RecoveredRow>, String> {
+    let shapes = TableShape::from_schema(file)?;
+    let mut rows = vec![];
+    for (i, shape) in shapes.iter().enumerate() {
+        for page_id in file.btree_pages(shape.root)? {
+            rows.extend(recover_page(file, page_id, &shapes, Some(i), min_confidence)?);
+        }
+    }
+    Ok(without_live_rows(file, &shapes, rows))
+}
+
+/*
+ * Drop the rows that are still in their table with the same values. A root
+ * page that splits keeps the cells it moved to its children in its free
+ * space, and pages freed by a merge keep the cells that moved elsewhere.
+ */
+fn without_live_rows(file: &SqliteFile, shapes: &[TableShape], rows: Vec<RecoveredRow>) -> Vec<RecoveredRow> {
+    let live: Vec<Vec<(i64, Record)>> = shapes.iter()
+        .map(|x| file.table_rows(x.root).unwrap_or_default())
+        .collect();
+    rows.into_iter().filter(|row| {
+        let rowid = match row.rowid {
+            Some(x) => x,
+            None => return true,
+        };
+        !shapes.iter().zip(&live)
+            .filter(|&(shape, _)| row.table.as_ref().is_none_or(|x| *x == shape.name))
+            .any(|(_, rows)| match rows.binary_search_by_key(&rowid, |x| x.0) {
+                Ok(i) => rows[i].1.values == row.values,
+                Err(_) => false,
+            })
+    }).collect()
+}
+
+/*
+ * The table whose shape fits the intact cells of a page best, the page most
+ * likely belonged to it
+ */
+fn likely_owner(candidates: &[Candidate], shapes: &[TableShape]) -> Option<usize> {
+    let intact: Vec<&Candidate> = candidates.iter().filter(|x| x.structure == INTACT_CELL).collect();
+    shapes.iter().enumerate()
+        .map(|(i, shape)| (i, intact.iter().map(|x| shape.fit(&x.record.values)).sum::<f64>()))
+        .filter(|&(_, fit)| fit > 0.0)
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+        .map(|(i, _)| i)
+}
+
+/*
+ * Rows on a page of the freelist. A leaf page that was freed keeps its
+ * B-tree header and cell pointers, a trunk page only the end of its old
+ * content, so without a usable header every offset is tried.
+ */
+pub fn recover_free_page(file: &SqliteFile, page_id: u32, is_trunk: bool, shapes: &[TableShape],
+                         min_confidence: f64) -> Result<Vec<RecoveredRow>, String> {
+    let page = file.page_bytes(page_id as usize)?;
+    let encoding = file.header.text_encoding;
+    let usable = &page[..file.header.usable_size() as usize];
+    let max_local = usable.len() - 35;
+    let header = match is_trunk {
+        true => None,
+        false => parse_btree_page(page).ok().map(|x| x.header),
+    };
+    let header = header.filter(|x| x.page_type.header_size() + 2 * x.cell_count as usize <= usable.len());
+
+    let mut rows = match header {
+        // Index pages hold no rows
+        Some(ref header) if !header.page_type.is_table() => vec![],
+        Some(header) => {
+            let mut candidates = vec![];
+            if header.page_type.is_leaf() {
+                let pointers = &usable[header.page_type.header_size()..][..2 * header.cell_count as usize];
+                for pointer in pointers.chunks(2) {
+                    let offset = u16::from_be_bytes([pointer[0], pointer[1]]) as usize;
+                    if offset < usable.len() {
+                        candidates.extend(table_cell_at(usable, offset, encoding, max_local));
+                    }
+                }
+            }
+            let owner = likely_owner(&candidates, shapes);
+            let mut rows = select_rows(candidates, shapes, owner, page_id, RecoverySource::FreePage, encoding,
+                                       min_confidence);
+            rows.extend(recover_page(file, page_id, shapes, owner, min_confidence)?);
+            rows.sort_by_key(|x| x.offset);
+            rows
+        },
+        None => {
+            let start = if is_trunk {
+                (8 + 4 * u32::from_be_bytes([page[4], page[5], page[6], page[7]]) as usize).min(usable.len())
+            } else {
+                0
+            };
+            let mut candidates = vec![];
+            for offset in start..usable.len() {
+                candidates.extend(table_cell_at(usable, offset, encoding, max_local));
+                candidates.extend(record_at(usable, offset, encoding)
+                                  .map(|x| Candidate { structure: STRAY_RECORD, ..x }));
+            }
+            let owner = likely_owner(&candidates, shapes);
+            select_rows(candidates, shapes, owner, page_id, RecoverySource::FreePage, encoding,
+                        min_confidence)
+        },
+    };
+    for row in &mut rows {
+        let shape = shapes.iter().find(|x| row.table.as_ref() == Some(&x.name));
+        if shape.is_none_or(|x| x.fit(&row.values) < MATCHING_SHAPE) {
+            row.table = None;
+        }
+    }
+    Ok(rows)
+}
+
+/*
+ * Rows on every page of the freelist
+ */
+pub fn recover_freelist(file: &SqliteFile, min_confidence: f64) -> Result<Vec<RecoveredRow>, String> {
+    let shapes = TableShape::from_schema(file)?;
+    let mut rows = vec![];
+    for (page_id, is_trunk) in file.freelist_pages()? {
+        rows.extend(recover_free_page(file, page_id, is_trunk, &shapes, min_confidence)?);
+    }
+    Ok(without_live_rows(file, &shapes, rows))
+}
+
+/*
+ * How likely the rows of a page without parent belong to a table, with the
+ * evidence the score is made of. The rowids only count if the table has
+ * rows of its own to compare with.
+ */
+#[derive(Debug,Clone,PartialEq)]
+pub struct Attribution {
+    pub table: String,
+    pub score: f64,
+    pub column_count: f64,
+    pub serial_types: f64,
+    pub rowids: Option<f64>,
+}
+
+/*
+ * The rows of a table leaf page on its own. Payloads that continue on
+ * overflow pages can't be read.
+ */
+pub fn page_rows(page: &[u8], usable_size: u32, encoding: TextEncoding)
+                 -> Result<Vec<(i64, Record)>, String> {
+    let btree_page = parse_btree_page(page)?;
+    if btree_page.header.page_type != BTreePageType::LeafTablePage {
+        return Err("Not a table leaf page".to_string())
+    }
+    let mut rows = vec![];
+    for cell in parse_cells(page, &btree_page, usable_size)? {
+        if cell.overflow_page.is_none() {
+            if let Ok(record) = parse_record(&cell.payload, encoding) {
+                rows.push((cell.rowid.unwrap_or(0), record));
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/*
+ * Score every table for the rows of an orphan page, best first. The rows of
+ * each table, sorted by rowid, are in known_rows in the order of the shapes.
+ * A row the table has as well makes the page a copy of its rows if the
+ * values are the same, and is a coincidence or an older version otherwise.
+ * Rows the table doesn't have fit best into a gap within its range.
+ */
+pub fn attribute_rows(rows: &[(i64, Record)], shapes: &[TableShape], known_rows: &[Vec<(i64, Record)>])
+                      -> Vec<Attribution> {
+    if rows.is_empty() {
+        return vec![]
+    }
+    let count = rows.len() as f64;
+    let mut attributions: Vec<Attribution> = shapes.iter().enumerate().map(|(i, shape)| {
+        let column_count = rows.iter().map(|x| shape.column_count_fit(&x.1.values)).sum::<f64>() / count;
+        let serial_types = rows.iter().map(|x| shape.type_fit(&x.1.values)).sum::<f64>() / count;
+        let rowids = known_rows.get(i).filter(|x| !x.is_empty()).map(|known| {
+            let (min, max) = (known[0].0, known[known.len() - 1].0);
+            rows.iter().map(|&(rowid, ref record)| match known.binary_search_by_key(&rowid, |x| x.0) {
+                Ok(j) if known[j].1.values == record.values => 1.0,
+                Ok(_) => 0.5,
+                Err(_) if min <= rowid && rowid <= max => 1.0,
+                Err(_) => 0.8,
+            }).sum::<f64>() / count
+        });
+        Attribution {
+            table: shape.name.clone(),
+            score: column_count * serial_types * rowids.unwrap_or(1.0),
+            column_count,
+            serial_types,
+            rowids,
+        }
+    }).collect();
+    attributions.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    attributions
+}
+
+impl std::fmt::Display for Attribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {:.2} (column count {:.2}, serial types {:.2}",
+               self.table, self.score, self.column_count, self.serial_types)?;
+        if let Some(rowids) = self.rowids {
+            write!(f, ", rowids {:.2}", rowids)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl std::fmt::Display for RecoveredRow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let values: Vec<String> = self.values.iter().map(|x| x.to_string()).collect();
+        write!(f, "{} ", self.table.as_ref().map_or("?", |x| x.as_str()))?;
+        match self.rowid {
+            Some(rowid) => write!(f, "rowid {}", rowid)?,
+            None => write!(f, "rowid ?")?,
+        }
+        write!(f, ": ({}) [page {}, offset {}, {}, confidence {:.2}",
+               values.join(", "), self.page, self.offset, self.source, self.confidence)?;
+        if self.guessed_columns > 0 {
+            write!(f, ", first column guessed")?;
+        }
+        write!(f, "]")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_structures::{Record, SqliteFile, Value};
+    use enums::{Affinity, TextEncoding};
+    use parser::parse_record;
+    use transaction::Transaction;
+    use writer::DatabaseBuilder;
+    use Parser;
+
+    fn row(i: i64) -> (i64, Vec<Value>) {
+        (i, vec![Value::Null, Value::Text(format!("name {}", i)), Value::Integer(i * 1000)])
+    }
+
+    #[test]
+    fn first_column_from_freeblock_size() {
+        let values = vec![Value::Text("abcd".to_string()), Value::Integer(7), Value::Integer(9)];
+        // Payload size, rowid, header size and the first serial type become the freeblock header
+        let mut cell = vec![0x0a, 0x05];
+        cell.extend(Record::encode(&values));
+        let size = cell.len() as u8;
+        cell[..4].copy_from_slice(&[0x00, 0x00, 0x00, size]);
+        let shape = super::TableShape {
+            name: "t".to_string(),
+            root: 2,
+            affinities: vec![Affinity::Text, Affinity::Integer, Affinity::Integer],
+            rowid_alias: None,
+        };
+        let candidates = super::reconstruct_record(&cell, 0, 0, &shape, TextEncoding::UTF8);
+        let candidate = candidates.iter().find(|x| x.record.values == values).unwrap();
+        assert_eq!(1, candidate.guessed_columns);
+        assert_eq!(cell.len(), candidate.end);
+    }
+
+    #[test]
+    fn deleted_rows_are_recovered() {
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        let sql = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, n INTEGER)";
+        builder.add_table("t", sql, (1..300).map(row)).unwrap();
+        builder.add_table("u", "CREATE TABLE u(x REAL)", vec![(1, vec![Value::Real(0.5)])]).unwrap();
+        let file = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+        assert!(super::recover_deleted(&file, super::MIN_CONFIDENCE).unwrap().is_empty());
+
+        let mut transaction = Transaction::new(file).unwrap();
+        let deleted = [3, 50, 51, 52, 200, 299];
+        for &i in &deleted {
+            assert!(transaction.delete("t", i).unwrap());
+        }
+        let file = transaction.into_file().unwrap();
+        let rows = super::recover_deleted(&file, super::MIN_CONFIDENCE).unwrap();
+        assert_eq!(deleted.len(), rows.len());
+        for &i in &deleted {
+            let recovered = rows.iter().find(|x| x.values == row(i).1).unwrap();
+            assert_eq!(Some("t"), recovered.table.as_deref());
+            assert!(recovered.rowid.is_none_or(|x| x == i));
+        }
+    }
+
+    #[test]
+    fn freelist_rows_are_recovered() {
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        let sql = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, n INTEGER)";
+        builder.add_table("t", sql, (1..300).map(row)).unwrap();
+        builder.add_table("u", "CREATE TABLE u(x REAL)", vec![(1, vec![Value::Real(0.5)])]).unwrap();
+        let file = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+        let mut transaction = Transaction::new(file).unwrap();
+        for i in 100..250 {
+            assert!(transaction.delete("t", i).unwrap());
+        }
+        let file = transaction.into_file().unwrap();
+        assert_eq!(file.header.freelist_count as usize, file.freelist_pages().unwrap().len());
+
+        let rows = super::recover_freelist(&file, super::MIN_CONFIDENCE).unwrap();
+        assert!(rows.iter().any(|x| x.source == super::RecoverySource::FreePage
+                                && x.rowid.is_some_and(|i| (100..250).contains(&i))));
+        for recovered in &rows {
+            assert_eq!(Some("t"), recovered.table.as_deref());
+            assert_eq!(row(recovered.rowid.unwrap()).1, recovered.values);
+        }
+    }
+
+    #[test]
+    fn live_rows_are_not_recovered() {
+        // Written by sqlite3 with secure_delete off: 24 rows on 512 byte pages, row 3 and rows 8 to 16
+        // deleted. The root page split and kept copies of its rows, the merge freed a leaf that still
+        // has rows 17 and 18.
+        let file = SqliteFile::from_vec(include_bytes!("../testdata/root_split.db")).unwrap();
+        let name = |row: &super::RecoveredRow| match row.values[1] {
+            Value::Text(ref x) => x.split(' ').nth(1).unwrap().to_string(),
+            _ => String::new(),
+        };
+        let rows = super::recover_deleted(&file, super::MIN_CONFIDENCE).unwrap();
+        assert_eq!(vec!["3", "15"], rows.iter().map(name).collect::<Vec<String>>());
+        let rows = super::recover_freelist(&file, super::MIN_CONFIDENCE).unwrap();
+        assert_eq!(vec!["16", "14", "13"], rows.iter().map(name).collect::<Vec<String>>());
+    }
+
+    #[test]
+    fn orphan_rows_are_attributed() {
+        let shape = |name: &str, affinities: Vec<Affinity>| super::TableShape {
+            name: name.to_string(),
+            root: 2,
+            affinities,
+            rowid_alias: None,
+        };
+        let shapes = vec![shape("a", vec![Affinity::Integer, Affinity::Text]),
+                          shape("b", vec![Affinity::Integer, Affinity::Text]),
+                          shape("c", vec![Affinity::Text, Affinity::Real])];
+        let encode = |values: Vec<Value>| parse_record(&Record::encode(&values), TextEncoding::UTF8).unwrap();
+        let record = |i: i64| encode(vec![Value::Integer(i), Value::Text(format!("row {}", i))]);
+        let rows: Vec<(i64, Record)> = (50..60).map(|i| (i, record(i))).collect();
+        // The rows fill a gap of a, b and c have their rowids for other rows
+        let known = vec![(1..40).chain(70..100).map(|i| (i, record(i))).collect(),
+                         (200..300).map(|i| (i, record(i))).collect(),
+                         (1..100).map(|i| (i, encode(vec![Value::Text("x".to_string())]))).collect()];
+        let attributions = super::attribute_rows(&rows, &shapes, &known);
+        let ranking: Vec<&str> = attributions.iter().map(|x| x.table.as_str()).collect();
+        assert_eq!(vec!["a", "b", "c"], ranking);
+        assert_eq!((1.0, Some(1.0)), (attributions[0].score, attributions[0].rowids));
+        assert!(attributions[1].rowids.is_some_and(|x| (x - 0.8).abs() < 1e-9));
+        assert!(attributions[2].score < 0.5);
+
+        // Without rows to compare with, only the values count
+        assert_eq!(None, super::attribute_rows(&rows, &shapes, &[])[0].rowids);
+    }
+}
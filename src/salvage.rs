@@ -0,0 +1,465 @@
+/*
+ * Salvaging what is left of a damaged database, like the .recover command of
+ * the sqlite3 shell. Every page is read on its own, so a damaged page only
+ * loses its own rows. Interior pages tell which B-tree the pages below them
+ * belong to, and rows of pages whose root is in the schema are put back into
+ * their table. Leaf cells of any other page go into lost_and_found, along
+ * with the tables they most likely belong to.
+ */
+extern crate std;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use carving::plausible_page;
+use data_structures::{BTreeCell, BTreePage, Record, SchemaEntry, SqliteFile, TableDefinition, Value};
+use parser::{parse_cells, parse_create_table, parse_record, PAGE_SIZE_MAX};
+use recovery::{attribute_rows, Attribution, TableShape};
+use writer::DatabaseBuilder;
+use Parser;
+
+// Deeper than any real B-tree, a longer chain of parents is a loop
+const DEPTH_MAX: usize = 20;
+
+#[derive(Debug,Clone)]
+pub struct SalvagedTable {
+    pub entry: SchemaEntry,
+    pub definition: Option<TableDefinition>,
+    // No rowids for WITHOUT ROWID tables
+    pub rows: Vec<(Option<i64>, Record)>,
+}
+
+/*
+ * A row of a B-tree that is not in the schema, or no longer connected to
+ * its root
+ */
+#[derive(Debug,Clone)]
+pub struct LostRow {
+    pub root: u32,
+    pub page: u32,
+    pub rowid: i64,
+    pub record: Record,
+}
+
+#[derive(Debug,Clone)]
+pub struct Salvage {
+    pub schema: Vec<SchemaEntry>,
+    pub tables: Vec<SalvagedTable>,
+    pub lost_and_found: Vec<LostRow>,
+    // The likely tables of the rows in lost_and_found, by their root
+    pub attributions: Vec<(u32, Vec<Attribution>)>,
+    pub damaged: Vec<(u32, String)>,
+}
+
+/*
+ * The page size that most pages are plausible B-tree pages with, for files
+ * whose header is gone
+ */
+fn guess_page_size(buffer: &[u8]) -> u32 {
+    let mut best = (0, 4096);
+    let mut page_size = 512;
+    while page_size <= PAGE_SIZE_MAX as usize {
+        let pages = (0..buffer.len() / page_size)
+            .filter(|&x| plausible_page(&buffer[x * page_size..], page_size).is_some())
+            .count();
+        if pages > best.0 {
+            best = (pages, page_size as u32);
+        }
+        page_size *= 2;
+    }
+    best.1
+}
+
+/*
+ * The file with its own header, or with the header of an empty database if
+ * that is damaged. A header that can't describe the pages gets the page size
+ * the pages have.
+ */
+fn open(buffer: &[u8]) -> Result<SqliteFile, String> {
+    let mut header = match SqliteFile::from_vec(buffer) {
+        Ok(file) => {
+            if file.header.page_layout_is_valid() {
+                return Ok(file)
+            }
+            file.header
+        },
+        Err(_) => SqliteFile::from_vec(&DatabaseBuilder::new().build()?)?.header,
+    };
+    header.page_size = guess_page_size(buffer);
+    if !header.page_layout_is_valid() {
+        header.reserved_space = 0;
+    }
+    header.database_size = (buffer.len() / header.page_size as usize) as u32;
+    header.version_valid_for = header.file_change_counter;
+    let mut bytes = buffer.to_vec();
+    if bytes.len() >= 100 {
+        bytes[..100].copy_from_slice(&header.to_bytes());
+    }
+    Ok(SqliteFile::new(header, bytes))
+}
+
+/*
+ * The pages that are no B-tree pages: the freelist, if it can be read, and
+ * the pointer map of auto-vacuum databases
+ */
+fn other_pages(file: &SqliteFile) -> HashSet<u32> {
+    let mut pages: HashSet<u32> = file.freelist_pages().unwrap_or_default().into_iter()
+        .map(|x| x.0)
+        .collect();
+    if file.header.largest_root_page != 0 {
+        let step = file.header.usable_size() / 5 + 1;
+        pages.extend((0..).map(|x| 2 + x * step).take_while(|&x| x <= file.page_count()));
+    }
+    pages
+}
+
+/*
+ * The cells of a page one by one, so that a bad cell pointer only loses
+ * that cell
+ */
+fn page_cells(file: &SqliteFile, page_id: u32, page: &BTreePage) -> Vec<Result<BTreeCell, String>> {
+    let bytes = match file.page_bytes(page_id as usize) {
+        Ok(x) => x,
+        Err(e) => return vec![Err(e)],
+    };
+    page.cell_pointer.iter().map(|&pointer| {
+        let single = BTreePage { header: page.header, cell_pointer: vec![pointer] };
+        parse_cells(bytes, &single, file.header.usable_size()).map(|mut x| x.remove(0))
+    }).collect()
+}
+
+/*
+ * The pages of an overflow chain, as far as it can be followed
+ */
+fn overflow_chain(file: &SqliteFile, first: u32) -> Vec<u32> {
+    let mut chain = vec![];
+    let mut next = first;
+    while next != 0 && chain.len() < file.page_count() as usize {
+        let page = match file.page_bytes(next as usize) {
+            Ok(x) => x,
+            Err(_) => break,
+        };
+        chain.push(next);
+        next = u32::from_be_bytes([page[0], page[1], page[2], page[3]]);
+    }
+    chain
+}
+
+/*
+ * Follow the parents up to the page that has none. A loop has no root.
+ */
+fn root_of(page_id: u32, parents: &HashMap<u32, u32>) -> Option<u32> {
+    let mut current = page_id;
+    for _ in 0..DEPTH_MAX {
+        match parents.get(&current) {
+            Some(&parent) => current = parent,
+            None => return Some(current),
+        }
+    }
+    None
+}
+
+pub fn salvage(buffer: &[u8]) -> Result<Salvage, String> {
+    if buffer.len() < 512 {
+        return Err("Too small to be a database".to_string())
+    }
+    let file = open(buffer)?;
+    let skipped = other_pages(&file);
+    let mut damaged = vec![];
+    let mut pages = vec![];
+    // page_count never goes beyond the end of the buffer, whatever the header says
+    for page_id in 1..=file.page_count() {
+        if skipped.contains(&page_id) {
+            continue
+        }
+        match file.get_page(page_id as usize) {
+            Ok(page) => pages.push((page_id, page)),
+            Err(_) => damaged.push((page_id, "Not a B-tree page".to_string())),
+        }
+    }
+
+    // Read all cells first, the overflow pages they use are no damaged pages
+    let mut parents = HashMap::new();
+    let mut overflow = HashSet::new();
+    let mut lost_cells: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    let mut cells = vec![];
+    for &(page_id, ref page) in &pages {
+        for cell in page_cells(&file, page_id, page) {
+            match cell {
+                Ok(cell) => {
+                    overflow.extend(cell.overflow_page.map_or(vec![], |x| overflow_chain(&file, x)));
+                    if let Some(child) = cell.left_child {
+                        parents.entry(child).or_insert(page_id);
+                    }
+                    cells.push((page_id, page.header.page_type, cell));
+                },
+                Err(e) => lost_cells.entry(page_id).or_default().push(e),
+            }
+        }
+        if let Some(child) = page.header.right_most_pointer {
+            parents.entry(child).or_insert(page_id);
+        }
+    }
+    damaged.retain(|x| !overflow.contains(&x.0));
+
+    let mut records = vec![];
+    for (page_id, page_type, cell) in cells {
+        if cell.left_child.is_some() && page_type.is_table() {
+            continue
+        }
+        let record = file.read_payload(&cell).and_then(|x| parse_record(&x, file.header.text_encoding));
+        match record {
+            Ok(record) => records.push((root_of(page_id, &parents), page_id, page_type, cell.rowid, record)),
+            Err(e) => lost_cells.entry(page_id).or_default().push(e),
+        }
+    }
+    for (page_id, errors) in lost_cells {
+        damaged.push((page_id, format!("{} unreadable cells, the first: {}", errors.len(), errors[0])));
+    }
+
+    let schema: Vec<SchemaEntry> = records.iter()
+        .filter(|x| x.0 == Some(1) && x.2.is_table())
+        .map(|x| SchemaEntry::from_record(&x.4))
+        .collect();
+    let mut tables: Vec<SalvagedTable> = schema.iter()
+        .filter(|x| x.entry_type == "table" && x.rootpage > 1)
+        .map(|x| SalvagedTable {
+            entry: x.clone(),
+            definition: x.sql.as_ref().and_then(|sql| parse_create_table(sql).ok()),
+            rows: vec![],
+        })
+        .collect();
+    let roots: HashSet<u32> = schema.iter().map(|x| x.rootpage).chain(Some(1)).collect();
+    let mut lost_and_found = vec![];
+    for (root, page, page_type, rowid, record) in records {
+        let table = tables.iter_mut().find(|x| Some(x.entry.rootpage) == root);
+        let without_rowid = table.as_ref()
+            .and_then(|x| x.definition.as_ref())
+            .is_some_and(|x| x.without_rowid);
+        match table {
+            // WITHOUT ROWID tables are index B-trees
+            Some(table) if page_type.is_table() != without_rowid => {
+                table.rows.push((rowid, record));
+            },
+            Some(_) => (),
+            // Indexes can be rebuilt, stray index pages are not worth keeping
+            None if page_type.is_table() && !root.is_some_and(|x| roots.contains(&x)) => {
+                let rowid = rowid.unwrap_or(0);
+                lost_and_found.push(LostRow { root: root.unwrap_or(page), page, rowid, record });
+            },
+            None => (),
+        }
+    }
+    for table in &mut tables {
+        let mut seen = HashSet::new();
+        table.rows.sort_by_key(|x| x.0);
+        table.rows.retain(|x| x.0.is_none() || seen.insert(x.0));
+    }
+    damaged.sort();
+    let attributions = attribute_lost_rows(&lost_and_found, &schema, &tables);
+    Ok(Salvage { schema, tables, lost_and_found, attributions, damaged })
+}
+
+fn attribute_lost_rows(lost_and_found: &[LostRow], schema: &[SchemaEntry], tables: &[SalvagedTable])
+                       -> Vec<(u32, Vec<Attribution>)> {
+    let shapes = TableShape::from_entries(schema);
+    let known_rows: Vec<Vec<(i64, Record)>> = shapes.iter().map(|shape| {
+        tables.iter()
+            .find(|x| x.entry.name == shape.name)
+            .map_or(vec![], |x| x.rows.iter().filter_map(|x| Some((x.0?, x.1.clone()))).collect())
+    }).collect();
+    let mut groups: BTreeMap<u32, Vec<(i64, Record)>> = BTreeMap::new();
+    for row in lost_and_found {
+        groups.entry(row.root).or_default().push((row.rowid, row.record.clone()));
+    }
+    groups.into_iter()
+        .map(|(root, rows)| (root, attribute_rows(&rows, &shapes, &known_rows)))
+        .collect()
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn value_list(values: &[Value]) -> String {
+    values.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/*
+ * The INSERT for a salvaged row. Records written before an ALTER TABLE ADD
+ * COLUMN are shorter, the missing columns get their default.
+ */
+fn insert(table: &SalvagedTable, rowid: Option<i64>, record: &Record) -> String {
+    let name = quote(&table.entry.name);
+    let definition = match table.definition {
+        Some(ref x) => x,
+        None => return format!("INSERT INTO {} VALUES({});", name, value_list(&record.values)),
+    };
+    let mut columns: Vec<String> = definition.record_columns().iter().map(|x| quote(x)).collect();
+    let mut values = record.values.clone();
+    values.truncate(columns.len());
+    columns.truncate(values.len());
+    match (definition.rowid_alias(), rowid) {
+        // The record has NULL in place of the INTEGER PRIMARY KEY
+        (Some(alias), Some(rowid)) if alias < values.len() => values[alias] = Value::Integer(rowid),
+        (None, Some(rowid)) if !definition.without_rowid => {
+            columns.insert(0, "_rowid_".to_string());
+            values.insert(0, Value::Integer(rowid));
+        },
+        _ => (),
+    }
+    format!("INSERT INTO {}({}) VALUES({});", name, columns.join(", "), value_list(&values))
+}
+
+impl Salvage {
+    /*
+     * A script that recreates the database from what was salvaged. Indexes
+     * are created after the rows are in, so they are rebuilt from them.
+     */
+    pub fn to_sql(&self) -> String {
+        let mut sql = vec![];
+        for &(page, ref reason) in &self.damaged {
+            sql.push(format!("-- Page {}: {}", page, reason));
+        }
+        sql.push("BEGIN;".to_string());
+        for table in &self.tables {
+            // Virtual tables need their module, their shadow tables are kept as they are
+            let create = match table.entry.sql {
+                Some(ref x) if !x.starts_with("CREATE VIRTUAL") => x,
+                _ => continue,
+            };
+            if table.entry.name.starts_with("sqlite_") {
+                continue
+            }
+            sql.push(format!("{};", create));
+            sql.extend(table.rows.iter().map(|&(rowid, ref record)| insert(table, rowid, record)));
+        }
+
+        // Only an AUTOINCREMENT table creates sqlite_sequence
+        let autoincrement = self.tables.iter()
+            .any(|x| x.entry.sql.as_ref().is_some_and(|x| x.to_uppercase().contains("AUTOINCREMENT")));
+        let sequence = self.tables.iter().find(|x| x.entry.name == "sqlite_sequence" && autoincrement);
+        if let Some(sequence) = sequence {
+            sql.push("DELETE FROM sqlite_sequence;".to_string());
+            for (_, record) in &sequence.rows {
+                sql.push(format!("INSERT INTO sqlite_sequence VALUES({});", value_list(&record.values)));
+            }
+        }
+
+        if !self.lost_and_found.is_empty() {
+            let names: HashSet<String> = self.schema.iter().map(|x| x.name.to_lowercase()).collect();
+            let name = Some("lost_and_found".to_string()).into_iter()
+                .chain((0..).map(|x| format!("lost_and_found_{}", x)))
+                .find(|x| !names.contains(x))
+                .unwrap_or_default();
+            let fields = self.lost_and_found.iter().map(|x| x.record.values.len()).max().unwrap_or(0);
+            let columns: Vec<String> = (0..fields).map(|x| format!(", c{}", x)).collect();
+            // The most likely tables of each group of rows, best first
+            for (root, attributions) in &self.attributions {
+                let likely: Vec<String> = attributions.iter()
+                    .filter(|x| x.score > 0.0)
+                    .take(3)
+                    .map(|x| format!("{} {:.2}", x.table, x.score))
+                    .collect();
+                sql.push(format!("-- rootpgno {}: {}", root, likely.join(", ")));
+            }
+            sql.push(format!("CREATE TABLE {}(rootpgno INTEGER, pgno INTEGER, nfield INTEGER, id INTEGER{});",
+                             name, columns.concat()));
+            for row in &self.lost_and_found {
+                let nfield = row.record.values.len() as i64;
+                let mut values = vec![Value::Integer(row.root as i64), Value::Integer(row.page as i64),
+                                      Value::Integer(nfield), Value::Integer(row.rowid)];
+                values.extend(row.record.values.iter().cloned());
+                values.resize(fields + 4, Value::Null);
+                sql.push(format!("INSERT INTO {} VALUES({});", name, value_list(&values)));
+            }
+        }
+
+        for entry in self.schema.iter().filter(|x| x.entry_type != "table") {
+            if let Some(ref x) = entry.sql {
+                sql.push(format!("{};", x));
+            }
+        }
+        sql.push("COMMIT;".to_string());
+        sql.join("\n")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_structures::{SqliteFile, Value};
+    use writer::DatabaseBuilder;
+    use Parser;
+
+    fn row(i: i64) -> (i64, Vec<Value>) {
+        (i, vec![Value::Null, Value::Text(format!("name {}", i))])
+    }
+
+    #[test]
+    fn damaged_pages_are_skipped() {
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        let sql = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT)";
+        builder.add_table("t", sql, (1..300).map(row)).unwrap();
+        builder.add_table("u", "CREATE TABLE u(x)", vec![(1, vec![Value::Integer(7)])]).unwrap();
+        let mut buffer = builder.build().unwrap();
+        let file = SqliteFile::from_vec(&buffer).unwrap();
+        let root = file.schema().unwrap()[0].rootpage;
+        let leaf = *file.btree_pages(root).unwrap().last().unwrap();
+        let lost: Vec<i64> = file.get_cells(leaf as usize).unwrap().iter().filter_map(|x| x.rowid).collect();
+
+        // A leaf page loses its rows, the rest of the table is still there
+        buffer[(leaf as usize - 1) * 1024] = 0xff;
+        let salvage = super::salvage(&buffer).unwrap();
+        assert_eq!(vec![leaf], salvage.damaged.iter().map(|x| x.0).collect::<Vec<_>>());
+        let rowids: Vec<i64> = salvage.tables[0].rows.iter().filter_map(|x| x.0).collect();
+        assert_eq!((1..300).filter(|x| !lost.contains(x)).collect::<Vec<_>>(), rowids);
+        assert_eq!(1, salvage.tables[1].rows.len());
+        assert!(salvage.lost_and_found.is_empty());
+
+        // Without the root, the leaves can't be told apart from other tables
+        buffer[(root as usize - 1) * 1024] = 0xff;
+        let salvage = super::salvage(&buffer).unwrap();
+        assert!(salvage.tables[0].rows.is_empty());
+        assert_eq!(299 - lost.len(), salvage.lost_and_found.len());
+        assert!(salvage.lost_and_found.iter().all(|x| x.root == x.page && x.record.values.len() == 2));
+        assert!(salvage.to_sql().contains("INSERT INTO lost_and_found VALUES("));
+    }
+
+    #[test]
+    fn damaged_page_size_is_guessed() {
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        let sql = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT)";
+        builder.add_table("t", sql, (1..300).map(row)).unwrap();
+        let mut buffer = builder.build().unwrap();
+        for &page_size in &[0u16, 32] {
+            buffer[16..18].copy_from_slice(&page_size.to_be_bytes());
+            let salvage = super::salvage(&buffer).unwrap();
+            assert!(salvage.damaged.is_empty());
+            assert_eq!(299, salvage.tables[0].rows.len());
+        }
+    }
+
+    #[test]
+    fn damaged_header_fields_are_ignored() {
+        let mut builder = DatabaseBuilder::new().page_size(1024);
+        let sql = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT)";
+        builder.add_table("t", sql, (1..300).map(row)).unwrap();
+        let buffer = builder.build().unwrap();
+
+        // A database size far beyond the end of the file
+        let mut damaged = buffer.clone();
+        damaged[28..32].copy_from_slice(&0x1000_0000u32.to_be_bytes());
+        let salvage = super::salvage(&damaged).unwrap();
+        assert!(salvage.damaged.is_empty());
+        assert_eq!(299, salvage.tables[0].rows.len());
+
+        // A write version the header parser rejects
+        let mut damaged = buffer;
+        damaged[18] = 7;
+        assert!(SqliteFile::from_vec(&damaged).is_err());
+        let salvage = super::salvage(&damaged).unwrap();
+        assert!(salvage.damaged.is_empty());
+        assert_eq!(299, salvage.tables[0].rows.len());
+        assert!(salvage.to_sql().starts_with("BEGIN"));
+    }
+}
@@ -0,0 +1,315 @@
+/*
+ * Decryption of SQLCipher databases. Each page is encrypted with AES-256-CBC
+ * and followed by its IV and an HMAC in the reserved space. Page 1 starts
+ * with the 16 byte KDF salt instead of the SQLite header string.
+ */
+extern crate std;
+
+use aes::Aes256;
+use cbc::cipher::{BlockDecryptMut, KeyIvInit};
+use cbc::cipher::block_padding::NoPadding;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::Sha512;
+
+use data_structures::SqliteFile;
+use parser::HEADER_STRING;
+use Parser;
+
+const SALT_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 16;
+const BLOCK_SIZE: usize = 16;
+const HMAC_SALT_MASK: u8 = 0x3a;
+const FAST_KDF_ITER: u32 = 2;
+
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum HmacAlgorithm {
+    Sha1,
+    Sha512,
+}
+
+impl HmacAlgorithm {
+    pub fn output_size(self) -> usize {
+        match self {
+            HmacAlgorithm::Sha1 => 20,
+            HmacAlgorithm::Sha512 => 64,
+        }
+    }
+}
+
+/*
+ * The algorithm is used both for PBKDF2 and the page HMAC, as in the
+ * default SQLCipher parameter sets
+ */
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct CipherParams {
+    pub page_size: usize,
+    pub kdf_iter: u32,
+    pub hmac: HmacAlgorithm,
+}
+
+impl CipherParams {
+    pub fn v3() -> CipherParams {
+        CipherParams {
+            page_size: 1024,
+            kdf_iter: 64_000,
+            hmac: HmacAlgorithm::Sha1,
+        }
+    }
+
+    pub fn v4() -> CipherParams {
+        CipherParams {
+            page_size: 4096,
+            kdf_iter: 256_000,
+            hmac: HmacAlgorithm::Sha512,
+        }
+    }
+
+    /*
+     * IV and HMAC, rounded up to the AES block size
+     */
+    pub fn reserve_size(&self) -> usize {
+        (IV_SIZE + self.hmac.output_size()).div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+    }
+}
+
+#[derive(Debug,Clone)]
+pub enum CipherKey {
+    Passphrase(Vec<u8>),
+    Raw([u8; KEY_SIZE]),
+}
+
+impl CipherKey {
+    /*
+     * Accepts the same formats as PRAGMA key: a passphrase, or a raw key
+     * written as x'<64 hex digits>'
+     */
+    pub fn parse(key: &str) -> Result<CipherKey, String> {
+        let hex = match (key.starts_with("x'") || key.starts_with("X'"), key.ends_with('\'')) {
+            (true, true) if key.len() >= 3 => &key[2..key.len() - 1],
+            _ => return Ok(CipherKey::Passphrase(key.as_bytes().to_vec())),
+        };
+        if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() {
+            return Err("Raw keys need 64 hex digits".to_string())
+        }
+        let mut raw = [0; KEY_SIZE];
+        for (i, byte) in raw.iter_mut().enumerate() {
+            *byte = match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+                Ok(x) => x,
+                Err(_) => return Err(format!("Invalid hex digits in raw key: {}", hex)),
+            };
+        }
+        Ok(CipherKey::Raw(raw))
+    }
+}
+
+struct Keys {
+    encryption: [u8; KEY_SIZE],
+    hmac: [u8; KEY_SIZE],
+}
+
+fn pbkdf2(algorithm: HmacAlgorithm, password: &[u8], salt: &[u8], rounds: u32) -> [u8; KEY_SIZE] {
+    let mut key = [0; KEY_SIZE];
+    match algorithm {
+        HmacAlgorithm::Sha1 => ::pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, rounds, &mut key),
+        HmacAlgorithm::Sha512 => ::pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, rounds, &mut key),
+    }
+    key
+}
+
+fn derive_keys(key: &CipherKey, salt: &[u8], params: &CipherParams) -> Keys {
+    let encryption = match *key {
+        CipherKey::Passphrase(ref passphrase) => pbkdf2(params.hmac, passphrase, salt, params.kdf_iter),
+        CipherKey::Raw(raw) => raw,
+    };
+    let hmac_salt: Vec<u8> = salt.iter().map(|x| x ^ HMAC_SALT_MASK).collect();
+    let hmac = pbkdf2(params.hmac, &encryption, &hmac_salt, FAST_KDF_ITER);
+    Keys { encryption, hmac }
+}
+
+/*
+ * HMAC over the ciphertext and IV, followed by the little-endian page number
+ */
+fn page_hmac(algorithm: HmacAlgorithm, key: &[u8], content: &[u8], page_id: u32) -> Vec<u8> {
+    let page_id = page_id.to_le_bytes();
+    match algorithm {
+        HmacAlgorithm::Sha1 => {
+            let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(key).expect("HMAC takes any key size");
+            mac.update(content);
+            mac.update(&page_id);
+            mac.finalize().into_bytes().to_vec()
+        },
+        HmacAlgorithm::Sha512 => {
+            let mut mac = <Hmac<Sha512> as Mac>::new_from_slice(key).expect("HMAC takes any key size");
+            mac.update(content);
+            mac.update(&page_id);
+            mac.finalize().into_bytes().to_vec()
+        },
+    }
+}
+
+fn decrypt_page(page: &[u8], page_id: u32, keys: &Keys, params: &CipherParams)
+                -> Result<Vec<u8>, String> {
+    let offset = if page_id == 1 { SALT_SIZE } else { 0 };
+    let reserve_start = params.page_size - params.reserve_size();
+    let iv = &page[reserve_start..reserve_start + IV_SIZE];
+    let hmac_start = reserve_start + IV_SIZE;
+    let stored_hmac = &page[hmac_start..hmac_start + params.hmac.output_size()];
+    if page_hmac(params.hmac, &keys.hmac, &page[offset..hmac_start], page_id) != stored_hmac {
+        return Err(format!("HMAC check failed for page {}", page_id))
+    }
+
+    let mut plain = page.to_vec();
+    cbc::Decryptor::<Aes256>::new_from_slices(&keys.encryption, iv)
+        .map_err(|e| e.to_string())?
+        .decrypt_padded_mut::<NoPadding>(&mut plain[offset..reserve_start])
+        .map_err(|e| e.to_string())?;
+    if page_id == 1 {
+        plain[..SALT_SIZE].copy_from_slice(HEADER_STRING.as_bytes());
+    }
+    Ok(plain)
+}
+
+/*
+ * Decrypt a complete database. Every page is authenticated before it is
+ * decrypted, so a wrong key or parameter set fails on page 1.
+ */
+pub fn decrypt(buffer: &[u8], key: &CipherKey, params: &CipherParams) -> Result<Vec<u8>, String> {
+    if buffer.len() < params.page_size || !buffer.len().is_multiple_of(params.page_size) {
+        return Err(format!("File size is not a multiple of the page size {}", params.page_size))
+    }
+    let keys = derive_keys(key, &buffer[..SALT_SIZE], params);
+    let mut plain = Vec::with_capacity(buffer.len());
+    for (i, page) in buffer.chunks(params.page_size).enumerate() {
+        plain.extend(decrypt_page(page, i as u32 + 1, &keys, params)?);
+    }
+    Ok(plain)
+}
+
+/*
+ * Try the SQLCipher 4 defaults first, then SQLCipher 3
+ */
+pub fn open(buffer: &[u8], key: &CipherKey) -> Result<SqliteFile, String> {
+    let plain = decrypt(buffer, key, &CipherParams::v4())
+        .or_else(|_| decrypt(buffer, key, &CipherParams::v3()))
+        .map_err(|_| "Wrong key or unsupported SQLCipher parameters".to_string())?;
+    SqliteFile::from_vec(&plain)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use aes::Aes256;
+    use cbc::cipher::{BlockEncryptMut, KeyIvInit};
+    use cbc::cipher::block_padding::NoPadding;
+
+    use data_structures::Value;
+
+    use super::{CipherKey, CipherParams, HmacAlgorithm};
+
+    fn encrypt(plain: &[u8], key: &CipherKey, params: &CipherParams) -> Vec<u8> {
+        let salt = [0x5a; 16];
+        let keys = super::derive_keys(key, &salt, params);
+        let mut buffer = vec![];
+        for (i, page) in plain.chunks(params.page_size).enumerate() {
+            let page_id = i as u32 + 1;
+            let offset = if page_id == 1 { 16 } else { 0 };
+            let reserve_start = params.page_size - params.reserve_size();
+            let mut page = page.to_vec();
+            let iv = [page_id as u8; 16];
+            cbc::Encryptor::<Aes256>::new_from_slices(&keys.encryption, &iv).unwrap()
+                .encrypt_padded_mut::<NoPadding>(&mut page[offset..reserve_start], reserve_start - offset)
+                .unwrap();
+            if page_id == 1 {
+                page[..16].copy_from_slice(&salt);
+            }
+            page[reserve_start..reserve_start + 16].copy_from_slice(&iv);
+            let hmac = super::page_hmac(params.hmac, &keys.hmac, &page[offset..reserve_start + 16], page_id);
+            page[reserve_start + 16..reserve_start + 16 + hmac.len()].copy_from_slice(&hmac);
+            buffer.extend(page);
+        }
+        buffer
+    }
+
+    fn plain_database(page_size: usize, reserve: u8) -> Vec<u8> {
+        let mut plain = vec![0; page_size * 2];
+        plain[..16].copy_from_slice(b"SQLite format 3\0");
+        plain[16..24].copy_from_slice(&[(page_size >> 8) as u8, page_size as u8, 1, 1, reserve, 64, 32, 32]);
+        plain[24..32].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        plain[44..48].copy_from_slice(&[0, 0, 0, 4]);
+        plain[56..60].copy_from_slice(&[0, 0, 0, 1]);
+        plain[92..96].copy_from_slice(&[0, 0, 0, 1]);
+        plain[100] = 0x0d;
+        plain[page_size] = 0x0d;
+        plain
+    }
+
+    #[test]
+    fn raw_key_format() {
+        match CipherKey::parse(&format!("x'{}'", "0f".repeat(32))).unwrap() {
+            CipherKey::Raw(raw) => assert_eq!([0x0f; 32], raw),
+            _ => panic!("expected a raw key"),
+        }
+        assert!(CipherKey::parse("x'0f'").is_err());
+        match CipherKey::parse("secret").unwrap() {
+            CipherKey::Passphrase(p) => assert_eq!(b"secret".to_vec(), p),
+            _ => panic!("expected a passphrase"),
+        }
+    }
+
+    #[test]
+    fn decrypt_round_trip() {
+        for &(params, reserve) in &[
+            (CipherParams { kdf_iter: 10, ..CipherParams::v3() }, 48),
+            (CipherParams { kdf_iter: 10, ..CipherParams::v4() }, 80),
+        ] {
+            assert_eq!(reserve as usize, params.reserve_size());
+            let key = CipherKey::Passphrase(b"correct horse".to_vec());
+            let plain = plain_database(params.page_size, reserve);
+            let encrypted = encrypt(&plain, &key, &params);
+            assert!(encrypted[16..params.page_size - reserve as usize] != plain[16..params.page_size - reserve as usize]);
+
+            let decrypted = super::decrypt(&encrypted, &key, &params).unwrap();
+            let usable = params.page_size - reserve as usize;
+            assert_eq!(&plain[..usable], &decrypted[..usable]);
+            assert_eq!(&plain[params.page_size..params.page_size + usable],
+                       &decrypted[params.page_size..params.page_size + usable]);
+
+            let wrong = CipherKey::Passphrase(b"wrong horse".to_vec());
+            assert!(super::decrypt(&encrypted, &wrong, &params).is_err());
+        }
+        assert_eq!(HmacAlgorithm::Sha512, CipherParams::v4().hmac);
+    }
+
+    /*
+     * Databases written by sqlite3 and encrypted with the SQLCipher 3 and 4
+     * defaults by a separate implementation of the format
+     */
+    #[test]
+    fn decrypt_fixtures() {
+        let passphrase = CipherKey::parse("correct horse battery staple").unwrap();
+        let raw = CipherKey::parse("x'000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f'").unwrap();
+        for &(buffer, key, page_size) in &[
+            (&include_bytes!("../testdata/sqlcipher3_passphrase.db")[..], &passphrase, 1024),
+            (&include_bytes!("../testdata/sqlcipher3_raw_key.db")[..], &raw, 1024),
+            (&include_bytes!("../testdata/sqlcipher4_passphrase.db")[..], &passphrase, 4096),
+            (&include_bytes!("../testdata/sqlcipher4_raw_key.db")[..], &raw, 4096),
+        ] {
+            let file = super::open(buffer, key).unwrap();
+            assert_eq!(page_size, file.header.page_size);
+            let root = file.schema().unwrap()[0].rootpage;
+            let rows: Vec<(i64, Vec<Value>)> = file.table_rows(root).unwrap().into_iter()
+                .map(|(rowid, record)| (rowid, record.values))
+                .collect();
+            assert_eq!(vec![
+                (1, vec![Value::Null, Value::Text("alpha".to_string()), Value::Blob(vec![0, 0xff])]),
+                (2, vec![Value::Null, Value::Text("beta".to_string()), Value::Null]),
+                (3, vec![Value::Null, Value::Text("gamma".to_string()), Value::Blob(vec![0; 1200])]),
+            ], rows);
+
+            let wrong = CipherKey::parse("wrong horse battery staple").unwrap();
+            assert!(super::open(buffer, &wrong).is_err());
+        }
+    }
+}
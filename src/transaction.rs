@@ -0,0 +1,1042 @@
+/*
+ * Changing rows of an existing database. Like SQLite's pager, a transaction
+ * keeps modified copies of the pages it touches. On commit, the original
+ * content of those pages goes to a rollback journal first, so a crash at any
+ * point leaves a file that SQLite restores to the old state on the next open.
+ *
+ * No SQLite file locks are taken, so the database must not be open in any
+ * other process while a transaction commits. SQLite wouldn't know the pages
+ * changed under it and could overwrite them or read a torn state.
+ *
+ * Only rowid tables without indexes can be changed. Tables with an index of
+ * any kind, which includes every UNIQUE constraint and every PRIMARY KEY that
+ * isn't an INTEGER PRIMARY KEY, are refused, as are WITHOUT ROWID tables and
+ * databases with auto_vacuum.
+ */
+extern crate std;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nom::IResult;
+
+use data_structures::{BTreePageType, Header, Journal, JournalHeader, JournalRecord, JournalSegment, Record,
+                      SqliteFile, Value};
+use parser::{cell_size, encode_varint, local_payload_size, parse_btree_page, parse_cells, parse_header,
+             parse_journal, parse_varint};
+use read_file;
+use writer::write_btree_page;
+
+const LOCK_BYTE_OFFSET: usize = 0x4000_0000;
+const SECTOR_SIZE: u32 = 512;
+// SQLite defragments a page rather than let fragments grow beyond this
+const FRAGMENTED_BYTES_MAX: usize = 60;
+// Older versions miscounted the leaves of a freelist trunk, so SQLite never
+// fills the last slots
+const TRUNK_UNUSED_SLOTS: usize = 8;
+
+/*
+ * The cells of a B-tree page, for pages that are rewritten as a whole when
+ * they are split or merged
+ */
+struct Node {
+    page_type: BTreePageType,
+    cells: Vec<Vec<u8>>,
+    right_most_pointer: Option<u32>,
+}
+
+impl Node {
+    fn child(&self, index: usize) -> u32 {
+        match self.cells.get(index) {
+            Some(cell) => u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]),
+            None => self.right_most_pointer.unwrap_or(0),
+        }
+    }
+
+    fn set_child(&mut self, index: usize, page_id: u32) {
+        match self.cells.get_mut(index) {
+            Some(cell) => cell[..4].copy_from_slice(&page_id.to_be_bytes()),
+            None => self.right_most_pointer = Some(page_id),
+        }
+    }
+
+    fn size(&self, header_offset: usize) -> usize {
+        header_offset + self.page_type.header_size() + self.cells.iter().map(|c| c.len() + 2).sum::<usize>()
+    }
+}
+
+fn read_u16(page: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([page[offset], page[offset + 1]]) as usize
+}
+
+fn write_u16(page: &mut [u8], offset: usize, value: usize) {
+    page[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes());
+}
+
+fn read_u32(page: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([page[offset], page[offset + 1], page[offset + 2], page[offset + 3]])
+}
+
+fn content_offset(page: &[u8], header_offset: usize) -> usize {
+    match read_u16(page, header_offset + 5) {
+        0 => 65536,
+        x => x,
+    }
+}
+
+fn set_content_offset(page: &mut [u8], header_offset: usize, offset: usize) {
+    write_u16(page, header_offset + 5, if offset == 65536 { 0 } else { offset });
+}
+
+fn cell_rowid(cell: &[u8], page_type: BTreePageType) -> i64 {
+    let mut i = if page_type.is_leaf() { cell } else { &cell[4..] };
+    if page_type.is_leaf() {
+        // Skip the payload size
+        if let IResult::Done(rest, _) = parse_varint(i) {
+            i = rest;
+        }
+    }
+    match parse_varint(i) {
+        IResult::Done(_, rowid) => rowid as i64,
+        _ => 0,
+    }
+}
+
+fn freeblocks(page: &[u8], header_offset: usize, usable_size: usize) -> Result<Vec<(usize, usize)>, String> {
+    let mut blocks = vec![];
+    let mut offset = read_u16(page, header_offset + 1);
+    while offset != 0 {
+        if offset + 4 > usable_size || blocks.last().is_some_and(|&(last, _)| offset <= last) {
+            return Err(format!("Freeblock at {} is out of order or bounds", offset))
+        }
+        blocks.push((offset, read_u16(page, offset + 2)));
+        offset = read_u16(page, offset);
+    }
+    Ok(blocks)
+}
+
+/*
+ * Move all cells to the end of the usable area, turning freeblocks and
+ * fragments into one gap after the cell pointers
+ */
+fn defragment(page: &mut [u8], header_offset: usize, page_type: BTreePageType, usable_size: usize)
+              -> Result<(), String> {
+    let original = page.to_vec();
+    let pointers = header_offset + page_type.header_size();
+    let count = read_u16(page, header_offset + 3);
+    let mut top = usable_size;
+    for i in 0..count {
+        let start = read_u16(&original, pointers + 2 * i);
+        let size = cell_size(&original[start.min(usable_size)..usable_size], usable_size as u32, page_type)?;
+        if top < pointers + 2 * count + size || start + size > original.len() {
+            return Err("Cells overlap the cell pointers".to_string())
+        }
+        top -= size;
+        page[top..top + size].copy_from_slice(&original[start..start + size]);
+        write_u16(page, pointers + 2 * i, top);
+    }
+    for byte in &mut page[pointers + 2 * count..top] {
+        *byte = 0;
+    }
+    write_u16(page, header_offset + 1, 0);
+    page[header_offset + 7] = 0;
+    set_content_offset(page, header_offset, top);
+    Ok(())
+}
+
+/*
+ * First fit in the freeblock list. The rest of a block stays free, unless
+ * it is too small for a freeblock and becomes fragmented bytes.
+ */
+fn take_freeblock(page: &mut [u8], header_offset: usize, size: usize) -> Option<usize> {
+    let mut previous = header_offset + 1;
+    let mut offset = read_u16(page, previous);
+    while offset != 0 && offset + 4 <= page.len() {
+        let block_size = read_u16(page, offset + 2);
+        if block_size >= size {
+            let leftover = block_size - size;
+            if leftover >= 4 {
+                write_u16(page, offset + 2, leftover);
+                return Some(offset + leftover)
+            }
+            if page[header_offset + 7] as usize + leftover > FRAGMENTED_BYTES_MAX {
+                return None
+            }
+            let next = read_u16(page, offset);
+            write_u16(page, previous, next);
+            page[header_offset + 7] += leftover as u8;
+            return Some(offset)
+        }
+        let next = read_u16(page, offset);
+        if next != 0 && next <= offset {
+            return None
+        }
+        previous = offset;
+        offset = next;
+    }
+    None
+}
+
+/*
+ * Find room for a cell like SQLite does: a freeblock, or the gap between the
+ * cell pointers and the content area, defragmenting if the free space is too
+ * scattered. The page must have room for the cell and its pointer.
+ */
+fn allocate_space(page: &mut [u8], header_offset: usize, page_type: BTreePageType, usable_size: usize,
+                  size: usize) -> Result<usize, String> {
+    let gap = header_offset + page_type.header_size() + 2 * read_u16(page, header_offset + 3);
+    if gap + 2 <= content_offset(page, header_offset) {
+        if let Some(start) = take_freeblock(page, header_offset, size) {
+            return Ok(start)
+        }
+    }
+    if gap + 2 + size > content_offset(page, header_offset) {
+        defragment(page, header_offset, page_type, usable_size)?;
+    }
+    let top = content_offset(page, header_offset) - size;
+    set_content_offset(page, header_offset, top);
+    Ok(top)
+}
+
+/*
+ * Give the bytes of a removed cell back to the page. They join the
+ * freeblock list, absorbing neighbouring freeblocks and the fragments in
+ * between, or move the content area if they are at its start.
+ */
+fn release_space(page: &mut [u8], header_offset: usize, usable_size: usize, start: usize, size: usize)
+                 -> Result<(), String> {
+    let mut blocks = freeblocks(page, header_offset, usable_size)?;
+    blocks.push((start, size));
+    blocks.sort();
+    let mut fragmented = page[header_offset + 7] as usize;
+    let mut merged: Vec<(usize, usize)> = vec![];
+    for (start, size) in blocks {
+        if let Some(last) = merged.last_mut() {
+            if start < last.0 + last.1 {
+                return Err(format!("Freeblock at {} overlaps its predecessor", start))
+            }
+            let gap = start - (last.0 + last.1);
+            if gap <= 3 && gap <= fragmented {
+                fragmented -= gap;
+                last.1 += gap + size;
+                continue
+            }
+        }
+        merged.push((start, size));
+    }
+    let mut top = content_offset(page, header_offset);
+    if merged.first().is_some_and(|&(start, _)| start == top) {
+        top += merged.remove(0).1;
+    }
+    set_content_offset(page, header_offset, top);
+    page[header_offset + 7] = fragmented as u8;
+    let mut previous = header_offset + 1;
+    for &(start, size) in &merged {
+        write_u16(page, previous, start);
+        write_u16(page, start + 2, size);
+        previous = start;
+    }
+    write_u16(page, previous, 0);
+    Ok(())
+}
+
+/*
+ * Split cells into pages, evenly over two pages if possible and otherwise
+ * filling one page after the other
+ */
+fn partition(sizes: &[usize], capacity: usize) -> Vec<Range<usize>> {
+    let total: usize = sizes.iter().sum();
+    let mut left = 0;
+    let mut best: Option<(usize, usize)> = None;
+    for split in 1..sizes.len() {
+        left += sizes[split - 1];
+        let larger = left.max(total - left);
+        if larger <= capacity && best.is_none_or(|(_, size)| larger < size) {
+            best = Some((split, larger));
+        }
+    }
+    if let Some((split, _)) = best {
+        return vec![0..split, split..sizes.len()]
+    }
+    let mut ranges = vec![];
+    let mut start = 0;
+    let mut used = 0;
+    for (i, &size) in sizes.iter().enumerate() {
+        if used + size > capacity && i > start {
+            ranges.push(start..i);
+            start = i;
+            used = 0;
+        }
+        used += size;
+    }
+    ranges.push(start..sizes.len());
+    ranges
+}
+
+pub struct Transaction {
+    file: SqliteFile,
+    header: Header,
+    page_count: u32,
+    pages: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Transaction {
+    pub fn new(file: SqliteFile) -> Result<Transaction, String> {
+        // Moving pages around would need the pointer map to be maintained
+        if file.header.largest_root_page != 0 {
+            return Err("Databases with auto_vacuum aren't supported".to_string())
+        }
+        Ok(Transaction {
+            header: file.header,
+            page_count: file.page_count(),
+            file,
+            pages: BTreeMap::new(),
+        })
+    }
+
+    /*
+     * Insert a row and return its rowid. Without an explicit rowid, the row
+     * gets the one after the largest in the table.
+     */
+    pub fn insert(&mut self, table: &str, rowid: Option<i64>, values: &[Value]) -> Result<i64, String> {
+        let root = self.table_root(table)?;
+        let rowid = match rowid {
+            Some(rowid) => rowid,
+            None => match self.max_rowid(root)? {
+                Some(i64::MAX) => return Err(format!("Table {} has no rowids left", table)),
+                Some(rowid) => rowid + 1,
+                None => 1,
+            },
+        };
+        let (path, leaf) = self.find_leaf(root, rowid)?;
+        let (index, found) = self.search_leaf(leaf, rowid)?;
+        if found {
+            return Err(format!("Row {} already exists in {}", rowid, table))
+        }
+        let cell = self.table_leaf_cell(rowid, values)?;
+        self.insert_cell(&path, leaf, index, cell)?;
+        Ok(rowid)
+    }
+
+    pub fn update(&mut self, table: &str, rowid: i64, values: &[Value]) -> Result<(), String> {
+        let root = self.table_root(table)?;
+        let (path, leaf) = self.find_leaf(root, rowid)?;
+        let (index, found) = self.search_leaf(leaf, rowid)?;
+        if !found {
+            return Err(format!("No row {} in {}", rowid, table))
+        }
+        self.remove_cell(leaf, index)?;
+        let cell = self.table_leaf_cell(rowid, values)?;
+        self.insert_cell(&path, leaf, index, cell)
+    }
+
+    /*
+     * Delete a row, returns false if there was none with that rowid
+     */
+    pub fn delete(&mut self, table: &str, rowid: i64) -> Result<bool, String> {
+        let root = self.table_root(table)?;
+        let (path, leaf) = self.find_leaf(root, rowid)?;
+        let (index, found) = self.search_leaf(leaf, rowid)?;
+        if !found {
+            return Ok(false)
+        }
+        self.remove_cell(leaf, index)?;
+        self.rebalance(&path, leaf)?;
+        Ok(true)
+    }
+
+    /*
+     * The original content of every changed page that existed before the
+     * transaction. Page 1 is always included since the header changes.
+     */
+    pub fn journal(&self) -> Journal {
+        let initial_size = self.file.page_count();
+        let nonce = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos()) ^ process::id();
+        let mut header = JournalHeader {
+            record_count: 0,
+            nonce,
+            initial_size,
+            sector_size: SECTOR_SIZE,
+            page_size: self.header.page_size,
+        };
+        let mut page_ids: Vec<u32> = self.pages.keys().cloned().filter(|&id| id <= initial_size).collect();
+        if page_ids.first() != Some(&1) {
+            page_ids.insert(0, 1);
+        }
+        let records: Vec<JournalRecord> = page_ids.into_iter()
+            .filter_map(|id| self.file.page_bytes(id as usize).ok().map(|data| (id, data.to_vec())))
+            .map(|(page_number, data)| JournalRecord {
+                page_number,
+                checksum: header.checksum(&data),
+                data,
+            })
+            .collect();
+        header.record_count = records.len() as u32;
+        Journal { segments: vec![JournalSegment { header, records }] }
+    }
+
+    /*
+     * The database with all changes applied, without touching any file
+     */
+    pub fn into_file(mut self) -> Result<SqliteFile, String> {
+        self.write_header()?;
+        let page_size = self.header.page_size as usize;
+        let mut buffer = self.file.as_bytes().to_vec();
+        buffer.resize(self.page_count as usize * page_size, 0);
+        for (&page_id, page) in &self.pages {
+            let start = (page_id as usize - 1) * page_size;
+            buffer[start..start + page_size].copy_from_slice(page);
+        }
+        let header = parse_header(&buffer)?;
+        Ok(SqliteFile::new(header, buffer))
+    }
+
+    /*
+     * Write the changes to the database file at path, which has to be the
+     * file the transaction was started on. The journal is synced before the
+     * first database page is written and deleted once all of them are.
+     * Nothing else may have the database open, see the module comment.
+     */
+    pub fn commit(mut self, path: &str) -> Result<SqliteFile, String> {
+        if self.pages.is_empty() {
+            return Ok(self.file)
+        }
+        let journal_path = format!("{}-journal", path);
+        if let Ok(journal) = read_file(&journal_path).and_then(|b| parse_journal(&b)) {
+            if journal.is_hot(&self.file) {
+                return Err(format!("Refusing to commit, {} is hot", journal_path))
+            }
+        }
+        let wal_path = format!("{}-wal", path);
+        if fs::metadata(&wal_path).map(|m| m.len() > 0).unwrap_or(false) {
+            return Err(format!("Refusing to commit, {} is not empty", wal_path))
+        }
+        let on_disk = parse_header(&read_file(path)?)?;
+        if on_disk.file_change_counter != self.file.header.file_change_counter {
+            return Err(format!("{} was changed since the transaction started", path))
+        }
+
+        self.write_header()?;
+        let page_size = self.header.page_size as u64;
+        let page_count = self.page_count as u64;
+        let write_err = |e: std::io::Error| format!("couldn't write file {}", e);
+        let mut journal_file = File::create(&journal_path).map_err(write_err)?;
+        journal_file.write_all(&self.journal().to_bytes()).map_err(write_err)?;
+        journal_file.sync_all().map_err(write_err)?;
+        // The journal's directory entry has to be durable as well
+        if let Some(directory) = Path::new(path).parent() {
+            let directory = if directory.as_os_str().is_empty() { Path::new(".") } else { directory };
+            let _ = File::open(directory).and_then(|d| d.sync_all());
+        }
+
+        let mut file = OpenOptions::new().write(true).open(path).map_err(write_err)?;
+        for (&page_id, page) in &self.pages {
+            file.seek(SeekFrom::Start((page_id as u64 - 1) * page_size)).map_err(write_err)?;
+            file.write_all(page).map_err(write_err)?;
+        }
+        file.set_len(page_count * page_size).map_err(write_err)?;
+        file.sync_all().map_err(write_err)?;
+        fs::remove_file(&journal_path).map_err(write_err)?;
+        self.into_file()
+    }
+
+    fn usable_size(&self) -> usize {
+        self.header.usable_size() as usize
+    }
+
+    fn page(&self, page_id: u32) -> Result<&[u8], String> {
+        match self.pages.get(&page_id) {
+            Some(page) => Ok(page),
+            None => self.file.page_bytes(page_id as usize),
+        }
+    }
+
+    fn page_mut(&mut self, page_id: u32) -> Result<&mut Vec<u8>, String> {
+        if !self.pages.contains_key(&page_id) {
+            let page = self.file.page_bytes(page_id as usize)?.to_vec();
+            self.pages.insert(page_id, page);
+        }
+        Ok(self.pages.get_mut(&page_id).unwrap())
+    }
+
+    /*
+     * The header as SQLite leaves it after a write transaction
+     */
+    fn write_header(&mut self) -> Result<(), String> {
+        let mut header = self.header;
+        header.database_size = self.page_count;
+        header.file_change_counter = self.file.header.file_change_counter.wrapping_add(1);
+        header.version_valid_for = header.file_change_counter;
+        let bytes = header.to_bytes();
+        self.page_mut(1)?[..100].copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    /*
+     * Root page of a rowid table. Indexes aren't updated along with the rows,
+     * so tables that have any are refused. That includes the automatic
+     * indexes of UNIQUE constraints and of non-integer primary keys.
+     * WITHOUT ROWID tables are refused as well.
+     */
+    fn table_root(&self, table: &str) -> Result<u32, String> {
+        let schema = self.file.schema()?;
+        let table_entry = schema.iter().find(|e| e.entry_type == "table" && e.name.eq_ignore_ascii_case(table));
+        let entry = match table_entry {
+            Some(entry) => entry,
+            None => return Err(format!("No table named {}", table)),
+        };
+        if schema.iter().any(|e| e.entry_type == "index" && e.tbl_name.eq_ignore_ascii_case(table)) {
+            return Err(format!("Table {} has indexes, which can't be updated", entry.name))
+        }
+        if entry.rootpage == 0 {
+            return Err(format!("Table {} is a virtual table", entry.name))
+        }
+        let page_type = self.read_node(entry.rootpage)?.page_type;
+        if !page_type.is_table() {
+            return Err(format!("Table {} is a WITHOUT ROWID table", entry.name))
+        }
+        Ok(entry.rootpage)
+    }
+
+    fn read_node(&self, page_id: u32) -> Result<Node, String> {
+        let page = self.page(page_id)?;
+        let btree_page = parse_btree_page(page)?;
+        let page_type = btree_page.header.page_type;
+        let usable_size = self.usable_size();
+        let mut cells = vec![];
+        for &pointer in &btree_page.cell_pointer {
+            let start = pointer as usize;
+            if start >= usable_size {
+                return Err(format!("Cell pointer {} on page {} is out of bounds", start, page_id))
+            }
+            let size = cell_size(&page[start..usable_size], usable_size as u32, page_type)?;
+            let mut cell = page[start..(start + size).min(usable_size)].to_vec();
+            cell.resize(size, 0);
+            cells.push(cell);
+        }
+        Ok(Node {
+            page_type,
+            cells,
+            right_most_pointer: btree_page.header.right_most_pointer,
+        })
+    }
+
+    fn write_node(&mut self, page_id: u32, node: &Node) -> Result<(), String> {
+        let header_offset = SqliteFile::btree_header_offset(page_id as usize);
+        let usable_size = self.usable_size();
+        let page = self.page_mut(page_id)?;
+        for byte in &mut page[header_offset..usable_size] {
+            *byte = 0;
+        }
+        write_btree_page(page, header_offset, usable_size, node.page_type, &node.cells,
+                         node.right_most_pointer);
+        Ok(())
+    }
+
+    /*
+     * Descend to the leaf that holds or would hold a rowid. The path has the
+     * interior pages passed on the way and the index of the child taken.
+     */
+    fn find_leaf(&self, root: u32, rowid: i64) -> Result<(Vec<(u32, usize)>, u32), String> {
+        let mut path = vec![];
+        let mut page_id = root;
+        loop {
+            // SQLite never builds trees deeper than this
+            if path.len() > 20 {
+                return Err(format!("B-tree at page {} is too deep, possible loop", root))
+            }
+            let node = self.read_node(page_id)?;
+            if node.page_type.is_leaf() {
+                return Ok((path, page_id))
+            }
+            let index = node.cells.iter()
+                .position(|c| rowid <= cell_rowid(c, node.page_type))
+                .unwrap_or(node.cells.len());
+            path.push((page_id, index));
+            page_id = node.child(index);
+        }
+    }
+
+    fn search_leaf(&self, page_id: u32, rowid: i64) -> Result<(usize, bool), String> {
+        let node = self.read_node(page_id)?;
+        let index = node.cells.iter()
+            .position(|c| rowid <= cell_rowid(c, node.page_type))
+            .unwrap_or(node.cells.len());
+        let found = node.cells.get(index).is_some_and(|c| cell_rowid(c, node.page_type) == rowid);
+        Ok((index, found))
+    }
+
+    fn max_rowid(&self, root: u32) -> Result<Option<i64>, String> {
+        let (_, leaf) = self.find_leaf(root, i64::MAX)?;
+        let node = self.read_node(leaf)?;
+        Ok(node.cells.last().map(|c| cell_rowid(c, node.page_type)))
+    }
+
+    /*
+     * A table leaf cell, with the part of the record that doesn't fit on the
+     * page spilled to overflow pages
+     */
+    fn table_leaf_cell(&mut self, rowid: i64, values: &[Value]) -> Result<Vec<u8>, String> {
+        let payload = Record::encode_with_encoding(values, self.header.text_encoding);
+        let usable_size = self.usable_size();
+        let mut cell = encode_varint(payload.len() as u64);
+        cell.extend(encode_varint(rowid as u64));
+        let page_type = BTreePageType::LeafTablePage;
+        let local = local_payload_size(payload.len() as u64, usable_size as u32, page_type);
+        cell.extend_from_slice(&payload[..local]);
+        if local < payload.len() {
+            let chunks: Vec<&[u8]> = payload[local..].chunks(usable_size - 4).collect();
+            let mut pages = vec![];
+            for _ in &chunks {
+                pages.push(self.allocate_page()?);
+            }
+            cell.extend_from_slice(&pages[0].to_be_bytes());
+            for (i, chunk) in chunks.iter().enumerate() {
+                let next = pages.get(i + 1).cloned().unwrap_or(0);
+                let page = self.page_mut(pages[i])?;
+                page[..4].copy_from_slice(&next.to_be_bytes());
+                page[4..4 + chunk.len()].copy_from_slice(chunk);
+            }
+        }
+        if cell.len() < 4 {
+            cell.resize(4, 0);
+        }
+        Ok(cell)
+    }
+
+    /*
+     * Put a cell at position index of a page, into its free space if there is
+     * enough, otherwise by splitting the page
+     */
+    fn insert_cell(&mut self, path: &[(u32, usize)], page_id: u32, index: usize, cell: Vec<u8>)
+                   -> Result<(), String> {
+        let header_offset = SqliteFile::btree_header_offset(page_id as usize);
+        let usable_size = self.usable_size();
+        let free = SqliteFile::page_free_space(self.page(page_id)?, page_id as usize, usable_size as u32)?;
+        if cell.len() + 2 <= free as usize {
+            let page = self.page_mut(page_id)?;
+            let page_type = parse_btree_page(page)?.header.page_type;
+            let start = allocate_space(page, header_offset, page_type, usable_size, cell.len())?;
+            page[start..start + cell.len()].copy_from_slice(&cell);
+            let pointers = header_offset + page_type.header_size();
+            let count = read_u16(page, header_offset + 3);
+            page.copy_within(pointers + 2 * index..pointers + 2 * count, pointers + 2 * index + 2);
+            write_u16(page, pointers + 2 * index, start);
+            write_u16(page, header_offset + 3, count + 1);
+            return Ok(())
+        }
+        let mut node = self.read_node(page_id)?;
+        // Appending to the right edge of the tree starts a new page instead
+        // of leaving two half full ones, like SQLite's balance_quick
+        let append = index == node.cells.len() && match path.last() {
+            Some(&(parent_id, child_index)) => self.read_node(parent_id)?.cells.len() == child_index,
+            None => true,
+        };
+        node.cells.insert(index, cell);
+        self.store_node(path, page_id, node, append)
+    }
+
+    /*
+     * Free a cell's overflow chain and the cell itself
+     */
+    fn remove_cell(&mut self, page_id: u32, index: usize) -> Result<(), String> {
+        let header_offset = SqliteFile::btree_header_offset(page_id as usize);
+        let usable_size = self.usable_size();
+        let page = self.page(page_id)?;
+        let btree_page = parse_btree_page(page)?;
+        let page_type = btree_page.header.page_type;
+        let cell = parse_cells(page, &btree_page, usable_size as u32)?.remove(index);
+        let start = btree_page.cell_pointer[index] as usize;
+        let size = cell_size(&page[start..usable_size], usable_size as u32, page_type)?;
+
+        if let Some(first) = cell.overflow_page {
+            let count = (cell.payload_size as usize - cell.payload.len()).div_ceil(usable_size - 4);
+            let mut next = first;
+            for _ in 0..count {
+                if next == 0 || next > self.page_count {
+                    return Err(format!("Overflow chain of a cell on page {} is broken", page_id))
+                }
+                let following = read_u32(self.page(next)?, 0);
+                self.free_page(next)?;
+                next = following;
+            }
+        }
+
+        let page = self.page_mut(page_id)?;
+        let pointers = header_offset + page_type.header_size();
+        let count = read_u16(page, header_offset + 3);
+        page.copy_within(pointers + 2 * (index + 1)..pointers + 2 * count, pointers + 2 * index);
+        write_u16(page, header_offset + 3, count - 1);
+        release_space(page, header_offset, usable_size, start, size)
+    }
+
+    /*
+     * Write the cells of a node to a page, splitting it if they don't fit.
+     * The root keeps its page number: when it is full, its content moves to
+     * a new child and the root becomes an interior page above it.
+     */
+    fn store_node(&mut self, path: &[(u32, usize)], page_id: u32, node: Node, append: bool)
+                  -> Result<(), String> {
+        let header_offset = SqliteFile::btree_header_offset(page_id as usize);
+        if node.size(header_offset) <= self.usable_size() {
+            return self.write_node(page_id, &node)
+        }
+        let (&(parent_id, child_index), ancestors) = match path.split_last() {
+            Some(x) => x,
+            None => {
+                let child = self.allocate_page()?;
+                let root = Node {
+                    page_type: BTreePageType::InteriorTablePage,
+                    cells: vec![],
+                    right_most_pointer: Some(child),
+                };
+                self.write_node(page_id, &root)?;
+                return self.store_node(&[(page_id, 0)], child, node, append)
+            },
+        };
+        let (nodes, keys) = self.split_node(node, append);
+        let mut page_ids = vec![page_id];
+        for _ in 1..nodes.len() {
+            page_ids.push(self.allocate_page()?);
+        }
+        for (&page_id, node) in page_ids.iter().zip(&nodes) {
+            self.write_node(page_id, node)?;
+        }
+        // The slot of the split page now points to the last part, the other
+        // parts are inserted before it with their largest rowid as key
+        let mut parent = self.read_node(parent_id)?;
+        parent.set_child(child_index, page_ids[page_ids.len() - 1]);
+        for (i, key) in keys.into_iter().enumerate() {
+            let mut cell = page_ids[i].to_be_bytes().to_vec();
+            cell.extend(encode_varint(key as u64));
+            parent.cells.insert(child_index + i, cell);
+        }
+        self.store_node(ancestors, parent_id, parent, false)
+    }
+
+    /*
+     * Divide an overfull node into pages and the keys that separate them.
+     * Of an interior node, the cell in the middle moves up to the parent.
+     */
+    fn split_node(&self, node: Node, append: bool) -> (Vec<Node>, Vec<i64>) {
+        let page_type = node.page_type;
+        if !page_type.is_leaf() {
+            let mut cells = node.cells;
+            let right = cells.split_off(cells.len() / 2 + 1);
+            let middle = cells.pop().unwrap();
+            let left = Node {
+                page_type,
+                right_most_pointer: Some(read_u32(&middle, 0)),
+                cells,
+            };
+            let right = Node {
+                page_type,
+                cells: right,
+                right_most_pointer: node.right_most_pointer,
+            };
+            return (vec![left, right], vec![cell_rowid(&middle, page_type)])
+        }
+        let ranges = if append {
+            let last = node.cells.len() - 1;
+            vec![0..last, last..last + 1]
+        } else {
+            let sizes: Vec<usize> = node.cells.iter().map(|c| c.len() + 2).collect();
+            partition(&sizes, self.usable_size() - page_type.header_size())
+        };
+        let keys = ranges[..ranges.len() - 1].iter()
+            .map(|r| cell_rowid(&node.cells[r.end - 1], page_type))
+            .collect();
+        let nodes = ranges.into_iter()
+            .map(|r| Node {
+                page_type,
+                cells: node.cells[r].to_vec(),
+                right_most_pointer: None,
+            })
+            .collect();
+        (nodes, keys)
+    }
+
+    /*
+     * Merge a page that is less than a third full with a sibling if both fit
+     * on one page. A root left with a single child takes over its content.
+     */
+    fn rebalance(&mut self, path: &[(u32, usize)], page_id: u32) -> Result<(), String> {
+        let usable_size = self.usable_size();
+        let (&(parent_id, child_index), ancestors) = match path.split_last() {
+            Some(x) => x,
+            None => {
+                let root = self.read_node(page_id)?;
+                if root.page_type.is_leaf() || !root.cells.is_empty() {
+                    return Ok(())
+                }
+                let child_id = root.child(0);
+                let child = self.read_node(child_id)?;
+                if child.size(SqliteFile::btree_header_offset(page_id as usize)) <= usable_size {
+                    self.write_node(page_id, &child)?;
+                    self.free_page(child_id)?;
+                }
+                return Ok(())
+            },
+        };
+        let free = SqliteFile::page_free_space(self.page(page_id)?, page_id as usize, usable_size as u32)?;
+        if free as usize * 3 <= usable_size * 2 {
+            return Ok(())
+        }
+        let mut parent = self.read_node(parent_id)?;
+        if parent.cells.is_empty() {
+            return Ok(())
+        }
+        // Merge with the right sibling, the right-most child with its left one
+        let left_index = child_index.min(parent.cells.len() - 1);
+        let left_id = parent.child(left_index);
+        let right_id = parent.child(left_index + 1);
+        let mut merged = self.read_node(left_id)?;
+        let right = self.read_node(right_id)?;
+        if !merged.page_type.is_leaf() {
+            let mut cell = merged.right_most_pointer.unwrap_or(0).to_be_bytes().to_vec();
+            cell.extend(encode_varint(cell_rowid(&parent.cells[left_index], parent.page_type) as u64));
+            merged.cells.push(cell);
+        }
+        merged.cells.extend(right.cells);
+        merged.right_most_pointer = right.right_most_pointer;
+        if merged.size(0) > usable_size {
+            return Ok(())
+        }
+        self.write_node(left_id, &merged)?;
+        self.free_page(right_id)?;
+        parent.cells.remove(left_index);
+        parent.set_child(left_index, left_id);
+        self.write_node(parent_id, &parent)?;
+        self.rebalance(ancestors, parent_id)
+    }
+
+    /*
+     * Reuse a page from the freelist, or grow the file. The leaves of the
+     * first trunk are used up before the trunk itself.
+     */
+    fn allocate_page(&mut self) -> Result<u32, String> {
+        let trunk_id = self.header.freelist_trunk_page;
+        let page_id = if trunk_id == 0 {
+            self.page_count += 1;
+            // The page with the lock byte is never used
+            if self.page_count as usize == LOCK_BYTE_OFFSET / self.header.page_size as usize + 1 {
+                self.page_count += 1;
+            }
+            self.page_count
+        } else {
+            let trunk = self.page(trunk_id)?;
+            let leaf_count = read_u32(trunk, 4) as usize;
+            let page_id = if leaf_count == 0 {
+                self.header.freelist_trunk_page = read_u32(trunk, 0);
+                trunk_id
+            } else if 8 + 4 * leaf_count <= self.usable_size() {
+                let leaf = read_u32(trunk, 4 + 4 * leaf_count);
+                self.page_mut(trunk_id)?[4..8].copy_from_slice(&(leaf_count as u32 - 1).to_be_bytes());
+                leaf
+            } else {
+                return Err(format!("Freelist trunk {} has too many leaves", trunk_id))
+            };
+            if page_id == 0 || page_id > self.page_count {
+                return Err(format!("Freelist page {} is out of range", page_id))
+            }
+            self.header.freelist_count = self.header.freelist_count.saturating_sub(1);
+            page_id
+        };
+        self.pages.insert(page_id, vec![0; self.header.page_size as usize]);
+        Ok(page_id)
+    }
+
+    /*
+     * Add a page to the freelist, as a leaf of the first trunk while it has
+     * room and otherwise as the new first trunk
+     */
+    fn free_page(&mut self, page_id: u32) -> Result<(), String> {
+        let trunk_id = self.header.freelist_trunk_page;
+        let max_leaves = self.usable_size() / 4 - TRUNK_UNUSED_SLOTS;
+        self.header.freelist_count += 1;
+        if trunk_id != 0 {
+            let leaf_count = read_u32(self.page(trunk_id)?, 4) as usize;
+            if leaf_count < max_leaves {
+                let trunk = self.page_mut(trunk_id)?;
+                trunk[8 + 4 * leaf_count..12 + 4 * leaf_count].copy_from_slice(&page_id.to_be_bytes());
+                trunk[4..8].copy_from_slice(&(leaf_count as u32 + 1).to_be_bytes());
+                return Ok(())
+            }
+        }
+        let page = self.page_mut(page_id)?;
+        for byte in page.iter_mut() {
+            *byte = 0;
+        }
+        page[..4].copy_from_slice(&trunk_id.to_be_bytes());
+        self.header.freelist_trunk_page = page_id;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+
+    use data_structures::{SqliteFile, Value};
+    use writer::DatabaseBuilder;
+    use Parser;
+
+    fn row(rowid: i64, size: usize) -> Vec<Value> {
+        vec![Value::Integer(rowid), Value::Blob(vec![rowid as u8; size])]
+    }
+
+    fn database(rows: i64) -> SqliteFile {
+        let mut builder = DatabaseBuilder::new().page_size(512);
+        builder.add_table("t", "CREATE TABLE t(a, b)", (1..=rows).map(|i| (i, row(i, 20)))).unwrap();
+        SqliteFile::from_vec(&builder.build().unwrap()).unwrap()
+    }
+
+    fn rows(file: &SqliteFile) -> Vec<(i64, Vec<Value>)> {
+        let root = file.schema().unwrap()[0].rootpage;
+        file.table_rows(root).unwrap().into_iter()
+            .map(|(rowid, record)| (rowid, record.values))
+            .collect()
+    }
+
+    fn read_u32(file: &SqliteFile, page_id: u32, offset: usize) -> u32 {
+        let page = file.page_bytes(page_id as usize).unwrap();
+        super::read_u32(page, offset)
+    }
+
+    fn visit(file: &SqliteFile, page_id: u32, owners: &mut Vec<u32>) {
+        owners[page_id as usize] += 1;
+        file.free_space(page_id as usize).unwrap();
+        for cell in file.get_cells(page_id as usize).unwrap() {
+            if let Some(child) = cell.left_child {
+                visit(file, child, owners);
+            }
+            let mut next = cell.overflow_page.unwrap_or(0);
+            while next != 0 {
+                owners[next as usize] += 1;
+                next = read_u32(file, next, 0);
+            }
+        }
+        if let Some(child) = file.get_page(page_id as usize).unwrap().header.right_most_pointer {
+            visit(file, child, owners);
+        }
+    }
+
+    /*
+     * Every page belongs to exactly one B-tree, overflow chain or the freelist
+     */
+    fn check_pages(file: &SqliteFile) {
+        let mut owners = vec![0; file.page_count() as usize + 1];
+        visit(file, 1, &mut owners);
+        for entry in file.schema().unwrap() {
+            visit(file, entry.rootpage, &mut owners);
+        }
+        let mut trunk = file.header.freelist_trunk_page;
+        let mut free_pages = 0;
+        while trunk != 0 {
+            owners[trunk as usize] += 1;
+            let leaf_count = read_u32(file, trunk, 4) as usize;
+            for i in 0..leaf_count {
+                owners[read_u32(file, trunk, 8 + 4 * i) as usize] += 1;
+            }
+            free_pages += leaf_count as u32 + 1;
+            trunk = read_u32(file, trunk, 0);
+        }
+        assert_eq!(file.header.freelist_count, free_pages);
+        assert!(owners[1..].iter().all(|&n| n == 1), "page owners {:?}", owners);
+    }
+
+    #[test]
+    fn random_changes_keep_tree_consistent() {
+        let mut model: BTreeMap<i64, Vec<Value>> = (1..=200).map(|i| (i, row(i, 20))).collect();
+        let mut file = database(200);
+        let mut state = 12345u64;
+        let mut random = move |n: u64| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) % n
+        };
+        for _ in 0..6 {
+            let mut transaction = super::Transaction::new(file).unwrap();
+            for _ in 0..150 {
+                let rowid = random(600) as i64 + 1;
+                let size = match random(10) { 0 => random(3000), _ => random(60) } as usize;
+                match random(3) {
+                    0 => {
+                        let result = transaction.insert("t", Some(rowid), &row(rowid, size));
+                        assert_eq!(model.contains_key(&rowid), result.is_err());
+                        model.entry(rowid).or_insert_with(|| row(rowid, size));
+                    },
+                    1 => {
+                        let result = transaction.update("T", rowid, &row(rowid, size));
+                        assert_eq!(model.contains_key(&rowid), result.is_ok());
+                        if let Some(values) = model.get_mut(&rowid) {
+                            *values = row(rowid, size);
+                        }
+                    },
+                    _ => {
+                        let deleted = transaction.delete("t", rowid).unwrap();
+                        assert_eq!(model.remove(&rowid).is_some(), deleted);
+                    },
+                }
+            }
+            file = transaction.into_file().unwrap();
+            assert_eq!(model.clone().into_iter().collect::<Vec<_>>(), rows(&file));
+            check_pages(&file);
+        }
+
+        let mut transaction = super::Transaction::new(file).unwrap();
+        for &rowid in model.keys() {
+            assert!(transaction.delete("t", rowid).unwrap());
+        }
+        assert_eq!(1, transaction.insert("t", None, &row(1, 5)).unwrap());
+        let file = transaction.into_file().unwrap();
+        check_pages(&file);
+        assert_eq!(file.page_count() - 2, file.header.freelist_count);
+    }
+
+    #[test]
+    fn journal_restores_original() {
+        let original = database(300);
+        let copy = SqliteFile::from_vec(original.as_bytes()).unwrap();
+        let mut transaction = super::Transaction::new(copy).unwrap();
+        for rowid in 1..150 {
+            transaction.delete("t", rowid).unwrap();
+        }
+        transaction.insert("t", Some(1000), &row(1000, 5000)).unwrap();
+        transaction.update("t", 200, &row(200, 300)).unwrap();
+        let journal = transaction.journal();
+        let changed = transaction.into_file().unwrap();
+        assert!(journal.is_hot(&changed));
+        assert_eq!(original.as_bytes(), journal.rollback(&changed).unwrap().as_bytes());
+    }
+
+    #[test]
+    fn commit_to_file() {
+        let path = env::temp_dir().join(format!("litereader-transaction-{}.db", process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, database(10).as_bytes()).unwrap();
+
+        let mut transaction = super::Transaction::new(SqliteFile::from_file(path).unwrap()).unwrap();
+        assert_eq!(11, transaction.insert("t", None, &row(11, 2000)).unwrap());
+        assert!(transaction.insert("missing", None, &[]).is_err());
+        let committed = transaction.commit(path).unwrap();
+
+        let file = SqliteFile::from_file(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(!Path::new(&format!("{}-journal", path)).exists());
+        assert_eq!(committed.as_bytes(), file.as_bytes());
+        assert_eq!(2, file.header.file_change_counter);
+        assert_eq!(11, rows(&file).len());
+    }
+}
@@ -0,0 +1,743 @@
+/*
+ * Writing new databases from scratch. Table B-trees are built bottom-up from
+ * rows in rowid order: leaves are filled one after the other and the interior
+ * levels are stacked on top until a single root page remains.
+ */
+extern crate std;
+
+use std::cmp::Ordering;
+
+use checksum::{cksumvfs_checksum, verify_file, CKSUMVFS_RESERVED_SPACE};
+use data_structures::{BTreePageType, Header, KeyColumn, Record, SchemaEntry, SqliteFile, Value};
+use enums::{Collation, ReadVersion, SchemaFormat, TextEncoding, WriteVersion};
+use parser::{encode_varint, local_payload_size, parse_record};
+use write_file_atomic;
+use Parser;
+
+const LOCK_BYTE_OFFSET: usize = 0x4000_0000;
+const CONTENT_OFFSET_MAX: usize = 65536;
+const SCHEMA_ROOT_PAGE: u32 = 1;
+const FILE_HEADER_SIZE: usize = 100;
+// Writers store their version since 3.7.0, which already writes the schema
+// format 4 of the builder. Newer features raise it.
+const SQLITE_VERSION_NUMBER: u32 = 3_007_000;
+
+struct PageWriter {
+    page_size: usize,
+    usable_size: usize,
+    pages: Vec<Vec<u8>>,
+}
+
+impl PageWriter {
+    fn allocate(&mut self) -> u32 {
+        self.pages.push(vec![0; self.page_size]);
+        let page_id = self.pages.len() as u32;
+        // The page with the lock byte is never used
+        if page_id as usize == LOCK_BYTE_OFFSET / self.page_size + 1 {
+            return self.allocate()
+        }
+        page_id
+    }
+
+    fn page_mut(&mut self, page_id: u32) -> &mut Vec<u8> {
+        &mut self.pages[page_id as usize - 1]
+    }
+
+    /*
+     * Spill the payload beyond the local part into a chain of overflow pages
+     */
+    fn write_payload(&mut self, payload: &[u8], page_type: BTreePageType, cell: &mut Vec<u8>) {
+        let local = local_payload_size(payload.len() as u64, self.usable_size as u32, page_type);
+        cell.extend_from_slice(&payload[..local]);
+        if local == payload.len() {
+            return
+        }
+        let chunks: Vec<&[u8]> = payload[local..].chunks(self.usable_size - 4).collect();
+        let pages: Vec<u32> = chunks.iter().map(|_| self.allocate()).collect();
+        cell.extend_from_slice(&pages[0].to_be_bytes());
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(i + 1).cloned().unwrap_or(0);
+            let page = self.page_mut(pages[i]);
+            page[..4].copy_from_slice(&next.to_be_bytes());
+            page[4..4 + chunk.len()].copy_from_slice(chunk);
+        }
+    }
+
+    fn capacity(&self, page_id: Option<u32>, page_type: BTreePageType) -> usize {
+        let header_offset = if page_id == Some(1) { FILE_HEADER_SIZE } else { 0 };
+        self.usable_size - header_offset - page_type.header_size()
+    }
+
+    fn write_btree_page(&mut self, page_id: u32, page_type: BTreePageType, cells: &[Vec<u8>],
+                        right_most_pointer: Option<u32>) {
+        let header_offset = if page_id == 1 { FILE_HEADER_SIZE } else { 0 };
+        let usable_size = self.usable_size;
+        let page = self.page_mut(page_id);
+        write_btree_page(page, header_offset, usable_size, page_type, cells, right_most_pointer);
+    }
+
+    /*
+     * Lay out a table B-tree from (rowid, payload) pairs in rowid order and
+     * return its root page. With a fixed root, the top level is written to
+     * that page once it fits.
+     */
+    fn write_table<I>(&mut self, rows: I, root: Option<u32>) -> Result<u32, String>
+        where I: IntoIterator<Item = (i64, Vec<u8>)> {
+        let leaf = BTreePageType::LeafTablePage;
+        let mut cells: Vec<(i64, Vec<u8>)> = vec![];
+        let mut used = 0;
+        let mut children: Vec<(u32, i64)> = vec![];
+        let mut last_rowid = None;
+        for (rowid, payload) in rows {
+            if last_rowid.is_some_and(|last| rowid <= last) {
+                return Err(format!("Rowid {} is not greater than the previous rowid", rowid))
+            }
+            last_rowid = Some(rowid);
+            let mut cell = vec![];
+            cell.extend(encode_varint(payload.len() as u64));
+            cell.extend(encode_varint(rowid as u64));
+            self.write_payload(&payload, leaf, &mut cell);
+            if cell.len() < 4 {
+                cell.resize(4, 0);
+            }
+            if !cells.is_empty() && used + cell.len() + 2 > self.capacity(None, leaf) {
+                // Interior keys are the largest rowid of the child on their left
+                let max_rowid = cells[cells.len() - 1].0;
+                let page_id = self.allocate();
+                let cell_bytes: Vec<Vec<u8>> = cells.drain(..).map(|(_, c)| c).collect();
+                self.write_btree_page(page_id, leaf, &cell_bytes, None);
+                children.push((page_id, max_rowid));
+                used = 0;
+            }
+            used += cell.len() + 2;
+            cells.push((rowid, cell));
+        }
+        let max_rowid = cells.last().map(|&(r, _)| r);
+        let remaining: Vec<Vec<u8>> = cells.into_iter().map(|(_, c)| c).collect();
+        if children.is_empty() && used <= self.capacity(root, leaf) {
+            let page_id = match root {
+                Some(page_id) => page_id,
+                None => self.allocate(),
+            };
+            self.write_btree_page(page_id, leaf, &remaining, None);
+            return Ok(page_id)
+        }
+        let page_id = self.allocate();
+        self.write_btree_page(page_id, leaf, &remaining, None);
+        children.push((page_id, max_rowid.unwrap_or(0)));
+        self.write_interior_levels(children, root)
+    }
+
+    fn write_interior_levels(&mut self, mut children: Vec<(u32, i64)>, root: Option<u32>)
+                             -> Result<u32, String> {
+        let interior = BTreePageType::InteriorTablePage;
+        let cell = |&(page_id, key): &(u32, i64)| {
+            let mut cell = page_id.to_be_bytes().to_vec();
+            cell.extend(encode_varint(key as u64));
+            cell
+        };
+        loop {
+            let total: usize = children[..children.len() - 1].iter().map(|c| cell(c).len() + 2).sum();
+            if total <= self.capacity(root, interior) {
+                let page_id = match root {
+                    Some(page_id) => page_id,
+                    None => self.allocate(),
+                };
+                let cells: Vec<Vec<u8>> = children[..children.len() - 1].iter().map(&cell).collect();
+                self.write_btree_page(page_id, interior, &cells, Some(children[children.len() - 1].0));
+                return Ok(page_id)
+            }
+            // Group children into pages, the last child of each group
+            // becomes the right-most pointer and needs no cell
+            let mut groups: Vec<Vec<(u32, i64)>> = vec![vec![]];
+            let mut used = 0;
+            for child in children {
+                let size = groups.last().unwrap().last().map_or(0, |c| cell(c).len() + 2);
+                if used + size > self.capacity(None, interior) {
+                    groups.push(vec![]);
+                    used = 0;
+                } else {
+                    used += size;
+                }
+                groups.last_mut().unwrap().push(child);
+            }
+            if groups.len() > 1 && groups.last().unwrap().len() == 1 {
+                let previous = groups.len() - 2;
+                let moved = groups[previous].pop().unwrap();
+                groups.last_mut().unwrap().insert(0, moved);
+            }
+            children = vec![];
+            for group in groups {
+                let page_id = self.allocate();
+                let cells: Vec<Vec<u8>> = group[..group.len() - 1].iter().map(&cell).collect();
+                let (right_most, key) = group[group.len() - 1];
+                self.write_btree_page(page_id, interior, &cells, Some(right_most));
+                children.push((page_id, key));
+            }
+        }
+    }
+
+    /*
+     * Lay out an index B-tree from records in index order. Unlike in tables,
+     * the key between two neighbouring pages is not kept in a leaf, it moves
+     * up into the interior page above them.
+     */
+    fn write_index<I>(&mut self, records: I, root: Option<u32>) -> Result<u32, String>
+        where I: IntoIterator<Item = Vec<u8>> {
+        // Interior cells are the leaf cell behind the child pointer, so the
+        // overflow pages of a key are written once
+        let mut keys = vec![];
+        for record in records {
+            let mut key = encode_varint(record.len() as u64);
+            self.write_payload(&record, BTreePageType::LeafIndexPage, &mut key);
+            if key.len() < 4 {
+                key.resize(4, 0);
+            }
+            keys.push(key);
+        }
+        let mut children: Vec<u32> = vec![];
+        loop {
+            let page_type = if children.is_empty() {
+                BTreePageType::LeafIndexPage
+            } else {
+                BTreePageType::InteriorIndexPage
+            };
+            let pointer_size = if page_type.is_leaf() { 0 } else { 4 };
+            let cell = |i: usize, keys: &[Vec<u8>]| {
+                let mut cell = children.get(i).map_or(vec![], |c| c.to_be_bytes().to_vec());
+                cell.extend_from_slice(&keys[i]);
+                cell
+            };
+            let total: usize = keys.iter().map(|k| k.len() + pointer_size + 2).sum();
+            if total <= self.capacity(root, page_type) {
+                let page_id = match root {
+                    Some(page_id) => page_id,
+                    None => self.allocate(),
+                };
+                let cells: Vec<Vec<u8>> = (0..keys.len()).map(|i| cell(i, &keys)).collect();
+                self.write_btree_page(page_id, page_type, &cells, children.last().cloned());
+                return Ok(page_id)
+            }
+            // Each page ends where the next key doesn't fit, that key
+            // separates it from the following page
+            let mut ranges = vec![];
+            let mut start = 0;
+            let mut used = 0;
+            for (i, key) in keys.iter().enumerate() {
+                let size = key.len() + pointer_size + 2;
+                if used + size > self.capacity(None, page_type) {
+                    ranges.push(start..i);
+                    start = i + 1;
+                    used = 0;
+                } else {
+                    used += size;
+                }
+            }
+            // The last page must not be empty, the previous page gives up
+            // its last key
+            if start > keys.len() - 1 {
+                let previous = ranges.pop().unwrap();
+                ranges.push(previous.start..previous.end - 1);
+                start = previous.end;
+            }
+            ranges.push(start..keys.len());
+            let mut parent_keys = vec![];
+            let mut parent_children = vec![];
+            for range in &ranges {
+                let page_id = self.allocate();
+                let cells: Vec<Vec<u8>> = range.clone().map(|i| cell(i, &keys)).collect();
+                self.write_btree_page(page_id, page_type, &cells, children.get(range.end).cloned());
+                parent_children.push(page_id);
+                if range.end < keys.len() {
+                    parent_keys.push(keys[range.end].clone());
+                }
+            }
+            keys = parent_keys;
+            children = parent_children;
+        }
+    }
+}
+
+/*
+ * Write a complete B-tree page with its cells packed at the end of the usable
+ * area, without freeblocks or fragments. Cells have to be at least four bytes.
+ */
+pub fn write_btree_page(page: &mut [u8], header_offset: usize, usable_size: usize, page_type: BTreePageType,
+                        cells: &[Vec<u8>], right_most_pointer: Option<u32>) {
+    let mut content_offset = usable_size;
+    let mut pointer_offset = header_offset + page_type.header_size();
+    for cell in cells {
+        content_offset -= cell.len();
+        page[content_offset..content_offset + cell.len()].copy_from_slice(cell);
+        page[pointer_offset..pointer_offset + 2].copy_from_slice(&(content_offset as u16).to_be_bytes());
+        pointer_offset += 2;
+    }
+    page[header_offset] = page_type.to_u8();
+    page[header_offset + 1..header_offset + 3].copy_from_slice(&[0, 0]);
+    page[header_offset + 3..header_offset + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    let encoded_offset = if content_offset == CONTENT_OFFSET_MAX { 0 } else { content_offset as u16 };
+    page[header_offset + 5..header_offset + 7].copy_from_slice(&encoded_offset.to_be_bytes());
+    page[header_offset + 7] = 0;
+    if let Some(pointer) = right_most_pointer {
+        page[header_offset + 8..header_offset + 12].copy_from_slice(&pointer.to_be_bytes());
+    }
+}
+
+pub struct DatabaseBuilder {
+    page_size: u32,
+    reserved_space: u8,
+    text_encoding: TextEncoding,
+    user_version: u32,
+    application_id: u32,
+    writer: Option<PageWriter>,
+    schema: Vec<Vec<Value>>,
+    // Header of the database being vacuumed
+    original_header: Option<Header>,
+}
+
+impl Default for DatabaseBuilder {
+    fn default() -> DatabaseBuilder {
+        DatabaseBuilder::new()
+    }
+}
+
+impl DatabaseBuilder {
+    pub fn new() -> DatabaseBuilder {
+        DatabaseBuilder {
+            page_size: 4096,
+            reserved_space: 0,
+            text_encoding: TextEncoding::UTF8,
+            user_version: 0,
+            application_id: 0,
+            writer: None,
+            schema: vec![],
+            original_header: None,
+        }
+    }
+
+    pub fn page_size(mut self, page_size: u32) -> DatabaseBuilder {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn reserved_space(mut self, reserved_space: u8) -> DatabaseBuilder {
+        self.reserved_space = reserved_space;
+        self
+    }
+
+    pub fn text_encoding(mut self, text_encoding: TextEncoding) -> DatabaseBuilder {
+        self.text_encoding = text_encoding;
+        self
+    }
+
+    pub fn user_version(mut self, user_version: u32) -> DatabaseBuilder {
+        self.user_version = user_version;
+        self
+    }
+
+    pub fn application_id(mut self, application_id: u32) -> DatabaseBuilder {
+        self.application_id = application_id;
+        self
+    }
+
+    fn writer(&mut self) -> Result<&mut PageWriter, String> {
+        if self.writer.is_none() {
+            if !self.page_size.is_power_of_two() || self.page_size < 512 || self.page_size > 65536 {
+                return Err(format!("Invalid page size {}", self.page_size))
+            }
+            if self.page_size - (self.reserved_space as u32) < 480 {
+                return Err(format!("Reserved space {} is too large", self.reserved_space))
+            }
+            let mut writer = PageWriter {
+                page_size: self.page_size as usize,
+                usable_size: (self.page_size - self.reserved_space as u32) as usize,
+                pages: vec![],
+            };
+            writer.allocate();
+            self.writer = Some(writer);
+        }
+        Ok(self.writer.as_mut().unwrap())
+    }
+
+    /*
+     * Add a table with rows in strictly increasing rowid order. The values of
+     * an INTEGER PRIMARY KEY column have to be Null, SQLite stores them only
+     * as the rowid. Tables must not need automatic indexes (UNIQUE or a
+     * non-integer PRIMARY KEY), add_table doesn't create them.
+     */
+    pub fn add_table<I>(&mut self, name: &str, sql: &str, rows: I) -> Result<u32, String>
+        where I: IntoIterator<Item = (i64, Vec<Value>)> {
+        let encoding = self.text_encoding;
+        let records = rows.into_iter()
+            .map(|(rowid, values)| (rowid, Record::encode_with_encoding(&values, encoding)));
+        let root = self.table_btree(records)?;
+        self.add_schema_entry(&SchemaEntry {
+            entry_type: "table".to_string(),
+            name: name.to_string(),
+            tbl_name: name.to_string(),
+            rootpage: root,
+            sql: Some(sql.to_string()),
+        });
+        Ok(root)
+    }
+
+    /*
+     * Write a table B-tree from encoded records in increasing rowid order and
+     * return its root page. It needs a schema entry to be found by SQLite.
+     */
+    pub fn table_btree<I>(&mut self, records: I) -> Result<u32, String>
+        where I: IntoIterator<Item = (i64, Vec<u8>)> {
+        self.writer()?.write_table(records, None)
+    }
+
+    /*
+     * Write an index B-tree from encoded records, which have to be in index
+     * order already, and return its root page
+     */
+    pub fn index_btree<I>(&mut self, records: I) -> Result<u32, String>
+        where I: IntoIterator<Item = Vec<u8>> {
+        self.writer()?.write_index(records, None)
+    }
+
+    pub fn add_schema_entry(&mut self, entry: &SchemaEntry) {
+        self.schema.push(vec![
+            Value::Text(entry.entry_type.clone()),
+            Value::Text(entry.name.clone()),
+            Value::Text(entry.tbl_name.clone()),
+            Value::Integer(entry.rootpage as i64),
+            entry.sql.clone().map_or(Value::Null, Value::Text),
+        ]);
+    }
+
+    pub fn build(mut self) -> Result<Vec<u8>, String> {
+        let encoding = self.text_encoding;
+        let schema: Vec<(i64, Vec<u8>)> = self.schema.iter().enumerate()
+            .map(|(i, values)| (i as i64 + 1, Record::encode_with_encoding(values, encoding)))
+            .collect();
+        let header = self.header();
+        let writer = self.writer()?;
+        writer.write_table(schema, Some(SCHEMA_ROOT_PAGE))?;
+        let mut header = header;
+        header.database_size = writer.pages.len() as u32;
+        writer.page_mut(1)[..FILE_HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        Ok(writer.pages.concat())
+    }
+
+    pub fn write(self, path: &str) -> Result<(), String> {
+        write_file_atomic(path, &self.build()?)
+    }
+
+    fn header(&self) -> Header {
+        let mut header = Header {
+            page_size: self.page_size,
+            read_version: ReadVersion::Legacy,
+            write_version: WriteVersion::Legacy,
+            reserved_space: self.reserved_space,
+            max_embedded_payload_fraction: 64,
+            min_embedded_payload_fraction: 32,
+            leaf_payload_fraction: 32,
+            file_change_counter: 1,
+            database_size: 0,
+            freelist_trunk_page: 0,
+            freelist_count: 0,
+            schema_cookie: 1,
+            schema_format: SchemaFormat::Format4,
+            default_page_cache_size: 0,
+            largest_root_page: 0,
+            text_encoding: self.text_encoding,
+            user_version: self.user_version,
+            incremental_vacuum_mode: false,
+            application_id: self.application_id,
+            reserved: [0; 20],
+            version_valid_for: 1,
+            sqlite_version: self.sqlite_version(),
+        };
+        // A vacuumed database is a new version of the same database, like
+        // after SQLite's VACUUM
+        if let Some(original) = self.original_header {
+            header.read_version = original.read_version;
+            header.write_version = original.write_version;
+            header.file_change_counter = original.file_change_counter.wrapping_add(1);
+            header.version_valid_for = header.file_change_counter;
+            header.schema_cookie = original.schema_cookie.wrapping_add(1);
+            header.default_page_cache_size = original.default_page_cache_size;
+            header.sqlite_version = original.sqlite_version.max(header.sqlite_version);
+        }
+        header
+    }
+
+    /*
+     * The oldest release that can write everything in the schema
+     */
+    fn sqlite_version(&self) -> u32 {
+        self.schema.iter()
+            .flat_map(|x| SchemaEntry::from_values(x).features())
+            .map(|x| x.introduced_in().to_u32())
+            .fold(SQLITE_VERSION_NUMBER, u32::max)
+    }
+}
+
+/*
+ * Rewrite a database with densely packed B-trees and no free pages. Records
+ * are copied as they are, so rowids, values and the schema stay the same.
+ * The page size, the reserved space and the text encoding can be changed on
+ * the way. Databases with auto_vacuum lose it, since no pointer map is
+ * written.
+ */
+pub fn vacuum(file: &SqliteFile, page_size: Option<u32>, reserved_space: Option<u8>,
+              text_encoding: Option<TextEncoding>) -> Result<Vec<u8>, String> {
+    let header = file.header;
+    let encoding = text_encoding.unwrap_or(header.text_encoding);
+    let mut builder = DatabaseBuilder::new()
+        .page_size(page_size.unwrap_or(header.page_size))
+        .reserved_space(reserved_space.unwrap_or(header.reserved_space))
+        .text_encoding(encoding)
+        .user_version(header.user_version)
+        .application_id(header.application_id);
+    builder.original_header = Some(header);
+    for entry in file.schema()? {
+        let rootpage = match entry.rootpage {
+            // Views, triggers and virtual tables have no B-tree
+            0 => 0,
+            root => {
+                let is_table = file.get_page(root as usize)?.header.page_type.is_table();
+                let mut records = vec![];
+                for cell in file.btree_cells(root)? {
+                    records.push((cell.rowid.unwrap_or(0), file.read_payload(&cell)?));
+                }
+                if encoding.effective() != header.text_encoding.effective() {
+                    records = convert_records(file, &entry, records, is_table, encoding)?;
+                }
+                if is_table {
+                    builder.table_btree(records)?
+                } else {
+                    builder.index_btree(records.into_iter().map(|(_, record)| record))?
+                }
+            },
+        };
+        builder.add_schema_entry(&SchemaEntry { rootpage, ..entry });
+    }
+    let reserved_space = builder.reserved_space;
+    let mut buffer = builder.build()?;
+    // Keep the pages of a cksumvfs database verifiable
+    let checksummed = header.reserved_space == CKSUMVFS_RESERVED_SPACE
+        && verify_file(file).map(|m| m.is_empty()).unwrap_or(false);
+    if checksummed && reserved_space == CKSUMVFS_RESERVED_SPACE {
+        let page_size = page_size.unwrap_or(header.page_size) as usize;
+        for page in buffer.chunks_mut(page_size) {
+            let (data, checksum) = page.split_at_mut(page_size - CKSUMVFS_RESERVED_SPACE as usize);
+            checksum.copy_from_slice(&cksumvfs_checksum(data));
+        }
+    }
+    Ok(buffer)
+}
+
+/*
+ * Re-encode the TEXT of every record. BINARY compares the encoded bytes, so
+ * indexes with TEXT in a BINARY column are sorted again.
+ */
+fn convert_records(file: &SqliteFile, entry: &SchemaEntry, records: Vec<(i64, Vec<u8>)>, is_table: bool,
+                   encoding: TextEncoding) -> Result<Vec<(i64, Vec<u8>)>, String> {
+    let from = file.header.text_encoding;
+    let mut rows = vec![];
+    for (rowid, payload) in records {
+        let mut values = parse_record(&payload, from)?.values;
+        // Samples are index records with TEXT in the database encoding
+        if entry.name == "sqlite_stat4" {
+            if let Some(&mut Value::Blob(ref mut sample)) = values.get_mut(5) {
+                *sample = Record::encode_with_encoding(&parse_record(sample, from)?.values, encoding);
+            }
+        }
+        rows.push((rowid, values));
+    }
+    if !is_table {
+        let key = file.index_key(entry)?;
+        let reorders = rows.iter().any(|(_, values)| {
+            key.iter().zip(values)
+                .any(|(k, v)| k.collation == Collation::Binary && matches!(*v, Value::Text(_)))
+        });
+        if reorders {
+            if let Some(k) = key.iter().find(|k| matches!(k.collation, Collation::Other(_))) {
+                return Err(format!("Can't sort {} by the application-defined collation {}",
+                                   entry.name, k.collation))
+            }
+            rows.sort_by(|a, b| compare_keys(&a.1, &b.1, &key, encoding));
+        }
+    }
+    Ok(rows.into_iter()
+       .map(|(rowid, values)| (rowid, Record::encode_with_encoding(&values, encoding)))
+       .collect())
+}
+
+pub fn compare_keys(a: &[Value], b: &[Value], key: &[KeyColumn], encoding: TextEncoding) -> Ordering {
+    for ((x, y), column) in a.iter().zip(b).zip(key) {
+        let order = x.compare(y, &column.collation, encoding).unwrap_or(Ordering::Equal);
+        let order = if column.descending { order.reverse() } else { order };
+        if order != Ordering::Equal {
+            return order
+        }
+    }
+    Ordering::Equal
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use data_structures::{KeyColumn, Record, SchemaEntry, SqliteFile, Value};
+    use enums::{Collation, TextEncoding};
+    use parser::parse_record;
+    use transaction::Transaction;
+    use Parser;
+
+    fn row(i: i64) -> (i64, Vec<Value>) {
+        (i * 2, vec![
+            Value::Integer(i),
+            Value::Text(format!("row {}", i)),
+            Value::Blob(vec![i as u8; (i as usize * 13) % 1500]),
+        ])
+    }
+
+    #[test]
+    fn build_and_read_back() {
+        let mut builder = super::DatabaseBuilder::new()
+            .page_size(512)
+            .reserved_space(8)
+            .text_encoding(TextEncoding::UTF16le)
+            .user_version(42);
+        let root = builder.add_table("t", "CREATE TABLE t(a, b, c)", (1..500).map(row)).unwrap();
+        builder.add_table("empty", "CREATE TABLE empty(x)", vec![]).unwrap();
+        let buffer = builder.build().unwrap();
+
+        let file = SqliteFile::from_vec(&buffer).unwrap();
+        assert_eq!(file.validate(), vec![]);
+        assert_eq!(42, file.header.user_version);
+        assert_eq!(buffer.len() / 512, file.page_count() as usize);
+        let schema = file.schema().unwrap();
+        assert_eq!(2, schema.len());
+        assert_eq!("t", schema[0].name);
+        assert_eq!(root, schema[0].rootpage);
+        assert_eq!(Some("CREATE TABLE empty(x)".to_string()), schema[1].sql);
+
+        let rows = file.table_rows(root).unwrap();
+        assert_eq!(499, rows.len());
+        for (i, &(rowid, ref record)) in rows.iter().enumerate() {
+            let (expected_rowid, expected) = row(i as i64 + 1);
+            assert_eq!(expected_rowid, rowid);
+            assert_eq!(expected, record.values);
+        }
+        assert!(file.table_rows(schema[1].rootpage).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rowids_must_increase() {
+        let mut builder = super::DatabaseBuilder::new();
+        let rows = vec![(2, vec![Value::Null]), (1, vec![Value::Null])];
+        assert!(builder.add_table("t", "CREATE TABLE t(a)", rows).is_err());
+    }
+
+    #[test]
+    fn large_schema_root_on_page_one() {
+        let mut builder = super::DatabaseBuilder::new().page_size(512);
+        for i in 0..100 {
+            let sql = format!("CREATE TABLE table_{}(a, b, c)", i);
+            builder.add_table(&format!("table_{}", i), &sql, (1..3).map(row)).unwrap();
+        }
+        let file = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+        let page = file.get_page(1).unwrap();
+        assert!(!page.header.page_type.is_leaf());
+        assert_eq!(100, file.schema().unwrap().len());
+    }
+
+    #[test]
+    fn index_btree_in_order() {
+        let mut builder = super::DatabaseBuilder::new().page_size(512);
+        let records: Vec<Vec<u8>> = (0..3000)
+            .map(|i| Record::encode(&[Value::Text(format!("key {:05}", i)), Value::Integer(i)]))
+            .collect();
+        let root = builder.index_btree(records.clone()).unwrap();
+        builder.add_schema_entry(&SchemaEntry {
+            entry_type: "index".to_string(),
+            name: "i".to_string(),
+            tbl_name: "t".to_string(),
+            rootpage: root,
+            sql: None,
+        });
+        let file = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+        assert!(!file.get_page(root as usize).unwrap().header.page_type.is_leaf());
+        let cells = file.btree_cells(root).unwrap();
+        let payloads: Vec<Vec<u8>> = cells.iter().map(|c| file.read_payload(c).unwrap()).collect();
+        assert_eq!(records, payloads);
+        assert_eq!(None, file.schema().unwrap()[0].sql);
+    }
+
+    #[test]
+    fn vacuum_drops_free_pages() {
+        let mut builder = super::DatabaseBuilder::new().page_size(512).user_version(9);
+        builder.add_table("t", "CREATE TABLE t(a, b, c)", (1..500).map(row)).unwrap();
+        let file = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+        let mut transaction = Transaction::new(file).unwrap();
+        for i in 1..400 {
+            transaction.delete("t", i * 2).unwrap();
+        }
+        let file = transaction.into_file().unwrap();
+        assert!(file.header.freelist_count > 0);
+
+        let vacuumed = SqliteFile::from_vec(&super::vacuum(&file, Some(1024), None, None).unwrap()).unwrap();
+        assert_eq!(vacuumed.validate(), vec![]);
+        assert_eq!(1024, vacuumed.header.page_size);
+        assert_eq!(0, vacuumed.header.freelist_count);
+        assert_eq!(9, vacuumed.header.user_version);
+        assert_eq!(file.header.schema_cookie + 1, vacuumed.header.schema_cookie);
+        let root = |file: &SqliteFile| file.schema().unwrap()[0].rootpage;
+        assert_eq!(file.table_rows(root(&file)).unwrap(), vacuumed.table_rows(root(&vacuumed)).unwrap());
+        assert!(vacuumed.as_bytes().len() < file.as_bytes().len());
+    }
+
+    #[test]
+    fn vacuum_converts_text_encoding() {
+        // UTF-8 and UTF-16le sort these differently
+        let texts = ["b", "a\u{e9}", "\u{ff21}", "\u{1f600}", "\u{4e2d}", "A"];
+        let rows: Vec<(i64, Vec<Value>)> = (0..600)
+            .map(|i| (i + 1, vec![Value::Text(format!("{}{}", texts[i as usize % 6], i)), Value::Integer(i)]))
+            .collect();
+        let mut entries: Vec<Vec<Value>> = rows.iter()
+            .map(|(rowid, values)| vec![values[0].clone(), Value::Integer(*rowid)])
+            .collect();
+        entries.sort_by(|a, b| super::compare_keys(a, b, &[key()], TextEncoding::UTF8));
+        let mut builder = super::DatabaseBuilder::new().page_size(512);
+        builder.add_table("t", "CREATE TABLE t(a TEXT, b)", rows).unwrap();
+        let root = builder.index_btree(entries.iter().map(|x| Record::encode(x))).unwrap();
+        builder.add_schema_entry(&SchemaEntry {
+            entry_type: "index".to_string(),
+            name: "i".to_string(),
+            tbl_name: "t".to_string(),
+            rootpage: root,
+            sql: Some("CREATE INDEX i ON t(a)".to_string()),
+        });
+        let file = SqliteFile::from_vec(&builder.build().unwrap()).unwrap();
+
+        let converted = super::vacuum(&file, None, None, Some(TextEncoding::UTF16le)).unwrap();
+        let converted = SqliteFile::from_vec(&converted).unwrap();
+        assert_eq!(TextEncoding::UTF16le, converted.header.text_encoding);
+        let schema = converted.schema().unwrap();
+        let values = |file: &SqliteFile, root| -> Vec<Vec<Value>> {
+            file.table_rows(root).unwrap().into_iter().map(|(_, record)| record.values).collect()
+        };
+        assert_eq!(values(&file, file.schema().unwrap()[0].rootpage), values(&converted, schema[0].rootpage));
+        let index: Vec<Vec<Value>> = converted.btree_cells(schema[1].rootpage).unwrap().iter()
+            .map(|c| parse_record(&converted.read_payload(c).unwrap(), TextEncoding::UTF16le).unwrap().values)
+            .collect();
+        assert!(index.windows(2).all(|x| {
+            super::compare_keys(&x[0], &x[1], &[key()], TextEncoding::UTF16le) == Ordering::Less
+        }));
+        assert_ne!(entries, index);
+        entries.sort_by(|a, b| super::compare_keys(a, b, &[key()], TextEncoding::UTF16le));
+        assert_eq!(entries, index);
+    }
+
+    fn key() -> KeyColumn {
+        KeyColumn { name: None, collation: Collation::Binary, descending: false }
+    }
+}